@@ -1,18 +1,66 @@
 use crate::{
     client::Client,
     error::{Error, Result as ApiResult},
-    models::{CodeExecutionRequest, CodeInterpreterOptions, Context, Execution},
+    models::{
+        CodeExecutionRequest, CodeInterpreterOptions, Context, Execution, ExecutionMessage,
+        InterpreterTransport, OutputMessage,
+    },
 };
+use futures::{SinkExt, StreamExt};
 use reqwest::StatusCode;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError};
 use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
 use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{
+    error::TlsError, http::HeaderValue, Error as WsError, Message,
+};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+type WsConnection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// Prefixes the line carrying the captured variable's JSON in stdout
+const CAPTURE_MARKER: &str = "__e2b_capture__:";
+
+const STREAMING_CHANNEL_CAPACITY: usize = 100;
+
+// How much the streaming relay holds for a slow consumer when
+// `max_output_bytes` is unset
+const STREAMING_RELAY_BYTES: usize = 10 * 1024 * 1024;
+
+// Run once per context for `CodeInterpreterOptions::setup_plotting`. IPython
+// kernels get the inline backend, which renders headlessly with Agg and
+// sends figures as display data; anything else gets plain Agg.
+const PLOTTING_SETUP: &str = r#"try:
+    import matplotlib as __e2b_mpl
+    try:
+        get_ipython().run_line_magic("matplotlib", "inline")
+    except NameError:
+        __e2b_mpl.use("Agg")
+    del __e2b_mpl
+except ImportError:
+    pass"#;
+
+// reqwest has no way to lift the client-wide timeout for a single request
+const UNBOUNDED_REQUEST_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
 
 #[derive(Clone)]
 pub struct CodeInterpreterApi {
     client: Client,
     jupyter_url: String,
     envd_access_token: Option<String>,
+    transport: InterpreterTransport,
+    default_env: HashMap<String, String>,
+    cache: Option<Arc<std::sync::Mutex<ExecutionCache>>>,
+    ws_connection: Arc<Mutex<Option<WsConnection>>>,
+    ws_unavailable: Arc<AtomicBool>,
+    // Contexts (`None` for the default one) where matplotlib is set up
+    plotting_ready: Arc<std::sync::Mutex<HashSet<Option<String>>>>,
 }
 
 impl CodeInterpreterApi {
@@ -21,6 +69,12 @@ impl CodeInterpreterApi {
             client,
             jupyter_url,
             envd_access_token: None,
+            transport: InterpreterTransport::default(),
+            default_env: HashMap::new(),
+            cache: None,
+            ws_connection: Arc::new(Mutex::new(None)),
+            ws_unavailable: Arc::new(AtomicBool::new(false)),
+            plotting_ready: Arc::new(std::sync::Mutex::new(HashSet::new())),
         }
     }
 
@@ -28,6 +82,49 @@ impl CodeInterpreterApi {
         self.envd_access_token = Some(token);
     }
 
+    /// Environment applied to every run; `CodeInterpreterOptions::env_vars`
+    /// entries take precedence over it.
+    pub fn set_default_env(&mut self, envs: HashMap<String, String>) {
+        self.default_env = envs;
+    }
+
+    /// Enables caching of up to `capacity` successful executions, keyed by
+    /// language, context, code, working directory and environment. Only use
+    /// this for deterministic code: a hit skips the kernel entirely, so side
+    /// effects and state changes are not replayed. Clones share the cache.
+    pub fn with_cache(mut self, capacity: usize) -> Self {
+        self.cache = Some(Arc::new(std::sync::Mutex::new(ExecutionCache::new(
+            capacity,
+        ))));
+        self
+    }
+
+    /// Drops cached results for `context_id`, e.g. after changing the
+    /// context's state outside this API. Every run that reaches the kernel
+    /// already does this for the context it runs in.
+    pub fn invalidate_context(&self, context_id: &str) {
+        if let Some(cache) = &self.cache {
+            cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .invalidate_context(Some(context_id));
+        }
+    }
+
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        }
+    }
+
+    pub fn set_transport(&mut self, transport: InterpreterTransport) {
+        self.transport = transport;
+    }
+
+    pub fn transport(&self) -> InterpreterTransport {
+        self.transport
+    }
+
     pub async fn run_code(&self, code: &str) -> ApiResult<Execution> {
         let options = CodeInterpreterOptions::default();
         self.run_code_with_options(code, &options).await
@@ -46,18 +143,175 @@ impl CodeInterpreterApi {
         code: &str,
         options: &CodeInterpreterOptions,
     ) -> ApiResult<Execution> {
+        self.run_code_observed(code, options, None).await
+    }
+
+    /// Runs `code` on a spawned task, sending each stdout/stderr chunk, result
+    /// and error to the returned channel as it arrives; the returned future
+    /// resolves to the full `Execution`. The run makes progress whether or
+    /// not the future is polled, so the channel and the future can be
+    /// consumed in either order. Messages the consumer hasn't taken yet are
+    /// held up to `max_output_bytes` (10 MiB when unset); past that they are
+    /// dropped from the channel but still land in the `Execution`. Must be
+    /// called within a Tokio runtime. Streamed runs bypass the execution cache.
+    pub fn run_code_streaming(
+        &self,
+        code: &str,
+        options: &CodeInterpreterOptions,
+    ) -> (
+        mpsc::Receiver<ExecutionMessage>,
+        impl Future<Output = ApiResult<Execution>> + Send + 'static,
+    ) {
+        let (sender, receiver) = mpsc::channel(STREAMING_CHANNEL_CAPACITY);
+        let (relay, mut relayed) =
+            Relay::new(options.max_output_bytes.unwrap_or(STREAMING_RELAY_BYTES));
+        let buffered = relay.buffered.clone();
+        tokio::spawn(async move {
+            while let Some(message) = relayed.recv().await {
+                buffered.fetch_sub(Relay::size(&message), Ordering::SeqCst);
+                if sender.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let api = self.clone();
+        let code = code.to_string();
+        let options = options.clone();
+        let handle =
+            tokio::spawn(async move { api.run_code_observed(&code, &options, Some(&relay)).await });
+        let run = async move {
+            handle
+                .await
+                .map_err(|e| Error::wrap(500, "Streaming run did not complete", e))?
+        };
+        (receiver, run)
+    }
+
+    async fn run_code_observed(
+        &self,
+        code: &str,
+        options: &CodeInterpreterOptions,
+        events: Option<&Relay>,
+    ) -> ApiResult<Execution> {
+        // Switched into and back out of in separate cells, so the user's code
+        // runs unmodified and the kernel's cwd is unchanged afterwards
+        let cwd_cells = match &options.cwd {
+            Some(cwd) => Some(Self::cwd_cells(options.language.as_deref(), cwd)?),
+            None => None,
+        };
+        let code = code.to_string();
+        let context_id = options.context.as_ref().map(|c| c.id.clone());
+
         let request = CodeExecutionRequest {
-            code: code.to_string(),
+            code,
             language: options.language.clone(),
-            context_id: options.context.as_ref().map(|c| c.id.clone()),
-            env_vars: options.env_vars.clone(),
+            context_id: context_id.clone(),
+            env_vars: self.merged_env_vars(options),
+        };
+
+        if let Some(name) = &options.capture_variable {
+            Self::capture_cell(name, options.language.as_deref())?;
+        }
+
+        let cache_key = self
+            .cache
+            .as_ref()
+            .filter(|_| options.capture_variable.is_none() && events.is_none())
+            .map(|_| CacheKey::new(&request, options.cwd.as_deref()));
+        if let (Some(cache), Some(key)) = (&self.cache, &cache_key) {
+            let hit = cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(key);
+            if let Some(execution) = hit {
+                tracing::debug!("Returning cached execution");
+                return Ok(execution);
+            }
+        }
+        // Whatever runs next may change the context's state, so earlier
+        // results from it can no longer be trusted
+        if let Some(cache) = &self.cache {
+            cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .invalidate_context(request.context_id.as_deref());
+        }
+
+        let setup_plotting = options.setup_plotting
+            && Self::is_python(options.language.as_deref())
+            && !self
+                .plotting_ready
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .contains(&context_id);
+        if setup_plotting {
+            self.run_helper_cell(PLOTTING_SETUP, options).await?;
+            self.plotting_ready
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(context_id.clone());
+        }
+
+        let mut execution = match &cwd_cells {
+            Some((enter, restore)) => {
+                self.run_helper_cell(enter, options).await?;
+                let execution = self.execute(&request, options, events).await;
+                if let Err(e) = self.run_helper_cell(restore, options).await {
+                    tracing::warn!("Failed to restore the kernel's working directory: {}", e);
+                }
+                execution?
+            }
+            None => self.execute(&request, options, events).await?,
         };
 
-        let timeout_duration = options.timeout.unwrap_or(Duration::from_secs(300));
+        if let Some(name) = &options.capture_variable {
+            if execution.error.is_none() {
+                execution.captured = Some(Box::pin(self.capture_variable(name, options)).await?);
+            }
+        }
+
+        // Failed or truncated runs are not worth replaying
+        if let (Some(cache), Some(key)) = (&self.cache, cache_key) {
+            if execution.error.is_none() && !execution.truncated {
+                cache
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(key, execution.clone());
+            }
+        }
+
+        Ok(execution)
+    }
+
+    // Sends one cell to the kernel, applying the run's timeouts and output cap
+    async fn execute(
+        &self,
+        request: &CodeExecutionRequest,
+        options: &CodeInterpreterOptions,
+        events: Option<&Relay>,
+    ) -> ApiResult<Execution> {
+        let total_timeout = options.total_timeout();
+        let idle_timeout = options.idle_timeout;
 
         let request_future = async {
+            if self.transport == InterpreterTransport::WebSocket
+                && !self.ws_unavailable.load(Ordering::Relaxed)
+            {
+                if let Some(execution) = self
+                    .execute_over_ws(request, options.max_output_bytes, idle_timeout, events)
+                    .await?
+                {
+                    return Ok(execution);
+                }
+            }
+
             let url = format!("{}/execute", self.jupyter_url);
-            let mut request_builder = self.client.http().post(&url).json(&request);
+            // The execution may legitimately outlive the client-wide timeout
+            let mut request_builder = self
+                .client
+                .post(&url)
+                .json(request)
+                .timeout(total_timeout.unwrap_or(UNBOUNDED_REQUEST_TIMEOUT));
 
             if let Some(token) = &self.envd_access_token {
                 request_builder = request_builder.header("X-Access-Token", token);
@@ -67,9 +321,13 @@ impl CodeInterpreterApi {
 
             match response.status() {
                 StatusCode::OK => {
-                    let text = response.text().await?;
-                    tracing::debug!("Jupyter response: {}", text);
-                    self.parse_jupyter_response(&text).await
+                    self.parse_jupyter_response(
+                        response,
+                        options.max_output_bytes,
+                        idle_timeout,
+                        events,
+                    )
+                    .await
                 }
                 StatusCode::NOT_FOUND => Err(Error::NotFound(format!(
                     "Jupyter server not found at {}",
@@ -77,7 +335,7 @@ impl CodeInterpreterApi {
                 ))),
                 status => {
                     let error_text = response.text().await.unwrap_or_default();
-                    Err(Error::Api {
+                    Err(Error::Envd {
                         status: status.as_u16(),
                         message: error_text,
                     })
@@ -85,151 +343,570 @@ impl CodeInterpreterApi {
             }
         };
 
-        timeout(timeout_duration, request_future)
+        match total_timeout {
+            Some(total) => timeout(total, request_future)
+                .await
+                .map_err(|_| Error::Timeout)?,
+            None => request_future.await,
+        }
+    }
+
+    // Runs SDK-generated code in the run's context, bypassing the cache,
+    // and fails if it raised
+    async fn run_helper_cell(
+        &self,
+        code: &str,
+        options: &CodeInterpreterOptions,
+    ) -> ApiResult<Execution> {
+        let request = CodeExecutionRequest {
+            code: code.to_string(),
+            language: options.language.clone(),
+            context_id: options.context.as_ref().map(|c| c.id.clone()),
+            env_vars: self.merged_env_vars(options),
+        };
+        let helper_options = CodeInterpreterOptions {
+            max_output_bytes: None,
+            ..options.clone()
+        };
+        let execution = self.execute(&request, &helper_options, None).await?;
+        if let Some(error) = execution.error {
+            return Err(Error::ExecutionError {
+                name: error.name,
+                value: error.value,
+                traceback: error.traceback,
+            });
+        }
+        Ok(execution)
+    }
+
+    fn merged_env_vars(&self, options: &CodeInterpreterOptions) -> Option<HashMap<String, String>> {
+        let mut envs = self.default_env.clone();
+        if let Some(overrides) = &options.env_vars {
+            envs.extend(overrides.clone());
+        }
+        (!envs.is_empty()).then_some(envs)
+    }
+
+    fn is_python(language: Option<&str>) -> bool {
+        matches!(
+            language.unwrap_or("python").to_lowercase().as_str(),
+            "python" | "python3"
+        )
+    }
+
+    fn capture_cell(name: &str, language: Option<&str>) -> ApiResult<String> {
+        if !Self::is_python(language) {
+            return Err(Error::Configuration(
+                "capture_variable is only supported for Python".to_string(),
+            ));
+        }
+        let mut chars = name.chars();
+        let valid = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+            && chars.all(|c| c.is_alphanumeric() || c == '_');
+        if !valid {
+            return Err(Error::Configuration(format!(
+                "Invalid variable name to capture: '{}'",
+                name
+            )));
+        }
+
+        Ok(format!(
+            r#"import json as __e2b_json
+import math as __e2b_math
+def __e2b_finite(value):
+    if isinstance(value, float) and not __e2b_math.isfinite(value):
+        return None
+    if isinstance(value, dict):
+        return {{key: __e2b_finite(item) for key, item in value.items()}}
+    if isinstance(value, (list, tuple)):
+        return [__e2b_finite(item) for item in value]
+    return value
+__e2b_value = {name}
+try:
+    try:
+        import pandas as __e2b_pd
+        if isinstance(__e2b_value, (__e2b_pd.DataFrame, __e2b_pd.Series)):
+            __e2b_value = __e2b_json.loads(__e2b_value.to_json(orient="split", date_format="iso"))
+        del __e2b_pd
+    except ImportError:
+        pass
+    try:
+        __e2b_text = __e2b_json.dumps(__e2b_value, default=str, allow_nan=False)
+    except ValueError:
+        __e2b_text = __e2b_json.dumps(__e2b_finite(__e2b_value), default=str, allow_nan=False)
+    print("{marker}" + __e2b_text)
+    del __e2b_text
+finally:
+    del __e2b_json, __e2b_math, __e2b_finite, __e2b_value"#,
+            name = name,
+            marker = CAPTURE_MARKER
+        ))
+    }
+
+    // Reads `name` back from the context the main cell ran in
+    async fn capture_variable(
+        &self,
+        name: &str,
+        options: &CodeInterpreterOptions,
+    ) -> ApiResult<serde_json::Value> {
+        let cell = Self::capture_cell(name, options.language.as_deref())?;
+        let capture_options = CodeInterpreterOptions {
+            cwd: None,
+            capture_variable: None,
+            setup_plotting: false,
+            max_output_bytes: None,
+            ..options.clone()
+        };
+
+        let execution = self.run_code_with_options(&cell, &capture_options).await?;
+        if let Some(error) = execution.error {
+            return Err(Error::ExecutionError {
+                name: error.name,
+                value: error.value,
+                traceback: error.traceback,
+            });
+        }
+
+        let line = execution
+            .stdout
+            .lines()
+            .find_map(|line| line.strip_prefix(CAPTURE_MARKER))
+            .ok_or_else(|| Error::Envd {
+                status: 500,
+                message: format!("Capturing '{}' produced no output", name),
+            })?;
+        Ok(serde_json::from_str(line)?)
+    }
+
+    // Code switching to `cwd` and code switching back, for `language`
+    fn cwd_cells(language: Option<&str>, cwd: &str) -> ApiResult<(String, String)> {
+        // A JSON string literal is also a valid Python, JavaScript and R literal
+        let quoted = serde_json::to_string(cwd)?;
+        let cells = match language.unwrap_or("python").to_lowercase().as_str() {
+            "python" | "python3" => (
+                format!(
+                    "import os as __e2b_os\n__e2b_prev_cwd = __e2b_os.getcwd()\n__e2b_os.chdir({})",
+                    quoted
+                ),
+                "__e2b_os.chdir(__e2b_prev_cwd)\ndel __e2b_os, __e2b_prev_cwd".to_string(),
+            ),
+            "javascript" | "js" | "typescript" | "ts" => (
+                format!(
+                    "globalThis.__e2bPrevCwd = process.cwd(); process.chdir({});",
+                    quoted
+                ),
+                "process.chdir(globalThis.__e2bPrevCwd); delete globalThis.__e2bPrevCwd;"
+                    .to_string(),
+            ),
+            "bash" | "sh" => (
+                format!(
+                    "__e2b_prev_cwd=\"$PWD\"; cd {}",
+                    crate::api::commands::shell_escape(cwd)
+                ),
+                "cd \"$__e2b_prev_cwd\"; unset __e2b_prev_cwd".to_string(),
+            ),
+            "r" => (
+                format!(".e2b_prev_cwd <- setwd({})", quoted),
+                "setwd(.e2b_prev_cwd); rm(.e2b_prev_cwd)".to_string(),
+            ),
+            other => {
+                return Err(Error::Configuration(format!(
+                    "Setting a working directory is not supported for language '{}'",
+                    other
+                )))
+            }
+        };
+        Ok(cells)
+    }
+
+    /// Runs `request` over the persistent WebSocket, connecting on first use.
+    /// Returns `None` when the connection can't be made so the caller can
+    /// fall back to HTTP. Only a server that refuses the upgrade (a `4xx`,
+    /// e.g. a 404 from a Jupyter server without the endpoint) makes later
+    /// calls skip the WebSocket attempt; other failures are retried next time.
+    async fn execute_over_ws(
+        &self,
+        request: &CodeExecutionRequest,
+        max_output_bytes: Option<usize>,
+        idle_timeout: Option<Duration>,
+        events: Option<&Relay>,
+    ) -> ApiResult<Option<Execution>> {
+        let mut slot = self.ws_connection.lock().await;
+
+        // Taken out of the slot so a timeout mid-execution drops the
+        // connection instead of leaving unread frames for the next call.
+        let mut connection = match slot.take() {
+            Some(connection) => connection,
+            None => match self.connect_ws().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    tracing::debug!("WebSocket transport unavailable, using HTTP: {}", e);
+                    if Self::ws_refused(&e) {
+                        self.ws_unavailable.store(true, Ordering::Relaxed);
+                    }
+                    return Ok(None);
+                }
+            },
+        };
+
+        let payload = serde_json::to_string(request)?;
+        connection
+            .send(Message::Text(payload.into()))
             .await
-            .map_err(|_| Error::Timeout)?
+            .map_err(Self::ws_error)?;
+
+        let mut execution = Self::empty_execution();
+        let mut progress = Progress::default();
+        let mut line_count = 0usize;
+        let mut finished = false;
+
+        while let Some(message) = Self::next_within(&mut connection, idle_timeout).await? {
+            let text = match message.map_err(Self::ws_error)? {
+                Message::Text(text) => text.to_string(),
+                Message::Binary(data) => String::from_utf8_lossy(&data).into_owned(),
+                Message::Close(_) => break,
+                _ => continue,
+            };
+
+            for line in text.lines() {
+                if Self::is_end_of_execution(line) {
+                    finished = true;
+                    break;
+                }
+                line_count += 1;
+                Self::handle_jupyter_line(&mut execution, line, line_count, max_output_bytes);
+            }
+            progress.emit(&execution, events);
+            if finished {
+                break;
+            }
+        }
+
+        // The code may have run, so this is not retried over HTTP
+        if !finished {
+            return Err(Error::Envd {
+                status: 502,
+                message: format!(
+                    "Interpreter WebSocket closed before the execution finished \
+                     ({} bytes of stdout received)",
+                    execution.stdout.len()
+                ),
+            });
+        }
+        // Only a connection that reached the end marker is safe to reuse
+        *slot = Some(connection);
+        Ok(Some(execution))
+    }
+
+    async fn connect_ws(&self) -> std::result::Result<WsConnection, WsError> {
+        let url = format!(
+            "{}/ws/execute",
+            self.jupyter_url
+                .replacen("https://", "wss://", 1)
+                .replacen("http://", "ws://", 1)
+        );
+        let mut request = url.into_client_request()?;
+        if let Some(token) = &self.envd_access_token {
+            let value = HeaderValue::from_str(token).map_err(|e| WsError::HttpFormat(e.into()))?;
+            request.headers_mut().insert("X-Access-Token", value);
+        }
+
+        let connector = self
+            .client
+            .config()
+            .ws_connector()
+            .map_err(|e| WsError::Tls(TlsError::Native(Box::new(e))))?;
+        let (connection, _) =
+            tokio_tungstenite::connect_async_tls_with_config(request, None, false, connector)
+                .await?;
+        Ok(connection)
     }
 
-    async fn parse_jupyter_response(&self, response_text: &str) -> ApiResult<Execution> {
-        // Parse streaming JSON lines from Jupyter response
-        tracing::debug!("Parsing Jupyter response, {} chars", response_text.len());
-        let mut execution = Execution {
+    // Whether the failure is permanent rather than a network hiccup
+    fn ws_refused(error: &WsError) -> bool {
+        match error {
+            WsError::Http(response) => response.status().is_client_error(),
+            WsError::Url(_) | WsError::HttpFormat(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Next item of `stream`, failing with `Error::Timeout` if nothing
+    /// arrives within `idle_timeout`.
+    async fn next_within<S>(
+        stream: &mut S,
+        idle_timeout: Option<Duration>,
+    ) -> ApiResult<Option<S::Item>>
+    where
+        S: futures::Stream + Unpin,
+    {
+        match idle_timeout {
+            Some(idle) => timeout(idle, stream.next())
+                .await
+                .map_err(|_| Error::Timeout),
+            None => Ok(stream.next().await),
+        }
+    }
+
+    fn is_end_of_execution(line: &str) -> bool {
+        serde_json::from_str::<serde_json::Value>(line)
+            .is_ok_and(|json| json.get("type").and_then(|t| t.as_str()) == Some("end_of_execution"))
+    }
+
+    fn ws_error(error: WsError) -> Error {
+        Error::wrap(502, "Interpreter WebSocket error", error)
+    }
+
+    fn empty_execution() -> Execution {
+        Execution {
             stdout: String::new(),
             stderr: String::new(),
             results: Vec::new(),
             error: None,
             is_main_result: false,
-        };
+            output: Vec::new(),
+            truncated: false,
+            captured: None,
+        }
+    }
 
-        let lines: Vec<&str> = response_text.lines().collect();
-        tracing::debug!("Response has {} lines", lines.len());
+    async fn parse_jupyter_response(
+        &self,
+        response: reqwest::Response,
+        max_output_bytes: Option<usize>,
+        idle_timeout: Option<Duration>,
+        events: Option<&Relay>,
+    ) -> ApiResult<Execution> {
+        // Parse streaming JSON lines from the Jupyter response as they arrive
+        let mut execution = Self::empty_execution();
+        let mut progress = Progress::default();
 
-        for (i, line) in lines.iter().enumerate() {
-            if line.trim().is_empty() {
-                continue;
+        let mut stream = response.bytes_stream();
+        let mut lines = LineBuffer::new(max_output_bytes);
+        let mut line_count = 0usize;
+
+        while let Some(chunk) = Self::next_within(&mut stream, idle_timeout).await? {
+            let dropped = lines.push(&chunk?, |line| {
+                line_count += 1;
+                Self::handle_jupyter_line(
+                    &mut execution,
+                    &String::from_utf8_lossy(line),
+                    line_count,
+                    max_output_bytes,
+                );
+            });
+            if let Some(size) = dropped {
+                tracing::warn!(
+                    "Discarding an interpreter message of over {} bytes, more than max_output_bytes",
+                    size
+                );
+                execution.truncated = true;
             }
+            progress.emit(&execution, events);
+        }
 
-            tracing::debug!("Line {}: {}", i, line);
-            match serde_json::from_str::<serde_json::Value>(line) {
-                Ok(json) => {
-                    tracing::debug!(
-                        "Parsed JSON keys: {:?}",
-                        json.as_object().map(|o| o.keys().collect::<Vec<_>>())
-                    );
-
-                    // Check for different possible response formats
-                    if let Some(msg_type) = json.get("type").and_then(|t| t.as_str()) {
-                        tracing::debug!("Message type: {}", msg_type);
-                        match msg_type {
-                            "stdout" => {
-                                if let Some(text) = json.get("text").and_then(|t| t.as_str()) {
-                                    execution.stdout.push_str(text);
-                                } else if let Some(data) = json.get("line").and_then(|l| l.as_str())
-                                {
-                                    execution.stdout.push_str(data);
-                                    execution.stdout.push('\n');
-                                } else if let Some(data) = json.get("data").and_then(|l| l.as_str())
-                                {
-                                    execution.stdout.push_str(data);
-                                    execution.stdout.push('\n');
-                                }
+        if let Some(line) = lines.finish() {
+            line_count += 1;
+            Self::handle_jupyter_line(
+                &mut execution,
+                &String::from_utf8_lossy(&line),
+                line_count,
+                max_output_bytes,
+            );
+            progress.emit(&execution, events);
+        }
+
+        tracing::debug!(
+            "Final execution result - stdout: {} bytes, stderr: {} bytes, results: {}, error: {:?}, truncated: {}",
+            execution.stdout.len(),
+            execution.stderr.len(),
+            execution.results.len(),
+            execution.error.is_some(),
+            execution.truncated
+        );
+        Ok(execution)
+    }
+
+    fn handle_jupyter_line(
+        execution: &mut Execution,
+        line: &str,
+        index: usize,
+        limit: Option<usize>,
+    ) {
+        if line.trim().is_empty() {
+            return;
+        }
+
+        tracing::debug!("Line {}: {}", index, line);
+        match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(json) => {
+                tracing::debug!(
+                    "Parsed JSON keys: {:?}",
+                    json.as_object().map(|o| o.keys().collect::<Vec<_>>())
+                );
+
+                // Check for different possible response formats
+                if let Some(msg_type) = json.get("type").and_then(|t| t.as_str()) {
+                    tracing::debug!("Message type: {}", msg_type);
+                    match msg_type {
+                        "stdout" => {
+                            if let Some(chunk) = Self::output_chunk(&json) {
+                                let timestamp = Self::arrival_timestamp(execution);
+                                Self::push_output(execution, chunk, false, timestamp, limit);
                             }
-                            "stderr" => {
-                                if let Some(text) = json.get("text").and_then(|t| t.as_str()) {
-                                    execution.stderr.push_str(text);
-                                } else if let Some(data) = json.get("line").and_then(|l| l.as_str())
-                                {
-                                    execution.stderr.push_str(data);
-                                    execution.stderr.push('\n');
-                                } else if let Some(data) = json.get("data").and_then(|l| l.as_str())
-                                {
-                                    execution.stderr.push_str(data);
-                                    execution.stderr.push('\n');
-                                }
+                        }
+                        "stderr" => {
+                            if let Some(chunk) = Self::output_chunk(&json) {
+                                let timestamp = Self::arrival_timestamp(execution);
+                                Self::push_output(execution, chunk, true, timestamp, limit);
                             }
-                            "result" | "display_data" => {
-                                let mut result_data = std::collections::HashMap::new();
+                        }
+                        "result" | "display_data" => {
+                            let mut result_data = std::collections::HashMap::new();
 
-                                // Check for text result
-                                if let Some(text) = json.get("text").and_then(|t| t.as_str()) {
-                                    result_data.insert("text/plain".to_string(), text.to_string());
-                                }
+                            // Check for text result
+                            if let Some(text) = json.get("text").and_then(|t| t.as_str()) {
+                                result_data.insert("text/plain".to_string(), text.to_string());
+                            }
 
-                                // Check for other data fields
-                                if let Some(data) = json.get("data") {
-                                    if let Some(data_obj) = data.as_object() {
-                                        for (k, v) in data_obj {
-                                            if let Some(v_str) = v.as_str() {
-                                                result_data.insert(k.clone(), v_str.to_string());
-                                            }
+                            // Check for other data fields
+                            if let Some(data) = json.get("data") {
+                                if let Some(data_obj) = data.as_object() {
+                                    for (k, v) in data_obj {
+                                        if let Some(v_str) = v.as_str() {
+                                            result_data.insert(k.clone(), v_str.to_string());
                                         }
                                     }
                                 }
-
-                                if !result_data.is_empty() {
-                                    execution.results.push(
-                                        crate::models::code_interpreter::Result {
-                                            result_type: msg_type.to_string(),
-                                            data: result_data,
-                                        },
-                                    );
-                                    execution.is_main_result = json
-                                        .get("is_main_result")
-                                        .and_then(|v| v.as_bool())
-                                        .unwrap_or(true);
-                                }
-                            }
-                            "error" => {
-                                execution.error = Some(crate::models::ExecutionError {
-                                    name: json
-                                        .get("name")
-                                        .and_then(|n| n.as_str())
-                                        .unwrap_or("Unknown")
-                                        .to_string(),
-                                    value: json
-                                        .get("value")
-                                        .and_then(|v| v.as_str())
-                                        .unwrap_or("")
-                                        .to_string(),
-                                    traceback: json
-                                        .get("traceback")
-                                        .and_then(|t| t.as_str())
-                                        .unwrap_or("")
-                                        .to_string(),
-                                });
                             }
-                            _ => {
-                                tracing::debug!("Unknown message type: {}", msg_type);
+
+                            let size: usize = result_data.values().map(String::len).sum();
+                            if limit
+                                .is_some_and(|limit| Self::output_bytes(execution) + size > limit)
+                            {
+                                execution.truncated = true;
+                            } else if !result_data.is_empty() {
+                                execution
+                                    .results
+                                    .push(crate::models::code_interpreter::Result {
+                                        result_type: msg_type.to_string(),
+                                        data: result_data,
+                                    });
+                                execution.is_main_result = json
+                                    .get("is_main_result")
+                                    .and_then(|v| v.as_bool())
+                                    .unwrap_or(true);
                             }
                         }
-                    } else {
-                        // Maybe the response has a different structure
-                        tracing::debug!("No 'type' field found, checking for other patterns");
-
-                        // Check if it's a direct output response
-                        if let Some(stdout) = json.get("stdout").and_then(|s| s.as_str()) {
-                            execution.stdout.push_str(stdout);
+                        "error" => {
+                            execution.error = Some(crate::models::ExecutionError {
+                                name: json
+                                    .get("name")
+                                    .and_then(|n| n.as_str())
+                                    .unwrap_or("Unknown")
+                                    .to_string(),
+                                value: json
+                                    .get("value")
+                                    .and_then(|v| v.as_str())
+                                    .unwrap_or("")
+                                    .to_string(),
+                                traceback: json
+                                    .get("traceback")
+                                    .and_then(|t| t.as_str())
+                                    .unwrap_or("")
+                                    .to_string(),
+                            });
                         }
-                        if let Some(stderr) = json.get("stderr").and_then(|s| s.as_str()) {
-                            execution.stderr.push_str(stderr);
+                        _ => {
+                            tracing::debug!("Unknown message type: {}", msg_type);
                         }
                     }
+                } else {
+                    // Maybe the response has a different structure
+                    tracing::debug!("No 'type' field found, checking for other patterns");
+
+                    // Check if it's a direct output response
+                    let timestamp = Self::arrival_timestamp(execution);
+                    if let Some(stdout) = json.get("stdout").and_then(|s| s.as_str()) {
+                        Self::push_output(execution, stdout.to_string(), false, timestamp, limit);
+                    }
+                    if let Some(stderr) = json.get("stderr").and_then(|s| s.as_str()) {
+                        Self::push_output(execution, stderr.to_string(), true, timestamp, limit);
+                    }
                 }
-                Err(_) => {
-                    // Skip malformed JSON lines
-                    continue;
+            }
+            Err(_) => {
+                // Skip malformed JSON lines
+            }
+        }
+    }
+
+    // What counts against `max_output_bytes`: stdout, stderr and result data
+    fn output_bytes(execution: &Execution) -> usize {
+        let results: usize = execution
+            .results
+            .iter()
+            .flat_map(|r| r.data.values())
+            .map(String::len)
+            .sum();
+        execution.stdout.len() + execution.stderr.len() + results
+    }
+
+    fn push_output(
+        execution: &mut Execution,
+        mut chunk: String,
+        is_stderr: bool,
+        timestamp: i64,
+        limit: Option<usize>,
+    ) {
+        if let Some(limit) = limit {
+            let used = Self::output_bytes(execution);
+            let remaining = limit.saturating_sub(used);
+            if chunk.len() > remaining {
+                let mut cut = remaining;
+                while !chunk.is_char_boundary(cut) {
+                    cut -= 1;
                 }
+                chunk.truncate(cut);
+                execution.truncated = true;
+            }
+            if chunk.is_empty() {
+                return;
             }
         }
 
-        tracing::debug!(
-            "Final execution result - stdout: '{}', stderr: '{}', results: {}, error: {:?}",
-            execution.stdout,
-            execution.stderr,
-            execution.results.len(),
-            execution.error.is_some()
-        );
-        Ok(execution)
+        if is_stderr {
+            execution.stderr.push_str(&chunk);
+        } else {
+            execution.stdout.push_str(&chunk);
+        }
+        execution.output.push(OutputMessage {
+            line: chunk,
+            timestamp,
+            error: is_stderr,
+        });
+    }
+
+    fn output_chunk(json: &serde_json::Value) -> Option<String> {
+        if let Some(text) = json.get("text").and_then(|t| t.as_str()) {
+            return Some(text.to_string());
+        }
+        json.get("line")
+            .or_else(|| json.get("data"))
+            .and_then(|l| l.as_str())
+            .map(|data| format!("{}\n", data))
+    }
+
+    // Chunks are stamped on arrival by the client clock alone, since only
+    // some kernel messages carry a timestamp and the two clocks can disagree.
+    // Never earlier than the previous chunk, so a clock step can't reorder
+    // `combined_output`.
+    fn arrival_timestamp(execution: &Execution) -> i64 {
+        let now = chrono::Utc::now().timestamp_micros();
+        execution
+            .output
+            .last()
+            .map_or(now, |last| now.max(last.timestamp))
     }
 
     pub async fn create_context(
@@ -246,7 +923,7 @@ impl CodeInterpreterApi {
         }
 
         let url = format!("{}/contexts", self.jupyter_url);
-        let mut request_builder = self.client.http().post(&url).json(&request_data);
+        let mut request_builder = self.client.post(&url).json(&request_data);
 
         if let Some(token) = &self.envd_access_token {
             request_builder = request_builder.header("X-Access-Token", token);
@@ -261,7 +938,7 @@ impl CodeInterpreterApi {
             }
             status => {
                 let error_text = response.text().await.unwrap_or_default();
-                Err(Error::Api {
+                Err(Error::Envd {
                     status: status.as_u16(),
                     message: error_text,
                 })
@@ -269,9 +946,11 @@ impl CodeInterpreterApi {
         }
     }
 
-    pub async fn list_contexts(&self) -> ApiResult<Vec<Context>> {
+    /// Lists the interpreter's contexts, keeping only those running
+    /// `language` when one is given.
+    pub async fn list_contexts(&self, language: Option<&str>) -> ApiResult<Vec<Context>> {
         let url = format!("{}/contexts", self.jupyter_url);
-        let mut request_builder = self.client.http().get(&url);
+        let mut request_builder = self.client.get(&url);
 
         if let Some(token) = &self.envd_access_token {
             request_builder = request_builder.header("X-Access-Token", token);
@@ -281,16 +960,523 @@ impl CodeInterpreterApi {
 
         match response.status() {
             StatusCode::OK => {
-                let contexts: Vec<Context> = response.json().await?;
+                let mut contexts: Vec<Context> = response.json().await?;
+                if let Some(language) = language {
+                    contexts.retain(|c| c.language.eq_ignore_ascii_case(language));
+                }
                 Ok(contexts)
             }
             status => {
                 let error_text = response.text().await.unwrap_or_default();
-                Err(Error::Api {
+                Err(Error::Envd {
                     status: status.as_u16(),
                     message: error_text,
                 })
             }
         }
     }
+
+    /// Looks up an existing context by ID, e.g. one created before
+    /// reconnecting to the sandbox, so runs can continue in the same kernel
+    /// state. Fails with `Error::NotFound` if the kernel is gone.
+    pub async fn attach_context(&self, context_id: &str) -> ApiResult<Context> {
+        self.list_contexts(None)
+            .await?
+            .into_iter()
+            .find(|context| context.id == context_id)
+            .ok_or_else(|| Error::NotFound(format!("Context {}", context_id)))
+    }
+}
+
+// Splits a byte stream into lines, scanning each byte once. A line longer
+// than `limit` is discarded up to its newline instead of being buffered.
+struct LineBuffer {
+    pending: Vec<u8>,
+    // Bytes at the start of `pending` already known to hold no newline
+    scanned: usize,
+    // Inside a discarded line
+    skipping: bool,
+    limit: Option<usize>,
+}
+
+impl LineBuffer {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            pending: Vec::new(),
+            scanned: 0,
+            skipping: false,
+            limit,
+        }
+    }
+
+    // Hands every completed line to `on_line`, without its newline. Returns
+    // the buffered size when it had to start discarding an oversized line.
+    fn push(&mut self, chunk: &[u8], mut on_line: impl FnMut(&[u8])) -> Option<usize> {
+        self.pending.extend_from_slice(chunk);
+        let mut start = 0;
+        while let Some(offset) = self.pending[self.scanned..]
+            .iter()
+            .position(|b| *b == b'\n')
+        {
+            let end = self.scanned + offset;
+            if !std::mem::take(&mut self.skipping) {
+                on_line(&self.pending[start..end]);
+            }
+            start = end + 1;
+            self.scanned = start;
+        }
+        self.pending.drain(..start);
+        self.scanned = self.pending.len();
+
+        if self.skipping {
+            self.pending.clear();
+            self.scanned = 0;
+            return None;
+        }
+        let size = self.pending.len();
+        if self.limit.is_some_and(|limit| size > limit) {
+            self.pending.clear();
+            self.scanned = 0;
+            self.skipping = true;
+            return Some(size);
+        }
+        None
+    }
+
+    // The trailing line, if the stream didn't end with a newline
+    fn finish(self) -> Option<Vec<u8>> {
+        (!self.skipping && !self.pending.is_empty()).then_some(self.pending)
+    }
+}
+
+// How much of an `Execution` has already been sent to a streaming consumer
+#[derive(Default)]
+struct Progress {
+    output: usize,
+    results: usize,
+    error: bool,
+}
+
+impl Progress {
+    fn emit(&mut self, execution: &Execution, events: Option<&Relay>) {
+        let Some(events) = events else {
+            return;
+        };
+
+        let mut messages = Vec::new();
+        for message in &execution.output[self.output..] {
+            messages.push(if message.error {
+                ExecutionMessage::Stderr(message.line.clone())
+            } else {
+                ExecutionMessage::Stdout(message.line.clone())
+            });
+        }
+        for result in &execution.results[self.results..] {
+            messages.push(ExecutionMessage::Result(result.clone()));
+        }
+        if let (false, Some(error)) = (self.error, &execution.error) {
+            messages.push(ExecutionMessage::Error(error.clone()));
+        }
+        self.output = execution.output.len();
+        self.results = execution.results.len();
+        self.error = execution.error.is_some();
+
+        for message in messages {
+            events.send(message);
+        }
+    }
+}
+
+// The run never waits on a streaming consumer; this holds what the bounded
+// channel has no room for yet, up to `limit` bytes, and drops the rest
+struct Relay {
+    sender: mpsc::UnboundedSender<ExecutionMessage>,
+    buffered: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl Relay {
+    fn new(limit: usize) -> (Self, mpsc::UnboundedReceiver<ExecutionMessage>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let relay = Self {
+            sender,
+            buffered: Arc::new(AtomicUsize::new(0)),
+            limit,
+        };
+        (relay, receiver)
+    }
+
+    fn send(&self, message: ExecutionMessage) {
+        let size = Self::size(&message);
+        if self.buffered.fetch_add(size, Ordering::SeqCst) + size > self.limit {
+            self.buffered.fetch_sub(size, Ordering::SeqCst);
+            return;
+        }
+        // The consumer may stop listening; the run still completes
+        let _ = self.sender.send(message);
+    }
+
+    fn size(message: &ExecutionMessage) -> usize {
+        match message {
+            ExecutionMessage::Stdout(line) | ExecutionMessage::Stderr(line) => line.len(),
+            ExecutionMessage::Result(result) => result.data.values().map(String::len).sum(),
+            ExecutionMessage::Error(error) => {
+                error.name.len() + error.value.len() + error.traceback.len()
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    language: Option<String>,
+    context_id: Option<String>,
+    code: String,
+    cwd: Option<String>,
+    env: Vec<(String, String)>,
+}
+
+impl CacheKey {
+    fn new(request: &CodeExecutionRequest, cwd: Option<&str>) -> Self {
+        let mut env: Vec<_> = request
+            .env_vars
+            .iter()
+            .flatten()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        env.sort();
+
+        Self {
+            language: request.language.clone(),
+            context_id: request.context_id.clone(),
+            code: request.code.clone(),
+            cwd: cwd.map(str::to_string),
+            env,
+        }
+    }
+}
+
+// Least-recently-used cache of successful executions. Each entry carries the
+// tick of its last use, and `order` maps ticks back to keys, oldest first.
+struct ExecutionCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<CacheKey, (Execution, u64)>,
+    order: BTreeMap<u64, CacheKey>,
+}
+
+impl ExecutionCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<Execution> {
+        let tick = self.next_tick();
+        let (execution, used) = self.entries.get_mut(key)?;
+        let previous = std::mem::replace(used, tick);
+        let execution = execution.clone();
+        if let Some(key) = self.order.remove(&previous) {
+            self.order.insert(tick, key);
+        }
+        Some(execution)
+    }
+
+    fn insert(&mut self, key: CacheKey, execution: Execution) {
+        if self.capacity == 0 {
+            return;
+        }
+        let tick = self.next_tick();
+        if let Some((_, previous)) = self.entries.insert(key.clone(), (execution, tick)) {
+            self.order.remove(&previous);
+        }
+        self.order.insert(tick, key);
+        while self.entries.len() > self.capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    // `None` is the default context
+    fn invalidate_context(&mut self, context_id: Option<&str>) {
+        self.entries
+            .retain(|key, _| key.context_id.as_deref() != context_id);
+        self.order
+            .retain(|_, key| key.context_id.as_deref() != context_id);
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(chunks: &[&[u8]], limit: Option<usize>) -> (Vec<String>, bool) {
+        let mut buffer = LineBuffer::new(limit);
+        let mut lines = Vec::new();
+        let mut dropped = false;
+        for chunk in chunks {
+            dropped |= buffer
+                .push(chunk, |line| {
+                    lines.push(String::from_utf8_lossy(line).into_owned())
+                })
+                .is_some();
+        }
+        lines.extend(buffer.finish().map(|l| String::from_utf8(l).unwrap()));
+        (lines, dropped)
+    }
+
+    #[test]
+    fn line_buffer_joins_lines_split_across_chunks() {
+        let (lines, dropped) = split(&[b"{\"a\"", b":1}\n{\"b\":2}\n{\"c\"", b":3}"], None);
+        assert_eq!(lines, [r#"{"a":1}"#, r#"{"b":2}"#, r#"{"c":3}"#]);
+        assert!(!dropped);
+    }
+
+    #[test]
+    fn line_buffer_discards_oversized_line_up_to_its_newline() {
+        let (lines, dropped) = split(&[b"ok\n0123456789", b"abcdef", b"ghi\nnext\n"], Some(8));
+        assert_eq!(lines, ["ok", "next"]);
+        assert!(dropped);
+    }
+
+    #[test]
+    fn results_count_against_max_output_bytes() {
+        let mut execution = CodeInterpreterApi::empty_execution();
+        let result = r#"{"type":"result","data":{"image/png":"0123456789"}}"#;
+        CodeInterpreterApi::handle_jupyter_line(&mut execution, result, 1, Some(15));
+        CodeInterpreterApi::handle_jupyter_line(&mut execution, result, 2, Some(15));
+        assert_eq!(execution.results.len(), 1);
+        assert!(execution.truncated);
+
+        let stdout = r#"{"type":"stdout","text":"abcdefghij"}"#;
+        CodeInterpreterApi::handle_jupyter_line(&mut execution, stdout, 3, Some(15));
+        assert_eq!(execution.stdout, "abcde");
+    }
+
+    #[test]
+    fn output_is_stamped_by_the_client_clock_only() {
+        let mut execution = CodeInterpreterApi::empty_execution();
+        let lines = [
+            r#"{"type":"stdout","text":"first\n"}"#,
+            // A kernel timestamp from a skewed clock must not reorder output
+            r#"{"type":"stderr","text":"second\n","timestamp":1}"#,
+            r#"{"type":"stdout","text":"third\n"}"#,
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            CodeInterpreterApi::handle_jupyter_line(&mut execution, line, i, None);
+        }
+        assert!(execution.output.iter().all(|m| m.timestamp > 1));
+        assert_eq!(execution.combined_output(), "first\nsecond\nthird\n");
+    }
+
+    fn request(code: &str, context_id: Option<&str>) -> CodeExecutionRequest {
+        CodeExecutionRequest {
+            code: code.to_string(),
+            language: Some("python".to_string()),
+            context_id: context_id.map(str::to_string),
+            env_vars: None,
+        }
+    }
+
+    fn key(code: &str, context_id: Option<&str>) -> CacheKey {
+        CacheKey::new(&request(code, context_id), None)
+    }
+
+    fn execution(stdout: &str) -> Execution {
+        Execution {
+            stdout: stdout.to_string(),
+            stderr: String::new(),
+            results: Vec::new(),
+            error: None,
+            is_main_result: false,
+            output: Vec::new(),
+            truncated: false,
+            captured: None,
+        }
+    }
+
+    #[test]
+    fn cache_hits_only_identical_requests() {
+        let mut cache = ExecutionCache::new(4);
+        cache.insert(key("1 + 1", None), execution("2"));
+
+        let hit = cache.get(&key("1 + 1", None));
+        assert_eq!(hit.map(|e| e.stdout).as_deref(), Some("2"));
+        assert!(cache.get(&key("1 + 2", None)).is_none());
+        assert!(cache.get(&key("1 + 1", Some("ctx"))).is_none());
+
+        let mut with_env = request("1 + 1", None);
+        with_env.env_vars = Some(HashMap::from([("A".to_string(), "1".to_string())]));
+        assert!(cache.get(&CacheKey::new(&with_env, None)).is_none());
+        let in_dir = CacheKey::new(&request("1 + 1", None), Some("/tmp"));
+        assert!(cache.get(&in_dir).is_none());
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let mut cache = ExecutionCache::new(2);
+        let a = key("a", None);
+        let b = key("b", None);
+        let c = key("c", None);
+        cache.insert(a.clone(), execution("a"));
+        cache.insert(b.clone(), execution("b"));
+        assert!(cache.get(&a).is_some());
+        cache.insert(c.clone(), execution("c"));
+
+        assert!(cache.get(&a).is_some());
+        assert!(cache.get(&b).is_none());
+        assert!(cache.get(&c).is_some());
+        assert_eq!(cache.order.len(), 2);
+    }
+
+    #[test]
+    fn cache_invalidates_one_context() {
+        let mut cache = ExecutionCache::new(4);
+        let default = key("x", None);
+        let other = key("x", Some("ctx"));
+        cache.insert(default.clone(), execution("default"));
+        cache.insert(other.clone(), execution("other"));
+
+        cache.invalidate_context(None);
+        assert!(cache.get(&default).is_none());
+        assert!(cache.get(&other).is_some());
+        assert_eq!(cache.order.len(), 1);
+    }
+
+    fn http_error(status: u16) -> WsError {
+        let response = tokio_tungstenite::tungstenite::http::Response::builder()
+            .status(status)
+            .body(None)
+            .unwrap();
+        WsError::Http(Box::new(response))
+    }
+
+    #[test]
+    fn only_refused_upgrades_disable_the_websocket() {
+        assert!(CodeInterpreterApi::ws_refused(&http_error(404)));
+        assert!(CodeInterpreterApi::ws_refused(&http_error(426)));
+        assert!(!CodeInterpreterApi::ws_refused(&http_error(503)));
+        assert!(!CodeInterpreterApi::ws_refused(&WsError::ConnectionClosed));
+        let io = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(!CodeInterpreterApi::ws_refused(&WsError::Io(io)));
+    }
+
+    #[test]
+    fn cwd_cells_restore_the_previous_directory() {
+        let (enter, restore) =
+            CodeInterpreterApi::cwd_cells(None, "/home/user/my \"app\"").unwrap();
+        assert_eq!(
+            enter,
+            "import os as __e2b_os\n__e2b_prev_cwd = __e2b_os.getcwd()\n__e2b_os.chdir(\"/home/user/my \\\"app\\\"\")"
+        );
+        assert!(restore.starts_with("__e2b_os.chdir(__e2b_prev_cwd)"));
+
+        let (enter, _) = CodeInterpreterApi::cwd_cells(Some("bash"), "/a b").unwrap();
+        assert!(enter.ends_with("cd '/a b'"));
+        assert!(CodeInterpreterApi::cwd_cells(Some("java"), "/tmp").is_err());
+    }
+
+    #[test]
+    fn capture_cell_rejects_nan_and_cleans_up_its_helpers() {
+        let cell = CodeInterpreterApi::capture_cell("df", None).unwrap();
+        assert!(cell.contains("__e2b_value = df\n"));
+        assert!(cell.contains("allow_nan=False"));
+        assert!(cell.contains("except ValueError:"));
+        assert!(cell.ends_with("del __e2b_json, __e2b_math, __e2b_finite, __e2b_value"));
+        assert!(cell.contains("del __e2b_pd"));
+
+        assert!(CodeInterpreterApi::capture_cell("a; b", None).is_err());
+        assert!(CodeInterpreterApi::capture_cell("x", Some("r")).is_err());
+    }
+
+    #[test]
+    fn zero_capacity_cache_stores_nothing() {
+        let mut cache = ExecutionCache::new(0);
+        let key = key("x", None);
+        cache.insert(key.clone(), execution("x"));
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn relay_drops_messages_past_its_limit() {
+        let (relay, mut relayed) = Relay::new(8);
+        relay.send(ExecutionMessage::Stdout("12345".to_string()));
+        relay.send(ExecutionMessage::Stderr("6789".to_string()));
+        relay.send(ExecutionMessage::Stdout("678".to_string()));
+
+        let first = relayed.try_recv().unwrap();
+        assert!(matches!(&first, ExecutionMessage::Stdout(line) if line == "12345"));
+        assert!(
+            matches!(relayed.try_recv().unwrap(), ExecutionMessage::Stdout(line) if line == "678")
+        );
+        assert!(relayed.try_recv().is_err());
+
+        // Taking a message off the relay frees its share of the limit
+        relay
+            .buffered
+            .fetch_sub(Relay::size(&first), Ordering::SeqCst);
+        relay.send(ExecutionMessage::Stdout("abcd".to_string()));
+        assert!(relayed.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn captured_nan_becomes_null() {
+        let sandbox = crate::test_support::live_sandbox_from("code-interpreter-v1").await;
+        let interpreter = sandbox.code_interpreter().unwrap();
+        let options = CodeInterpreterOptions {
+            capture_variable: Some("values".to_string()),
+            ..Default::default()
+        };
+
+        let execution = interpreter
+            .run_code_with_options("values = [1.5, float('nan')]", &options)
+            .await
+            .unwrap();
+        let leaked = interpreter
+            .run_code_with_options("print('__e2b_value' in globals())", &Default::default())
+            .await
+            .unwrap();
+
+        assert_eq!(execution.captured, Some(serde_json::json!([1.5, null])));
+        assert_eq!(leaked.stdout.trim(), "False");
+        sandbox.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn streaming_run_finishes_before_channel_is_read() {
+        let sandbox = crate::test_support::live_sandbox_from("code-interpreter-v1").await;
+        let code = format!(
+            "for i in range({}):\n    print(i, flush=True)",
+            4 * STREAMING_CHANNEL_CAPACITY
+        );
+
+        let (mut messages, run) = sandbox.run_python_streaming(&code).unwrap();
+        let execution = run.await.unwrap();
+        let mut received = 0;
+        while messages.recv().await.is_some() {
+            received += 1;
+        }
+
+        assert!(execution.error.is_none());
+        assert!(received > STREAMING_CHANNEL_CAPACITY);
+        sandbox.delete().await.unwrap();
+    }
 }
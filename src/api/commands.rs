@@ -1,24 +1,290 @@
 use crate::{
+    client::Client,
+    config::Config,
     error::{Error, Result},
-    models::{CommandHandle, CommandOptions, CommandOutput, CommandResult, ProcessInfo},
-    rpc::RpcClient,
+    models::{
+        CommandAuditEntry, CommandHandle, CommandOptions, CommandOutput, CommandResult,
+        OutputEncoding, ProcessInfo, ShellKind, StdStream, WriteEntry,
+    },
+    rpc::{temp_path, RpcClient},
 };
 use base64::{engine::general_purpose, Engine};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use futures::stream::{self, Stream};
 use serde_json::json;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, PoisonError};
 use std::time::Duration;
-use tokio::sync::{mpsc, oneshot};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, OnceCell};
 use tokio::time::timeout;
 
+type AuditCallback = Arc<dyn Fn(&CommandAuditEntry) + Send + Sync>;
+
+// Kills the process group `$1` leads or, when it doesn't lead one, `$1` and
+// all its descendants, so the children of pipelines and `a && b` go too
+const KILL_TREE_SCRIPT: &str = r#"pgid=$(sed 's/.*) . [0-9-]* \([0-9-]*\) .*/\1/' "/proc/$1/stat" 2>/dev/null) || exit 0
+if [ "$pgid" = "$1" ]; then exec kill -KILL -- "-$1"; fi
+tree() { echo "$1"; for c in $(cat /proc/"$1"/task/*/children 2>/dev/null); do tree "$c"; done; }
+kill -KILL $(tree "$1") 2>/dev/null
+exit 0"#;
+
+// Identifies a run with a timeout, so it can be killed even when the
+// timeout fires before its start event has delivered the PID
+struct TimedRun {
+    tag: String,
+    pid: std::sync::OnceLock<u32>,
+}
+
+impl TimedRun {
+    fn new() -> Self {
+        Self {
+            tag: format!("e2b-timed-{}", uuid::Uuid::new_v4().simple()),
+            pid: std::sync::OnceLock::new(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct AuditLog {
+    enabled: bool,
+    // Keyed by a sequence number so background entries can be completed
+    entries: VecDeque<(u64, CommandAuditEntry)>,
+    next_id: u64,
+    callback: Option<AuditCallback>,
+}
+
+impl AuditLog {
+    fn push(&mut self, entry: CommandAuditEntry) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        if self.entries.len() == CommandsApi::MAX_HISTORY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((id, entry));
+        id
+    }
+}
+
+// A recorded background command, completed once the process exits
+struct BackgroundAudit {
+    log: Arc<std::sync::Mutex<AuditLog>>,
+    id: u64,
+    entry: CommandAuditEntry,
+    started: std::time::Instant,
+}
+
+impl BackgroundAudit {
+    fn finish(mut self, exit_code: Option<i32>) {
+        self.entry.exit_code = exit_code;
+        self.entry.duration = Some(self.started.elapsed());
+
+        let mut log = self.log.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, slot)) = log.entries.iter_mut().find(|(id, _)| *id == self.id) {
+            *slot = self.entry.clone();
+        }
+        let callback = log.callback.clone();
+        drop(log);
+        if let Some(callback) = callback {
+            callback(&self.entry);
+        }
+    }
+}
+
+// Decodes one output stream chunk by chunk for live consumers, holding back
+// a multi-byte character split across chunks until the rest of it arrives.
+// Invalid sequences are replaced, since a streamed chunk can't fail the run.
+#[derive(Default)]
+struct ChunkDecoder {
+    partial: Vec<u8>,
+}
+
+impl ChunkDecoder {
+    fn push(&mut self, chunk: &[u8]) -> String {
+        self.partial.extend_from_slice(chunk);
+        let cut = self.partial.len() - Self::incomplete_tail(&self.partial);
+        let text = String::from_utf8_lossy(&self.partial[..cut]).into_owned();
+        self.partial.drain(..cut);
+        text
+    }
+
+    fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.partial).into_owned();
+        self.partial.clear();
+        text
+    }
+
+    // Length of a character at the end of `bytes` that is still missing bytes
+    fn incomplete_tail(bytes: &[u8]) -> usize {
+        for back in 1..=bytes.len().min(3) {
+            let byte = bytes[bytes.len() - back];
+            if byte & 0xC0 == 0x80 {
+                continue;
+            }
+            let needed = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => return 0,
+            };
+            return if needed > back { back } else { 0 };
+        }
+        0
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct CommandsApi {
     rpc_client: Option<Arc<RpcClient>>,
+    default_shell: ShellKind,
+    default_env: HashMap<String, String>,
+    audit: Arc<std::sync::Mutex<AuditLog>>,
+    rest_fallback: Option<RestFallback>,
+}
+
+// Where to send commands over REST when envd's process RPC is unreachable
+#[derive(Clone)]
+struct RestFallback {
+    client: Client,
+    sandbox_id: String,
 }
 
 impl CommandsApi {
+    /// Entries kept by `history`; older ones are dropped first.
+    pub const MAX_HISTORY: usize = 1000;
+
     pub fn new() -> Self {
-        Self { rpc_client: None }
+        Self {
+            rpc_client: None,
+            default_shell: ShellKind::default(),
+            default_env: HashMap::new(),
+            audit: Arc::default(),
+            rest_fallback: None,
+        }
+    }
+
+    /// Lets `run_with_options` fall back to the REST commands endpoint of
+    /// `sandbox_id` while no RPC client is connected.
+    pub(crate) fn set_rest_fallback(&mut self, client: Client, sandbox_id: &str) {
+        self.rest_fallback = Some(RestFallback {
+            client,
+            sandbox_id: sandbox_id.to_string(),
+        });
+    }
+
+    /// Starts recording every command run through this API (and its
+    /// clones) in memory; see `history`.
+    pub fn enable_history(&self) {
+        self.audit_log().enabled = true;
+    }
+
+    /// Registers a callback invoked with each recorded command, e.g. to
+    /// export it to an external audit trail. Background commands are passed
+    /// once they exit. Also enables recording.
+    pub fn on_command<F>(&self, callback: F)
+    where
+        F: Fn(&CommandAuditEntry) + Send + Sync + 'static,
+    {
+        let mut audit = self.audit_log();
+        audit.enabled = true;
+        audit.callback = Some(Arc::new(callback));
+    }
+
+    /// Commands recorded since `enable_history`, oldest first, up to the
+    /// last `MAX_HISTORY`.
+    pub fn history(&self) -> Vec<CommandAuditEntry> {
+        self.audit_log()
+            .entries
+            .iter()
+            .map(|(_, entry)| entry.clone())
+            .collect()
+    }
+
+    fn audit_log(&self) -> std::sync::MutexGuard<'_, AuditLog> {
+        self.audit.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn record(&self, entry: impl FnOnce() -> CommandAuditEntry) {
+        let mut audit = self.audit_log();
+        if !audit.enabled {
+            return;
+        }
+        let entry = entry();
+        if let Some(callback) = audit.callback.clone() {
+            // Don't hold the lock while running user code
+            drop(audit);
+            callback(&entry);
+            audit = self.audit_log();
+        }
+        audit.push(entry);
+    }
+
+    // Records a background command as started; the entry is completed, and
+    // passed to the callback, by `BackgroundAudit::finish`
+    fn record_started(&self, command: &str, started_at: DateTime<Utc>) -> Option<BackgroundAudit> {
+        let mut audit = self.audit_log();
+        if !audit.enabled {
+            return None;
+        }
+        let entry = CommandAuditEntry {
+            command: command.to_string(),
+            started_at,
+            exit_code: None,
+            duration: None,
+            background: true,
+        };
+        let id = audit.push(entry.clone());
+        Some(BackgroundAudit {
+            log: self.audit.clone(),
+            id,
+            entry,
+            started: std::time::Instant::now(),
+        })
+    }
+
+    async fn audited<T, F>(
+        &self,
+        command: impl FnOnce() -> String,
+        run: F,
+        exit_code: impl FnOnce(&T) -> i32,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>>,
+    {
+        let started_at = Utc::now();
+        let started = std::time::Instant::now();
+        let result = run.await;
+        self.record(|| CommandAuditEntry {
+            command: command(),
+            started_at,
+            exit_code: result.as_ref().ok().map(exit_code),
+            duration: Some(started.elapsed()),
+            background: false,
+        });
+        result
+    }
+
+    pub fn set_default_shell(&mut self, shell: ShellKind) {
+        self.default_shell = shell;
+    }
+
+    /// Environment applied to every command; `CommandOptions::envs` entries
+    /// take precedence over it.
+    pub fn set_default_env(&mut self, envs: HashMap<String, String>) {
+        self.default_env = envs;
+    }
+
+    fn merged_envs(&self, options: &CommandOptions) -> HashMap<String, String> {
+        // Without inheritance the variables are passed to `env -i` instead
+        if !options.inherit_env {
+            return HashMap::new();
+        }
+        let mut envs = self.default_env.clone();
+        if let Some(overrides) = &options.envs {
+            envs.extend(overrides.clone());
+        }
+        envs
     }
 
     pub async fn init_rpc(&mut self, envd_url: &str, access_token: Option<&str>) -> Result<()> {
@@ -27,10 +293,40 @@ impl CommandsApi {
         Ok(())
     }
 
+    pub async fn init_rpc_with_config(
+        &mut self,
+        envd_url: &str,
+        access_token: Option<&str>,
+        config: &Config,
+    ) -> Result<()> {
+        let rpc_client = RpcClient::connect_with_config(envd_url, access_token, config).await?;
+        self.rpc_client = Some(Arc::new(rpc_client));
+        Ok(())
+    }
+
+    pub(crate) fn set_rpc_client(&mut self, rpc_client: RpcClient) {
+        self.rpc_client = Some(Arc::new(rpc_client));
+    }
+
+    // Like `init_rpc_with_config`, but the first call waits for envd to come
+    // up, polling once per `ready` gate
+    pub(crate) async fn init_rpc_deferred(
+        &mut self,
+        envd_url: &str,
+        access_token: Option<&str>,
+        config: &Config,
+        ready: Arc<OnceCell<()>>,
+    ) -> Result<()> {
+        let rpc_client = RpcClient::connect_with_config(envd_url, access_token, config)
+            .await?
+            .with_readiness_gate(ready);
+        self.rpc_client = Some(Arc::new(rpc_client));
+        Ok(())
+    }
+
     fn get_rpc_client(&self) -> Result<&Arc<RpcClient>> {
-        self.rpc_client.as_ref().ok_or_else(|| Error::Api {
-            status: 500,
-            message: "RPC client not initialized. Call init_rpc first.".to_string(),
+        self.rpc_client.as_ref().ok_or_else(|| {
+            Error::Configuration("RPC client not initialized. Call init_rpc first.".to_string())
         })
     }
 
@@ -50,6 +346,122 @@ impl CommandsApi {
         self.run_with_options(cmd, &options).await
     }
 
+    /// Runs `template` after replacing every `{{name}}` placeholder with the
+    /// shell-escaped value of `vars[name]`, so substituted values can never
+    /// be interpreted as shell syntax.
+    pub async fn run_template(
+        &self,
+        template: &str,
+        vars: &HashMap<String, String>,
+    ) -> Result<CommandResult> {
+        let cmd = Self::render_template(template, vars)?;
+        self.run(&cmd).await
+    }
+
+    fn render_template(template: &str, vars: &HashMap<String, String>) -> Result<String> {
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(start) = rest.find("{{") {
+            rendered.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let end = after.find("}}").ok_or_else(|| {
+                Error::Configuration(format!(
+                    "Unclosed placeholder in command template: {}",
+                    template
+                ))
+            })?;
+            let name = after[..end].trim();
+            let value = vars.get(name).ok_or_else(|| {
+                Error::Configuration(format!("Missing value for placeholder '{}'", name))
+            })?;
+            rendered.push_str(&shell_escape(value));
+            rest = &after[end + 2..];
+        }
+        rendered.push_str(rest);
+
+        Ok(rendered)
+    }
+
+    /// Uploads `script` to a temporary file, runs it with `interpreter`
+    /// (e.g. `python3`, `bash`, `node`) and removes the file afterwards,
+    /// avoiding heredoc quoting issues for arbitrary script content.
+    pub async fn run_script(
+        &self,
+        script: &str,
+        interpreter: &str,
+        options: &CommandOptions,
+    ) -> Result<CommandResult> {
+        let rpc_client = self.get_rpc_client()?;
+        let path = temp_path("e2b-script-", "");
+
+        rpc_client
+            .filesystem_upload(vec![WriteEntry::text(path.clone(), script)], "user")
+            .await?;
+
+        let cmd = format!("{} {}", interpreter, shell_escape(&path));
+        let result = self.run_with_options(&cmd, options).await;
+
+        if let Err(e) = rpc_client
+            .filesystem_remove(json!({ "path": path, "username": "user" }))
+            .await
+        {
+            tracing::warn!("Failed to remove script {}: {}", path, e);
+        }
+
+        result
+    }
+
+    /// Uploads `local_input` to a temporary file and runs `cmd` with its stdin
+    /// redirected from that file. The upload is streamed from disk, which
+    /// avoids the base64 overhead of `send_stdin` for very large inputs. The
+    /// temporary file is removed even if the command fails.
+    pub async fn run_with_file_input(
+        &self,
+        cmd: &str,
+        local_input: &Path,
+        options: &CommandOptions,
+    ) -> Result<CommandResult> {
+        let rpc_client = self.get_rpc_client()?;
+        let path = temp_path("e2b-input-", "");
+
+        let result = async {
+            rpc_client
+                .filesystem_upload_file(local_input, &path, "user")
+                .await?;
+            let cmd = format!("( {} ) < {}", cmd, shell_escape(&path));
+            self.run_with_options(&cmd, options).await
+        }
+        .await;
+
+        if let Err(e) = rpc_client
+            .filesystem_remove(json!({ "path": path, "username": "user" }))
+            .await
+        {
+            tracing::debug!("Failed to remove input file {}: {}", path, e);
+        }
+
+        result
+    }
+
+    /// Resolves `program` via `command -v`, returning `None` when it isn't installed.
+    pub async fn which(&self, program: &str) -> Result<Option<String>> {
+        let result = self
+            .run(&format!("command -v {}", shell_escape(program)))
+            .await?;
+
+        if result.exit_code != 0 {
+            return Ok(None);
+        }
+
+        let path = result.stdout.trim();
+        if path.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(path.to_string()))
+        }
+    }
+
     pub async fn run_background(&self, cmd: &str) -> Result<CommandHandle> {
         let options = CommandOptions {
             background: true,
@@ -64,18 +476,298 @@ impl CommandsApi {
         options: &CommandOptions,
     ) -> Result<CommandResult> {
         if options.background {
-            return Err(Error::Api {
-                status: 400,
-                message: "Use run_background for background commands".to_string(),
-            });
+            return Err(Error::Configuration(
+                "Use run_background for background commands".to_string(),
+            ));
         }
 
-        if let Some(timeout_duration) = options.timeout {
-            timeout(timeout_duration, self.execute_command(cmd, options))
-                .await
-                .map_err(|_| Error::Timeout)?
-        } else {
-            self.execute_command(cmd, options).await
+        if let (None, Some(fallback)) = (&self.rpc_client, &self.rest_fallback) {
+            return self
+                .audited(
+                    || cmd.to_string(),
+                    self.run_over_rest(fallback, cmd, options),
+                    |result| result.exit_code,
+                )
+                .await;
+        }
+
+        let (command, args) = self.build_shell_command(cmd, options);
+        self.audited(
+            || cmd.to_string(),
+            self.execute_with_timeout(&command, &args, options),
+            |result| result.exit_code,
+        )
+        .await
+    }
+
+    /// Best-effort execution through `POST /sandboxes/{id}/commands`, used
+    /// when the process RPC could not be connected. Output arrives only once
+    /// the command finishes, and streaming, stdin, background commands and
+    /// `OutputEncoding::Binary` are unavailable. The command line and
+    /// environment are built the same way as for the RPC path.
+    async fn run_over_rest(
+        &self,
+        fallback: &RestFallback,
+        cmd: &str,
+        options: &CommandOptions,
+    ) -> Result<CommandResult> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct RestCommandResult {
+            #[serde(default)]
+            stdout: String,
+            #[serde(default)]
+            stderr: String,
+            #[serde(default, alias = "exit_code")]
+            exit_code: Option<i32>,
+        }
+
+        if options.output_encoding == OutputEncoding::Binary {
+            return Err(Error::Configuration(
+                "OutputEncoding::Binary needs the process RPC".to_string(),
+            ));
+        }
+
+        tracing::debug!("Process RPC unavailable, running command over REST");
+        let command_line = Self::rest_command_line(self.build_shell_command(cmd, options));
+        let client = &fallback.client;
+        let url = client.build_url(&format!("/sandboxes/{}/commands", fallback.sandbox_id));
+        let mut request = client.post(&url).json(&json!({
+            "cmd": command_line,
+            "envs": self.merged_envs(options),
+            "cwd": options.cwd,
+            "timeout": options.timeout.map(|t| t.as_secs()),
+        }));
+        if let Some(limit) = options.timeout {
+            request = request.timeout(limit);
+        }
+
+        let response = request.send().await.map_err(|e| match e {
+            Error::Http(e) if e.is_timeout() => Error::Timeout,
+            other => other,
+        })?;
+        match response.status() {
+            reqwest::StatusCode::OK => {
+                let result: RestCommandResult = response.json().await?;
+                Ok(CommandResult {
+                    stdout: result.stdout,
+                    stderr: result.stderr,
+                    exit_code: result.exit_code.unwrap_or(-1),
+                    execution_time: None,
+                    stdout_bytes: None,
+                    stderr_bytes: None,
+                    transcript: Vec::new(),
+                })
+            }
+            reqwest::StatusCode::NOT_FOUND => Err(Error::NotFound(format!(
+                "Sandbox {} or its REST commands endpoint",
+                fallback.sandbox_id
+            ))),
+            status => {
+                let error_text = response.text().await.unwrap_or_default();
+                Err(Error::Envd {
+                    status: status.as_u16(),
+                    message: error_text,
+                })
+            }
+        }
+    }
+
+    /// Runs `program` with `args` directly, without wrapping it in a shell.
+    /// Arguments are passed verbatim, so no quoting or escaping is needed.
+    pub async fn run_argv(
+        &self,
+        program: &str,
+        args: &[&str],
+        options: &CommandOptions,
+    ) -> Result<CommandResult> {
+        if options.background {
+            return Err(Error::Configuration(
+                "Use run_background for background commands".to_string(),
+            ));
+        }
+
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        self.audited(
+            || {
+                std::iter::once(program)
+                    .chain(args.iter().map(String::as_str))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            },
+            self.execute_with_timeout(program, &args, options),
+            |result| result.exit_code,
+        )
+        .await
+    }
+
+    /// Runs `cmd`, writing its stdout and stderr to the given writers as the
+    /// output arrives instead of buffering it, and returns the exit code.
+    pub async fn run_to_writer<W, E>(
+        &self,
+        cmd: &str,
+        stdout: W,
+        stderr: E,
+        options: &CommandOptions,
+    ) -> Result<i32>
+    where
+        W: AsyncWrite + Unpin,
+        E: AsyncWrite + Unpin,
+    {
+        self.audited(
+            || cmd.to_string(),
+            self.forward_to_writers(cmd, stdout, stderr, options),
+            |exit_code| *exit_code,
+        )
+        .await
+    }
+
+    async fn forward_to_writers<W, E>(
+        &self,
+        cmd: &str,
+        mut stdout: W,
+        mut stderr: E,
+        options: &CommandOptions,
+    ) -> Result<i32>
+    where
+        W: AsyncWrite + Unpin,
+        E: AsyncWrite + Unpin,
+    {
+        let rpc_client = self.get_rpc_client()?;
+        Self::check_cwd(rpc_client, options).await?;
+
+        let (command, args) = self.build_shell_command(cmd, options);
+        let mut params = json!({
+            "process": {
+                "cmd": command,
+                "args": args,
+                "envs": self.merged_envs(options),
+                "cwd": options.cwd
+            }
+        });
+        let run = TimedRun::new();
+        if options.timeout.is_some() {
+            params["tag"] = json!(run.tag);
+        }
+
+        let forward = async {
+            let mut stream = rpc_client.process_start(params).await?;
+            let mut exit_code = None;
+
+            while let Some(event) = stream.next_event().await? {
+                match event.event {
+                    crate::rpc::ProcessEventData::Data { data } => {
+                        if let Some(chunk) = &data.stdout {
+                            Self::forward_output(chunk, &mut stdout, "stdout").await?;
+                        }
+                        if let Some(chunk) = &data.stderr {
+                            Self::forward_output(chunk, &mut stderr, "stderr").await?;
+                        }
+                    }
+                    crate::rpc::ProcessEventData::End { end } => {
+                        exit_code = end.exit_code.or_else(|| {
+                            end.status
+                                .split("exit status ")
+                                .nth(1)
+                                .and_then(|code| code.trim().parse().ok())
+                        });
+                        break;
+                    }
+                    crate::rpc::ProcessEventData::Start { start } => {
+                        let _ = run.pid.set(start.pid);
+                    }
+                }
+            }
+
+            Ok(exit_code.unwrap_or(-1))
+        };
+
+        let Some(timeout_duration) = options.timeout else {
+            return forward.await;
+        };
+        match timeout(timeout_duration, forward).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.kill_timed_out(&run).await;
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    async fn kill_timed_out(&self, run: &TimedRun) {
+        let pid = match run.pid.get() {
+            Some(pid) => Some(*pid),
+            None => self.find_tagged(&run.tag).await,
+        };
+        let Some(pid) = pid else {
+            tracing::warn!("Timed-out command {} never showed up to be killed", run.tag);
+            return;
+        };
+
+        tracing::debug!(
+            "Command timed out, killing process {} and its children",
+            pid
+        );
+        let args = ["-c", KILL_TREE_SCRIPT, "sh", &pid.to_string()].map(String::from);
+        let killed = self
+            .execute_command("/bin/sh", &args, &CommandOptions::default(), None)
+            .await;
+        match killed {
+            Ok(result) if result.exit_code == 0 => {}
+            Ok(result) => tracing::warn!(
+                "Failed to kill timed-out process {}: {}",
+                pid,
+                result.stderr.trim()
+            ),
+            Err(e) => tracing::warn!("Failed to kill timed-out process {}: {}", pid, e),
+        }
+    }
+
+    // envd may register a process shortly after the start request that
+    // timed out, so look for its tag a few times
+    async fn find_tagged(&self, tag: &str) -> Option<u32> {
+        for _ in 0..5 {
+            if let Ok(processes) = self.list().await {
+                if let Some(process) = processes.iter().find(|p| p.tag.as_deref() == Some(tag)) {
+                    return Some(process.pid);
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        None
+    }
+
+    async fn forward_output<W: AsyncWrite + Unpin>(
+        encoded: &str,
+        writer: &mut W,
+        stream: &str,
+    ) -> Result<()> {
+        let decoded = general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|e| Error::undecodable(format!("Failed to decode {}", stream), e))?;
+        writer.write_all(&decoded).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    async fn execute_with_timeout(
+        &self,
+        command: &str,
+        args: &[String],
+        options: &CommandOptions,
+    ) -> Result<CommandResult> {
+        let Some(timeout_duration) = options.timeout else {
+            return self.execute_command(command, args, options, None).await;
+        };
+
+        let run = TimedRun::new();
+        let execute = self.execute_command(command, args, options, Some(&run));
+        match timeout(timeout_duration, execute).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.kill_timed_out(&run).await;
+                Err(Error::Timeout)
+            }
         }
     }
 
@@ -87,63 +779,91 @@ impl CommandsApi {
         self.start_command(cmd, options).await
     }
 
-    async fn execute_command(&self, cmd: &str, options: &CommandOptions) -> Result<CommandResult> {
-        let rpc_client = self.get_rpc_client()?;
+    /// Starts `cmd` and returns a handle that owns the process's event
+    /// stream, so output can be streamed and the result awaited without
+    /// reconnecting by PID.
+    pub async fn start(&self, cmd: &str, options: &CommandOptions) -> Result<RunningCommand> {
+        let handle = self.start_command(cmd, options).await?;
+        Ok(RunningCommand {
+            commands: self.clone(),
+            handle,
+        })
+    }
 
-        let (command, args) = Self::build_shell_command(cmd);
+    async fn execute_command(
+        &self,
+        command: &str,
+        args: &[String],
+        options: &CommandOptions,
+        timed: Option<&TimedRun>,
+    ) -> Result<CommandResult> {
+        let rpc_client = self.get_rpc_client()?;
+        Self::check_cwd(rpc_client, options).await?;
 
         // StartRequest has a ProcessConfig field named "process"
-        let params = json!({
+        let mut params = json!({
             "process": {
                 "cmd": command,
                 "args": args,
-                "envs": options.envs.clone().unwrap_or_default(),
+                "envs": self.merged_envs(options),
                 "cwd": options.cwd
             }
         });
+        if let Some(timed) = timed {
+            params["tag"] = json!(timed.tag);
+        }
 
         let mut stream = rpc_client.process_start(params).await?;
-        let mut stdout = String::new();
-        let mut stderr = String::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut transcript = Vec::new();
         let mut exit_code = None;
-        let mut _pid = None;
 
-        // Process all events from the stream
+        // Process all events from the stream. Output is kept as raw bytes until
+        // the end so multi-byte characters split across chunks decode correctly.
         while let Some(event) = stream.next_event().await? {
             match event.event {
                 crate::rpc::ProcessEventData::Start { start } => {
-                    _pid = Some(start.pid);
+                    if let Some(timed) = timed {
+                        let _ = timed.pid.set(start.pid);
+                    }
                 }
                 crate::rpc::ProcessEventData::Data { data } => {
                     if let Some(stdout_data) = &data.stdout {
                         // Decode Base64 stdout data
                         let decoded =
                             general_purpose::STANDARD.decode(stdout_data).map_err(|e| {
-                                Error::Api {
-                                    status: 500,
-                                    message: format!("Failed to decode stdout: {}", e),
-                                }
+                                Self::malformed_frame(
+                                    command,
+                                    args,
+                                    "stdout",
+                                    stdout.len(),
+                                    stdout_data,
+                                    e,
+                                )
                             })?;
-                        let text = String::from_utf8(decoded).map_err(|e| Error::Api {
-                            status: 500,
-                            message: format!("Failed to convert stdout to UTF-8: {}", e),
-                        })?;
-                        stdout.push_str(&text);
+                        if options.capture_transcript {
+                            transcript.push(Self::transcript_chunk(StdStream::Stdout, &decoded));
+                        }
+                        stdout.extend_from_slice(&decoded);
                     }
                     if let Some(stderr_data) = &data.stderr {
                         // Decode Base64 stderr data
                         let decoded =
                             general_purpose::STANDARD.decode(stderr_data).map_err(|e| {
-                                Error::Api {
-                                    status: 500,
-                                    message: format!("Failed to decode stderr: {}", e),
-                                }
+                                Self::malformed_frame(
+                                    command,
+                                    args,
+                                    "stderr",
+                                    stderr.len(),
+                                    stderr_data,
+                                    e,
+                                )
                             })?;
-                        let text = String::from_utf8(decoded).map_err(|e| Error::Api {
-                            status: 500,
-                            message: format!("Failed to convert stderr to UTF-8: {}", e),
-                        })?;
-                        stderr.push_str(&text);
+                        if options.capture_transcript {
+                            transcript.push(Self::transcript_chunk(StdStream::Stderr, &decoded));
+                        }
+                        stderr.extend_from_slice(&decoded);
                     }
                 }
                 crate::rpc::ProcessEventData::End { end } => {
@@ -163,82 +883,186 @@ impl CommandsApi {
             }
         }
 
+        let encoding = options.output_encoding;
         Ok(CommandResult {
-            stdout,
-            stderr,
+            stdout: Self::decode_output(&stdout, encoding, "stdout")?,
+            stderr: Self::decode_output(&stderr, encoding, "stderr")?,
             exit_code: exit_code.unwrap_or(-1),
             execution_time: None,
+            stdout_bytes: (encoding == OutputEncoding::Binary).then_some(stdout),
+            stderr_bytes: (encoding == OutputEncoding::Binary).then_some(stderr),
+            transcript,
         })
     }
 
+    // Chunks may split a multi-byte character, so each is decoded lossily
+    fn transcript_chunk(stream: StdStream, chunk: &[u8]) -> (StdStream, String, DateTime<Utc>) {
+        (
+            stream,
+            String::from_utf8_lossy(chunk).into_owned(),
+            Utc::now(),
+        )
+    }
+
+    // A chunk that is not valid base64 means the envd frame itself is
+    // corrupt, not that the process failed, so report it as a bad gateway
+    // response with enough context to find the offending command.
+    fn malformed_frame(
+        command: &str,
+        args: &[String],
+        stream: &str,
+        offset: usize,
+        chunk: &str,
+        source: base64::DecodeError,
+    ) -> Error {
+        const MAX_COMMAND_CHARS: usize = 80;
+
+        let mut cmdline = std::iter::once(command)
+            .chain(args.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if let Some((idx, _)) = cmdline.char_indices().nth(MAX_COMMAND_CHARS) {
+            cmdline.truncate(idx);
+            cmdline.push_str("...");
+        }
+
+        Error::wrap(
+            502,
+            format!(
+                "Malformed process output frame: invalid base64 in {} chunk of {} bytes at byte offset {} (command: {})",
+                stream,
+                chunk.len(),
+                offset,
+                cmdline
+            ),
+            source,
+        )
+    }
+
+    // The REST endpoint takes a single command line rather than an argv
+    fn rest_command_line((command, args): (String, Vec<String>)) -> String {
+        std::iter::once(command)
+            .chain(args)
+            .map(|word| shell_escape(&word))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn decode_output(bytes: &[u8], encoding: OutputEncoding, stream: &str) -> Result<String> {
+        match encoding {
+            OutputEncoding::Utf8 => String::from_utf8(bytes.to_vec()).map_err(|e| {
+                Error::undecodable(format!("Failed to convert {} to UTF-8", stream), e)
+            }),
+            OutputEncoding::Utf8Lossy | OutputEncoding::Binary => {
+                Ok(String::from_utf8_lossy(bytes).into_owned())
+            }
+        }
+    }
+
+    // A background run has no way to fail once started, so output that isn't
+    // valid UTF-8 under `OutputEncoding::Utf8` is decoded lossily instead
+    fn decode_background(bytes: &[u8], encoding: OutputEncoding, stream: &str) -> String {
+        Self::decode_output(bytes, encoding, stream).unwrap_or_else(|e| {
+            tracing::warn!("{}; replacing invalid sequences", e);
+            String::from_utf8_lossy(bytes).into_owned()
+        })
+    }
+
+    async fn check_cwd(rpc_client: &RpcClient, options: &CommandOptions) -> Result<()> {
+        let Some(cwd) = options.cwd.as_deref().filter(|_| options.verify_cwd) else {
+            return Ok(());
+        };
+
+        let params = json!({
+            "path": cwd,
+            "username": "user"
+        });
+
+        match rpc_client.filesystem_stat(params).await {
+            Ok(response) if response["entry"]["type"] == "FILE_TYPE_DIRECTORY" => Ok(()),
+            Ok(_) => Err(Error::NotFound(format!(
+                "Working directory {} is not a directory",
+                cwd
+            ))),
+            Err(Error::Envd { status: 404, .. }) => {
+                Err(Error::NotFound(format!("Working directory {}", cwd)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     async fn start_command(&self, cmd: &str, options: &CommandOptions) -> Result<CommandHandle> {
+        let started_at = Utc::now();
         let rpc_client = self.get_rpc_client()?;
+        Self::check_cwd(rpc_client, options).await?;
 
-        let (command, args) = Self::build_shell_command(cmd);
+        let (command, args) = self.build_shell_command(cmd, options);
 
         // StartRequest has a ProcessConfig field named "process"
         let params = json!({
             "process": {
                 "cmd": command,
                 "args": args,
-                "envs": options.envs.clone().unwrap_or_default(),
+                "envs": self.merged_envs(options),
                 "cwd": options.cwd
             }
         });
 
         let mut stream = rpc_client.process_start(params).await?;
+        // Output seen before the start event explains an immediate failure
+        let mut early_stderr = Vec::new();
 
         // Process all events in the stream to find the start event
         while let Some(event) = stream.next_event().await? {
             match event.event {
                 crate::rpc::ProcessEventData::Start { start } => {
                     let pid = start.pid;
+                    let audit = self.record_started(cmd, started_at);
 
                     let (stdout_tx, stdout_rx) = mpsc::channel(100);
                     let (stderr_tx, stderr_rx) = mpsc::channel(100);
                     let (result_tx, result_rx) = oneshot::channel();
 
                     let mut stream = stream;
+                    let capture_transcript = options.capture_transcript;
+                    let encoding = options.output_encoding;
                     tokio::spawn(async move {
-                        let stdout_sender = stdout_tx;
-                        let stderr_sender = stderr_tx;
-                        let mut stdout_acc = String::new();
-                        let mut stderr_acc = String::new();
+                        let senders = [
+                            (StdStream::Stdout, stdout_tx),
+                            (StdStream::Stderr, stderr_tx),
+                        ];
+                        let mut decoders = [ChunkDecoder::default(), ChunkDecoder::default()];
+                        let mut raw = [Vec::new(), Vec::new()];
+                        let mut transcript = Vec::new();
                         let mut exit_code = None;
-                        let mut execution_time = None;
+                        let execution_time = None;
 
                         while let Ok(Some(event)) = stream.next_event().await {
                             match event.event {
                                 crate::rpc::ProcessEventData::Data { data } => {
-                                    if let Some(stdout_data) = data.stdout.as_ref() {
-                                        if let Ok(decoded) =
-                                            general_purpose::STANDARD.decode(stdout_data)
-                                        {
-                                            if let Ok(text) = String::from_utf8(decoded.clone()) {
-                                                stdout_acc.push_str(&text);
-                                                let _ = stdout_sender
-                                                    .send(CommandOutput {
-                                                        data: text,
-                                                        timestamp: Utc::now(),
-                                                    })
-                                                    .await;
-                                            }
+                                    let chunks = [data.stdout, data.stderr];
+                                    for (i, chunk) in chunks.iter().enumerate() {
+                                        let Some(decoded) = chunk.as_ref().and_then(|chunk| {
+                                            general_purpose::STANDARD.decode(chunk).ok()
+                                        }) else {
+                                            continue;
+                                        };
+                                        raw[i].extend_from_slice(&decoded);
+                                        let text = decoders[i].push(&decoded);
+                                        if text.is_empty() {
+                                            continue;
                                         }
-                                    }
-                                    if let Some(stderr_data) = data.stderr.as_ref() {
-                                        if let Ok(decoded) =
-                                            general_purpose::STANDARD.decode(stderr_data)
-                                        {
-                                            if let Ok(text) = String::from_utf8(decoded.clone()) {
-                                                stderr_acc.push_str(&text);
-                                                let _ = stderr_sender
-                                                    .send(CommandOutput {
-                                                        data: text,
-                                                        timestamp: Utc::now(),
-                                                    })
-                                                    .await;
-                                            }
+                                        let (kind, sender) = &senders[i];
+                                        let timestamp = Utc::now();
+                                        if capture_transcript {
+                                            transcript.push((*kind, text.clone(), timestamp));
                                         }
+                                        let _ = sender
+                                            .send(CommandOutput {
+                                                data: text,
+                                                timestamp,
+                                            })
+                                            .await;
                                     }
                                 }
                                 crate::rpc::ProcessEventData::End { end } => {
@@ -254,37 +1078,81 @@ impl CommandsApi {
                                             }
                                         });
                                     }
-                                    execution_time = None;
                                     break;
                                 }
                                 crate::rpc::ProcessEventData::Start { .. } => {}
                             }
                         }
 
+                        // Flush a character left incomplete by the last chunk
+                        for (i, (kind, sender)) in senders.iter().enumerate() {
+                            let text = decoders[i].finish();
+                            if text.is_empty() {
+                                continue;
+                            }
+                            let timestamp = Utc::now();
+                            if capture_transcript {
+                                transcript.push((*kind, text.clone(), timestamp));
+                            }
+                            let _ = sender
+                                .send(CommandOutput {
+                                    data: text,
+                                    timestamp,
+                                })
+                                .await;
+                        }
+                        let [stdout, stderr] = raw;
+
+                        if let Some(audit) = audit {
+                            audit.finish(exit_code);
+                        }
                         let _ = result_tx.send(CommandResult {
-                            stdout: stdout_acc,
-                            stderr: stderr_acc,
+                            stdout: Self::decode_background(&stdout, encoding, "stdout"),
+                            stderr: Self::decode_background(&stderr, encoding, "stderr"),
                             exit_code: exit_code.unwrap_or(-1),
                             execution_time,
+                            stdout_bytes: (encoding == OutputEncoding::Binary).then_some(stdout),
+                            stderr_bytes: (encoding == OutputEncoding::Binary).then_some(stderr),
+                            transcript,
                         });
                     });
 
                     return Ok(CommandHandle::new(pid, stdout_rx, stderr_rx, result_rx));
                 }
-                crate::rpc::ProcessEventData::Data { .. } => continue,
-                crate::rpc::ProcessEventData::End { .. } => {
-                    return Err(Error::Api {
-                        status: 500,
-                        message: "Process ended immediately after start".to_string(),
-                    });
+                crate::rpc::ProcessEventData::Data { data } => {
+                    if let Some(decoded) = data
+                        .stderr
+                        .and_then(|stderr| general_purpose::STANDARD.decode(stderr).ok())
+                    {
+                        early_stderr.extend_from_slice(&decoded);
+                    }
+                }
+                crate::rpc::ProcessEventData::End { end } => {
+                    return Err(Self::start_failure(cmd, &end, &early_stderr));
                 }
             }
         }
 
-        Err(Error::Api {
-            status: 500,
-            message: "Failed to start process: no PID received".to_string(),
-        })
+        Err(Error::invalid_response(
+            "Failed to start process: no PID received",
+        ))
+    }
+
+    // A command that could not start fails the same way when run again, so
+    // this is an `ExecutionError` rather than a (retryable) envd error
+    fn start_failure(cmd: &str, end: &crate::rpc::ProcessEnd, stderr: &[u8]) -> Error {
+        let mut value = format!("Process '{}' failed to start: {}", cmd, end.status);
+        if let Some(code) = end.exit_code {
+            value.push_str(&format!(" (exit code {})", code));
+        }
+        if let Some(error) = end.error.as_deref().filter(|e| !e.is_empty()) {
+            value.push_str(&format!(": {}", error));
+        }
+        Error::ExecutionError {
+            name: "StartFailed".to_string(),
+            value,
+            traceback: String::from_utf8_lossy(stderr).trim_end().to_string(),
+        }
     }
 
     pub async fn wait_for_command(&self, handle: CommandHandle) -> Result<CommandResult> {
@@ -297,8 +1165,8 @@ impl CommandsApi {
         });
 
         let mut stream = rpc_client.process_connect(params).await?;
-        let mut stdout = String::new();
-        let mut stderr = String::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
         let mut exit_code = None;
 
         // Read all events from the stream until process ends
@@ -309,29 +1177,15 @@ impl CommandsApi {
                         // Decode Base64 stdout data
                         let decoded = base64::engine::general_purpose::STANDARD
                             .decode(stdout_data)
-                            .map_err(|e| Error::Api {
-                                status: 500,
-                                message: format!("Failed to decode stdout: {}", e),
-                            })?;
-                        let text = String::from_utf8(decoded).map_err(|e| Error::Api {
-                            status: 500,
-                            message: format!("Failed to convert stdout to UTF-8: {}", e),
-                        })?;
-                        stdout.push_str(&text);
+                            .map_err(|e| Error::undecodable("Failed to decode stdout", e))?;
+                        stdout.extend_from_slice(&decoded);
                     }
                     if let Some(stderr_data) = &data.stderr {
                         // Decode Base64 stderr data
                         let decoded = base64::engine::general_purpose::STANDARD
                             .decode(stderr_data)
-                            .map_err(|e| Error::Api {
-                                status: 500,
-                                message: format!("Failed to decode stderr: {}", e),
-                            })?;
-                        let text = String::from_utf8(decoded).map_err(|e| Error::Api {
-                            status: 500,
-                            message: format!("Failed to convert stderr to UTF-8: {}", e),
-                        })?;
-                        stderr.push_str(&text);
+                            .map_err(|e| Error::undecodable("Failed to decode stderr", e))?;
+                        stderr.extend_from_slice(&decoded);
                     }
                 }
                 crate::rpc::ProcessEventData::End { end } => {
@@ -355,11 +1209,16 @@ impl CommandsApi {
             }
         }
 
+        // The handle doesn't carry the run's options, so the default applies
+        let encoding = OutputEncoding::default();
         Ok(CommandResult {
-            stdout,
-            stderr,
+            stdout: Self::decode_output(&stdout, encoding, "stdout")?,
+            stderr: Self::decode_output(&stderr, encoding, "stderr")?,
             exit_code: exit_code.unwrap_or(-1),
             execution_time: None,
+            stdout_bytes: None,
+            stderr_bytes: None,
+            transcript: Vec::new(),
         })
     }
 
@@ -376,14 +1235,14 @@ impl CommandsApi {
         } else if let Some(processes_array) = response["processes"].as_array() {
             // Response has a "processes" field
             processes_array
-        } else if response.as_object().map_or(false, |obj| obj.is_empty()) {
+        } else if response.as_object().is_some_and(|obj| obj.is_empty()) {
             // Response is an empty object, meaning no processes
             return Ok(Vec::new());
         } else {
-            return Err(Error::Api {
-                status: 500,
-                message: format!("Invalid response format: expected array or object with 'processes' field, got: {}", response),
-            });
+            return Err(Error::invalid_response(format!(
+                "Invalid response format: expected array or object with 'processes' field, got: {}",
+                response
+            )));
         };
 
         let mut result = Vec::new();
@@ -410,6 +1269,17 @@ impl CommandsApi {
                 .unwrap_or_default();
             let cwd = config["cwd"].as_str().map(|s| s.to_string());
             let tag = process["tag"].as_str().map(|s| s.to_string());
+            // Not every envd version reports these
+            let started_at = Self::parse_started_at(
+                process
+                    .get("startedAt")
+                    .or_else(|| process.get("started_at")),
+            );
+            let status = process
+                .get("status")
+                .or_else(|| process.get("state"))
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string());
 
             result.push(ProcessInfo {
                 pid,
@@ -418,12 +1288,25 @@ impl CommandsApi {
                 args,
                 envs,
                 cwd,
+                started_at,
+                status,
             });
         }
 
         Ok(result)
     }
 
+    // Accepts RFC 3339 strings and Unix timestamps in seconds
+    fn parse_started_at(value: Option<&serde_json::Value>) -> Option<DateTime<Utc>> {
+        let value = value?;
+        if let Some(text) = value.as_str() {
+            return DateTime::parse_from_rfc3339(text)
+                .ok()
+                .map(|dt| dt.with_timezone(&Utc));
+        }
+        DateTime::from_timestamp(value.as_i64()?, 0)
+    }
+
     pub async fn kill(&self, pid: u32) -> Result<bool> {
         let rpc_client = self.get_rpc_client()?;
 
@@ -436,7 +1319,7 @@ impl CommandsApi {
 
         match rpc_client.process_send_signal(params).await {
             Ok(_) => Ok(true),
-            Err(Error::Api { status: 404, .. }) => Ok(false),
+            Err(Error::Envd { status: 404, .. }) => Ok(false),
             Err(e) => Err(e),
         }
     }
@@ -465,10 +1348,435 @@ impl CommandsApi {
         Ok(CommandHandle::from_pid(pid))
     }
 
-    fn build_shell_command(cmd: &str) -> (String, Vec<String>) {
-        (
-            "/bin/bash".to_string(),
-            vec!["-l".to_string(), "-c".to_string(), cmd.to_string()],
-        )
+    fn build_shell_command(&self, cmd: &str, options: &CommandOptions) -> (String, Vec<String>) {
+        let (shell, args) = match options.shell.unwrap_or(self.default_shell) {
+            ShellKind::Login if options.inherit_env => (
+                "/bin/bash".to_string(),
+                vec!["-l".to_string(), "-c".to_string(), cmd.to_string()],
+            ),
+            ShellKind::Login | ShellKind::NonLogin => (
+                "/bin/bash".to_string(),
+                vec!["-c".to_string(), cmd.to_string()],
+            ),
+            ShellKind::Sh => (
+                "/bin/sh".to_string(),
+                vec!["-c".to_string(), cmd.to_string()],
+            ),
+        };
+        if options.inherit_env {
+            return (shell, args);
+        }
+
+        // envd has no switch to start from an empty environment, so clear it
+        // with `env -i` and pass only the requested variables. The shell is
+        // given by absolute path, so it starts without a PATH.
+        let mut envs: Vec<(&String, &String)> = options.envs.iter().flatten().collect();
+        envs.sort();
+        let mut env_args = vec!["-i".to_string()];
+        env_args.extend(envs.into_iter().map(|(k, v)| format!("{}={}", k, v)));
+        env_args.push(shell);
+        env_args.extend(args);
+        ("/usr/bin/env".to_string(), env_args)
+    }
+}
+
+/// Quotes `value` for safe use as a single word in a POSIX shell command.
+pub fn shell_escape(value: &str) -> String {
+    if !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+    {
+        return value.to_string();
+    }
+
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// A command started with `CommandsApi::start`.
+pub struct RunningCommand {
+    commands: CommandsApi,
+    handle: CommandHandle,
+}
+
+impl RunningCommand {
+    pub fn pid(&self) -> u32 {
+        self.handle.pid()
+    }
+
+    /// Stdout chunks as they arrive. Can only be taken once; later calls
+    /// return an empty stream.
+    pub fn stdout_stream(&mut self) -> impl Stream<Item = CommandOutput> {
+        Self::output_stream(self.handle.take_stdout())
+    }
+
+    pub fn stderr_stream(&mut self) -> impl Stream<Item = CommandOutput> {
+        Self::output_stream(self.handle.take_stderr())
+    }
+
+    fn output_stream(
+        receiver: Option<mpsc::Receiver<CommandOutput>>,
+    ) -> impl Stream<Item = CommandOutput> {
+        stream::unfold(receiver, |receiver| async move {
+            let mut rx = receiver?;
+            let item = rx.recv().await?;
+            Some((item, Some(rx)))
+        })
+    }
+
+    /// Waits for the process to exit. Output streams that were never taken
+    /// are discarded; streams that were taken must be drained concurrently,
+    /// or the command stalls once their buffers fill up.
+    pub async fn wait(mut self) -> Result<CommandResult> {
+        let result = self.handle.take_result();
+        drop(self.handle);
+
+        match result {
+            Some(rx) => rx.await.map_err(|_| Error::Envd {
+                status: 500,
+                message: "Process stream closed before the command finished".to_string(),
+            }),
+            None => Err(Error::Configuration(
+                "Command result already taken".to_string(),
+            )),
+        }
+    }
+
+    /// Sends SIGKILL to the process. Returns `false` if it had already exited.
+    pub async fn kill(&self) -> Result<bool> {
+        self.commands.kill(self.handle.pid()).await
+    }
+
+    pub fn into_handle(self) -> CommandHandle {
+        self.handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::live_sandbox;
+
+    #[test]
+    fn chunk_decoder_joins_characters_split_across_chunks() {
+        let mut decoder = ChunkDecoder::default();
+        let bytes = "héllo €".as_bytes();
+        // Split inside 'é' and inside '€'
+        assert_eq!(decoder.push(&bytes[..2]), "h");
+        assert_eq!(decoder.push(&bytes[2..8]), "éllo ");
+        assert_eq!(decoder.push(&bytes[8..]), "€");
+        assert_eq!(decoder.finish(), "");
+    }
+
+    #[test]
+    fn chunk_decoder_replaces_invalid_bytes() {
+        let mut decoder = ChunkDecoder::default();
+        assert_eq!(decoder.push(b"a\xffb"), "a\u{fffd}b");
+        // A truncated character at the very end is flushed lossily
+        assert_eq!(decoder.push(b"c\xe2\x82"), "c");
+        assert_eq!(decoder.finish(), "\u{fffd}");
+    }
+
+    #[test]
+    fn background_output_falls_back_to_lossy() {
+        let bytes = b"ok\xff";
+        assert!(CommandsApi::decode_output(bytes, OutputEncoding::Utf8, "stdout").is_err());
+        assert_eq!(
+            CommandsApi::decode_background(bytes, OutputEncoding::Utf8, "stdout"),
+            "ok\u{fffd}"
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn background_run_keeps_non_utf8_output() {
+        let sandbox = live_sandbox().await;
+        let options = CommandOptions {
+            output_encoding: OutputEncoding::Binary,
+            ..Default::default()
+        };
+        let mut handle = sandbox
+            .commands()
+            .run_background_with_options(r"printf 'a\377b'", &options)
+            .await
+            .unwrap();
+        let mut stdout = handle.take_stdout().unwrap();
+        let chunk = stdout.recv().await.unwrap();
+        assert_eq!(chunk.data, "a\u{fffd}b");
+
+        let result = handle.take_result().unwrap().await.unwrap();
+        assert_eq!(result.stdout, "a\u{fffd}b");
+        assert_eq!(result.stdout_bytes.as_deref(), Some(&b"a\xffb"[..]));
+        sandbox.delete().await.unwrap();
+    }
+
+    fn audit_entry(command: &str) -> CommandAuditEntry {
+        CommandAuditEntry {
+            command: command.to_string(),
+            started_at: Utc::now(),
+            exit_code: Some(0),
+            duration: Some(Duration::ZERO),
+            background: false,
+        }
+    }
+
+    #[test]
+    fn history_keeps_the_most_recent_entries() {
+        let commands = CommandsApi::new();
+        commands.enable_history();
+        for i in 0..CommandsApi::MAX_HISTORY + 5 {
+            commands.record(|| audit_entry(&i.to_string()));
+        }
+
+        let history = commands.history();
+        assert_eq!(history.len(), CommandsApi::MAX_HISTORY);
+        assert_eq!(history[0].command, "5");
+    }
+
+    #[test]
+    fn background_entry_is_completed_on_exit() {
+        let commands = CommandsApi::new();
+        let exported = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = exported.clone();
+        commands.on_command(move |entry| sink.lock().unwrap().push(entry.clone()));
+
+        let audit = commands.record_started("sleep 1", Utc::now()).unwrap();
+        assert_eq!(commands.history()[0].exit_code, None);
+        assert!(exported.lock().unwrap().is_empty());
+
+        audit.finish(Some(3));
+        let entry = &commands.history()[0];
+        assert!(entry.background);
+        assert_eq!(entry.exit_code, Some(3));
+        assert!(entry.duration.is_some());
+        assert_eq!(exported.lock().unwrap()[0].exit_code, Some(3));
+    }
+
+    #[test]
+    fn nothing_is_recorded_until_enabled() {
+        let commands = CommandsApi::new();
+        commands.record(|| audit_entry("ls"));
+        assert!(commands.record_started("ls", Utc::now()).is_none());
+        assert!(commands.history().is_empty());
+    }
+
+    fn isolated(envs: &[(&str, &str)]) -> CommandOptions {
+        CommandOptions {
+            envs: Some(
+                envs.iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            inherit_env: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn isolated_env_passes_only_the_caller_envs() {
+        let mut api = CommandsApi::new();
+        api.set_default_env(HashMap::from([("SANDBOX".to_string(), "1".to_string())]));
+        let (cmd, args) = api.build_shell_command("echo $PATH", &isolated(&[("A", "1")]));
+
+        assert_eq!(cmd, "/usr/bin/env");
+        assert_eq!(args, ["-i", "A=1", "/bin/bash", "-c", "echo $PATH"]);
+        assert!(!args.iter().any(|a| a.starts_with("PATH=")));
+        assert!(api.merged_envs(&isolated(&[])).is_empty());
+    }
+
+    #[test]
+    fn isolated_env_keeps_a_caller_path() {
+        let api = CommandsApi::new();
+        let (_, args) = api.build_shell_command("true", &isolated(&[("PATH", "/opt/bin")]));
+        assert_eq!(args[..2], ["-i", "PATH=/opt/bin"]);
+    }
+
+    #[test]
+    fn rest_command_line_matches_the_rpc_argv() {
+        let mut commands = CommandsApi::new();
+        commands.set_default_shell(ShellKind::Sh);
+        let line = CommandsApi::rest_command_line(
+            commands.build_shell_command("echo $HOME", &isolated(&[("A", "b c")])),
+        );
+        assert_eq!(line, "/usr/bin/env -i 'A=b c' /bin/sh -c 'echo $HOME'");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn isolated_env_has_no_path_unless_provided() {
+        let sandbox = live_sandbox().await;
+        let commands = sandbox.commands();
+        // `env` lists what was exported to the process; a PATH the shell
+        // makes up for its own lookups is not exported
+        let exported = commands
+            .run_with_options("/usr/bin/env", &isolated(&[]))
+            .await
+            .unwrap();
+        assert!(!exported.stdout.lines().any(|l| l.starts_with("PATH=")));
+
+        let provided = commands
+            .run_with_options("echo $PATH", &isolated(&[("PATH", "/opt/bin")]))
+            .await
+            .unwrap();
+        assert_eq!(provided.stdout.trim(), "/opt/bin");
+        sandbox.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn timed_out_command_is_killed_with_its_children() {
+        let sandbox = live_sandbox().await;
+        let commands = sandbox.commands();
+        let options = CommandOptions {
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+
+        let result = commands
+            .run_with_options("sleep 100 && echo done", &options)
+            .await;
+        assert!(matches!(result, Err(Error::Timeout)));
+
+        let listed = commands.list().await.unwrap();
+        assert!(!listed
+            .iter()
+            .any(|p| p.args.iter().any(|a| a.contains("sleep 100"))));
+        let survivors = commands
+            .run("pgrep -f '[s]leep 100' || true")
+            .await
+            .unwrap();
+        assert_eq!(survivors.stdout.trim(), "");
+        sandbox.delete().await.unwrap();
+    }
+
+    #[test]
+    fn shell_escape_leaves_plain_words_alone() {
+        assert_eq!(shell_escape("/home/user/file.txt"), "/home/user/file.txt");
+        assert_eq!(shell_escape(""), "''");
+        assert_eq!(shell_escape("my file"), "'my file'");
+        assert_eq!(shell_escape("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn injected_value_stays_a_single_word() {
+        let vars = HashMap::from([("path".to_string(), "\"; rm -rf /".to_string())]);
+        let cmd = CommandsApi::render_template("cat {{ path }}", &vars).unwrap();
+        assert_eq!(cmd, "cat '\"; rm -rf /'");
+
+        assert!(CommandsApi::render_template("cat {{path", &vars).is_err());
+        assert!(CommandsApi::render_template("cat {{other}}", &vars).is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn run_template_prints_injection_attempt_verbatim() {
+        let sandbox = live_sandbox().await;
+        let vars = HashMap::from([("value".to_string(), "\"; rm -rf /".to_string())]);
+        let result = sandbox
+            .commands()
+            .run_template("printf %s {{value}}", &vars)
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "\"; rm -rf /");
+        sandbox.delete().await.unwrap();
+    }
+
+    #[test]
+    fn start_failure_keeps_status_exit_code_and_stderr() {
+        let end = crate::rpc::ProcessEnd {
+            exited: true,
+            status: "exit status 127".to_string(),
+            exit_code: Some(127),
+            error: Some("exec failed".to_string()),
+        };
+        let error = CommandsApi::start_failure(
+            "this_binary_does_not_exist",
+            &end,
+            b"bash: this_binary_does_not_exist: command not found\n",
+        );
+        assert!(!error.is_retryable());
+        match error {
+            Error::ExecutionError {
+                name,
+                value,
+                traceback,
+            } => {
+                assert_eq!(name, "StartFailed");
+                assert_eq!(
+                    value,
+                    "Process 'this_binary_does_not_exist' failed to start: \
+                     exit status 127 (exit code 127): exec failed"
+                );
+                assert_eq!(
+                    traceback,
+                    "bash: this_binary_does_not_exist: command not found"
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn missing_binary_explains_the_failure() {
+        let sandbox = live_sandbox().await;
+        // The shell may start before the lookup fails, in which case the
+        // reason arrives with the result instead
+        match sandbox
+            .commands()
+            .run_background("this_binary_does_not_exist")
+            .await
+        {
+            Err(e) => assert!(e.to_string().contains("exit code 127"), "{}", e),
+            Ok(mut handle) => {
+                let result = handle.take_result().unwrap().await.unwrap();
+                assert_eq!(result.exit_code, 127);
+                assert!(result.stderr.contains("this_binary_does_not_exist"));
+            }
+        }
+        sandbox.delete().await.unwrap();
+    }
+
+    // Rejects every write, like a closed pipe
+    struct BrokenPipe;
+
+    impl AsyncWrite for BrokenPipe {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn local_write_failure_is_an_io_error() {
+        let encoded = general_purpose::STANDARD.encode("hello");
+        let error = CommandsApi::forward_output(&encoded, &mut BrokenPipe, "stdout")
+            .await
+            .unwrap_err();
+        assert!(matches!(&error, Error::Io(e) if e.kind() == std::io::ErrorKind::BrokenPipe));
+        assert!(!error.is_retryable());
+
+        let mut written = Vec::new();
+        CommandsApi::forward_output(&encoded, &mut written, "stdout")
+            .await
+            .unwrap();
+        assert_eq!(written, b"hello");
     }
 }
@@ -1,20 +1,165 @@
 use crate::{
-    error::{Error, Result},
-    models::{EntryInfo, FileInfo, ReadFormat, ReadResult, WatchHandle, WriteEntry, WriteInfo},
-    rpc::RpcClient,
+    config::Config,
+    error::{Error, MoveFailure, Result},
+    models::{
+        ChecksumAlgo, EntryInfo, FileInfo, FilesystemEvent, FilesystemEventType, ListOptions,
+        ReadFormat, ReadResult, SortKey, WatchHandle, WatchOptions, WriteEntry, WriteInfo,
+    },
+    rpc::{temp_path, RpcClient, WatchEvent, WatchStream},
 };
 use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::stream::{self, Stream};
 use serde_json::{json, Value};
-use std::sync::Arc;
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, PoisonError};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::sync::{mpsc, oneshot, OnceCell};
+use tokio::time::Instant;
 
-#[derive(Clone, Default)]
+const DEFAULT_USERNAME: &str = "user";
+
+/// Resolves `.` and `..` segments and collapses repeated slashes. `..` never
+/// climbs above `/`.
+fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            other => segments.push(other),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+// Hands watch events to the consumer, either waiting for room in the channel
+// or dropping and counting events while it is full
+struct WatchSink {
+    sender: mpsc::Sender<FilesystemEvent>,
+    drop_on_lag: bool,
+    lag: Arc<std::sync::Mutex<crate::models::filesystem::LagLog>>,
+}
+
+impl WatchSink {
+    // Returns `false` once the consumer has gone away
+    async fn deliver(&self, event: FilesystemEvent) -> bool {
+        let sent = if self.drop_on_lag {
+            match self.sender.try_send(event) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => false,
+                Err(mpsc::error::TrySendError::Closed(_)) => return false,
+            }
+        } else {
+            if self.sender.send(event).await.is_err() {
+                return false;
+            }
+            true
+        };
+
+        let mut lag = self.lag.lock().unwrap_or_else(PoisonError::into_inner);
+        if sent {
+            lag.record_delivered();
+        } else {
+            lag.record_dropped();
+        }
+        true
+    }
+}
+
+#[derive(Clone)]
 pub struct FilesystemApi {
     rpc_client: Option<Arc<RpcClient>>,
+    username: String,
+    base_dir: Option<String>,
+    root: Option<String>,
+}
+
+impl Default for FilesystemApi {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FilesystemApi {
     pub fn new() -> Self {
-        Self { rpc_client: None }
+        Self {
+            rpc_client: None,
+            username: DEFAULT_USERNAME.to_string(),
+            base_dir: None,
+            root: None,
+        }
+    }
+
+    /// Returns a copy of this API that performs every operation as `username`
+    /// (e.g. `"root"` for root-owned files). The RPC connection is shared.
+    pub fn as_user(&self, username: &str) -> FilesystemApi {
+        Self {
+            username: username.to_string(),
+            ..self.clone()
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Returns a copy of this API that resolves relative paths against `dir`.
+    /// Without a base directory, relative paths are rejected.
+    pub fn with_base_dir(&self, dir: &str) -> FilesystemApi {
+        Self {
+            base_dir: Some(normalize_path(dir)),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of this API that rejects any path which, once `.` and
+    /// `..` are resolved, falls outside `root`.
+    pub fn restricted_to(&self, root: &str) -> FilesystemApi {
+        Self {
+            root: Some(normalize_path(root)),
+            ..self.clone()
+        }
+    }
+
+    /// Normalizes `path` and checks it against the base directory and root
+    /// configured on this API. Every operation goes through this first.
+    fn resolve(&self, path: &str) -> Result<String> {
+        let absolute = if path.starts_with('/') {
+            normalize_path(path)
+        } else {
+            let base = self.base_dir.as_deref().ok_or_else(|| {
+                Error::Configuration(format!(
+                    "Relative path '{}' requires an absolute path or a base directory",
+                    path
+                ))
+            })?;
+            normalize_path(&format!("{}/{}", base, path))
+        };
+
+        if let Some(root) = &self.root {
+            let inside = root == "/"
+                || absolute == *root
+                || absolute
+                    .strip_prefix(root.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'));
+            if !inside {
+                return Err(Error::Configuration(format!(
+                    "Path '{}' is outside the allowed root '{}'",
+                    path, root
+                )));
+            }
+        }
+
+        Ok(absolute)
     }
 
     pub async fn init_rpc(&mut self, envd_url: &str, access_token: Option<&str>) -> Result<()> {
@@ -23,43 +168,145 @@ impl FilesystemApi {
         Ok(())
     }
 
+    pub async fn init_rpc_with_config(
+        &mut self,
+        envd_url: &str,
+        access_token: Option<&str>,
+        config: &Config,
+    ) -> Result<()> {
+        let rpc_client = RpcClient::connect_with_config(envd_url, access_token, config).await?;
+        self.rpc_client = Some(Arc::new(rpc_client));
+        Ok(())
+    }
+
+    pub(crate) fn set_rpc_client(&mut self, rpc_client: RpcClient) {
+        self.rpc_client = Some(Arc::new(rpc_client));
+    }
+
+    // Like `init_rpc_with_config`, but the first call waits for envd to come
+    // up, polling once per `ready` gate
+    pub(crate) async fn init_rpc_deferred(
+        &mut self,
+        envd_url: &str,
+        access_token: Option<&str>,
+        config: &Config,
+        ready: Arc<OnceCell<()>>,
+    ) -> Result<()> {
+        let rpc_client = RpcClient::connect_with_config(envd_url, access_token, config)
+            .await?
+            .with_readiness_gate(ready);
+        self.rpc_client = Some(Arc::new(rpc_client));
+        Ok(())
+    }
+
     fn get_rpc_client(&self) -> Result<&Arc<RpcClient>> {
-        self.rpc_client.as_ref().ok_or_else(|| Error::Api {
-            status: 500,
-            message: "RPC client not initialized. Call init_rpc first.".to_string(),
+        self.rpc_client.as_ref().ok_or_else(|| {
+            Error::Configuration("RPC client not initialized. Call init_rpc first.".to_string())
         })
     }
 
     pub async fn read_text(&self, path: &str) -> Result<String> {
         match self.read(path, ReadFormat::Text).await? {
             ReadResult::Text(content) => Ok(content),
-            ReadResult::Binary(_) => Err(Error::Api {
-                status: 500,
-                message: "Unexpected binary result".to_string(),
-            }),
+            ReadResult::Binary(_) => Err(Error::invalid_response("Unexpected binary result")),
         }
     }
 
     pub async fn read_binary(&self, path: &str) -> Result<Vec<u8>> {
         match self.read(path, ReadFormat::Binary).await? {
             ReadResult::Binary(content) => Ok(content),
-            ReadResult::Text(_) => Err(Error::Api {
-                status: 500,
-                message: "Unexpected text result".to_string(),
-            }),
+            ReadResult::Text(_) => Err(Error::invalid_response("Unexpected text result")),
         }
     }
 
     pub async fn read(&self, path: &str, format: ReadFormat) -> Result<ReadResult> {
         let rpc_client = self.get_rpc_client()?;
+        let path = self.resolve(path)?;
+        let path = path.as_str();
 
-        // Use the HTTP GET endpoint like the Python SDK
-        let content = rpc_client.filesystem_read(path, "user").await?;
-
+        // Use the HTTP GET endpoint like the Python SDK. It returns raw bytes
+        // for every file, so only the text format needs any decoding.
         match format {
-            ReadFormat::Text => Ok(ReadResult::Text(content)),
-            ReadFormat::Binary => Ok(ReadResult::Binary(content.into_bytes())),
+            ReadFormat::Text => {
+                let content = rpc_client.filesystem_read(path, &self.username).await?;
+                Ok(ReadResult::Text(content))
+            }
+            ReadFormat::Binary => {
+                let content = rpc_client
+                    .filesystem_read_bytes(path, &self.username)
+                    .await?;
+                Ok(ReadResult::Binary(content))
+            }
+        }
+    }
+
+    /// Reads up to `length` bytes of `path` starting at `offset`.
+    pub async fn read_range(&self, path: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let rpc_client = self.get_rpc_client()?;
+        let path = self.resolve(path)?;
+        rpc_client
+            .filesystem_read_range(&path, &self.username, offset, length)
+            .await
+    }
+
+    /// Returns the last `lines` lines of `path`. The file is read backwards
+    /// in chunks, so only its end is downloaded.
+    pub async fn tail(&self, path: &str, lines: usize) -> Result<Vec<String>> {
+        const CHUNK_SIZE: u64 = 64 * 1024;
+        if lines == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut offset = self.get_info(path).await?.size;
+        let mut buffer: Vec<u8> = Vec::new();
+        while offset > 0 {
+            let start = offset.saturating_sub(CHUNK_SIZE);
+            let mut chunk = self.read_range(path, start, offset - start).await?;
+            chunk.extend_from_slice(&buffer);
+            buffer = chunk;
+            offset = start;
+
+            // A trailing newline ends the last line rather than starting one
+            let body = buffer.strip_suffix(b"\n").unwrap_or(&buffer);
+            if body.iter().filter(|b| **b == b'\n').count() >= lines {
+                break;
+            }
         }
+
+        let text = String::from_utf8_lossy(&buffer);
+        let all: Vec<&str> = text.lines().collect();
+        Ok(all[all.len().saturating_sub(lines)..]
+            .iter()
+            .map(|line| line.to_string())
+            .collect())
+    }
+
+    /// Yields lines appended to `path` after the call, like `tail -f`. The
+    /// file size is polled every `poll_interval`; if the file shrinks
+    /// (truncated or rotated) reading restarts from its beginning. Errors are
+    /// yielded without ending the stream, so a missing file is retried.
+    pub fn tail_follow(
+        &self,
+        path: &str,
+        poll_interval: Duration,
+    ) -> impl Stream<Item = Result<String>> {
+        let state = TailState {
+            api: self.clone(),
+            path: path.to_string(),
+            poll_interval,
+            position: None,
+            partial: Vec::new(),
+            ready: VecDeque::new(),
+            failed: false,
+        };
+        stream::unfold(state, |mut state| async move {
+            if state.failed {
+                tokio::time::sleep(state.poll_interval).await;
+            }
+            let item = state.next_line().await;
+            state.failed = item.is_err();
+            Some((item, state))
+        })
     }
 
     pub async fn write_text(&self, path: &str, content: &str) -> Result<WriteInfo> {
@@ -73,11 +320,95 @@ impl FilesystemApi {
     }
 
     pub async fn write(&self, entry: WriteEntry) -> Result<WriteInfo> {
-        let entries = vec![entry];
-        let mut results = self.upload_files(&entries).await?;
-        results.pop().ok_or_else(|| Error::Api {
-            status: 500,
-            message: "Write operation returned no result".to_string(),
+        let mut results = self.upload_files(vec![entry]).await?;
+        results
+            .pop()
+            .ok_or_else(|| Error::invalid_response("Write operation returned no result"))
+    }
+
+    /// Writes `entry` and returns the full `FileInfo` (permissions, owner,
+    /// modification time) of the written file. The upload endpoint only
+    /// reports path and size, so this stats the file afterwards over the
+    /// existing RPC connection; use `write` when `WriteInfo` is enough.
+    pub async fn write_with_info(&self, entry: WriteEntry) -> Result<FileInfo> {
+        let written = self.write(entry).await?;
+        self.get_info(&written.path).await
+    }
+
+    /// Hex digest of the file at `path` as stored in the sandbox, for
+    /// comparing against a locally computed one after an upload.
+    pub async fn checksum(&self, path: &str, algo: ChecksumAlgo) -> Result<String> {
+        let path = self.resolve(path)?;
+        let rpc_client = self.get_rpc_client()?;
+        rpc_client
+            .filesystem_checksum(&path, algo, &self.username)
+            .await
+    }
+
+    /// Streams the local file at `local_path` to `remote_path` and returns
+    /// the write info together with the digest of the uploaded file.
+    pub async fn upload_file(
+        &self,
+        local_path: &Path,
+        remote_path: &str,
+        algo: ChecksumAlgo,
+    ) -> Result<(WriteInfo, String)> {
+        let remote_path = self.resolve(remote_path)?;
+        let rpc_client = self.get_rpc_client()?;
+        let written = rpc_client
+            .filesystem_upload_file(local_path, &remote_path, &self.username)
+            .await?;
+        let digest = rpc_client
+            .filesystem_checksum(&remote_path, algo, &self.username)
+            .await?;
+        Ok((written, digest))
+    }
+
+    /// Opens `path` for appending. The writer buffers locally and appends in
+    /// chunks of `RemoteFileWriter::CHUNK_SIZE`; `flush` or `shutdown` sends
+    /// whatever is still buffered, and data not flushed before the writer is
+    /// dropped is lost.
+    pub async fn open_append_writer(&self, path: &str) -> Result<RemoteFileWriter> {
+        let path = self.resolve(path)?;
+        Ok(RemoteFileWriter {
+            rpc_client: self.get_rpc_client()?.clone(),
+            username: self.username.clone(),
+            path,
+            buffer: Vec::new(),
+            pending: None,
+        })
+    }
+
+    /// Writes `entry` to a temporary file in the target's directory and then
+    /// renames it over the target, so readers see either the old or the new
+    /// content but never a partially written file.
+    pub async fn write_atomic(&self, entry: WriteEntry) -> Result<WriteInfo> {
+        let target = self.resolve(&entry.path)?;
+        let (dir, name) = match target.rsplit_once('/') {
+            Some((dir, name)) => (dir, name),
+            None => ("", target.as_str()),
+        };
+        let temp_path = format!("{}/.{}.{}.tmp", dir, name, uuid::Uuid::new_v4().simple());
+
+        let temp_entry = WriteEntry {
+            path: temp_path.clone(),
+            data: entry.data,
+            mode: entry.mode,
+        };
+        let written = self.write(temp_entry).await?;
+
+        if let Err(e) = self.rename(&temp_path, &target).await {
+            if let Err(cleanup) = self.remove(&temp_path).await {
+                tracing::warn!("Failed to remove temp file {}: {}", temp_path, cleanup);
+            }
+            return Err(e);
+        }
+
+        Ok(WriteInfo {
+            path: target.clone(),
+            name: name.to_string(),
+            entry_type: written.entry_type,
+            size: written.size,
         })
     }
 
@@ -85,19 +416,21 @@ impl FilesystemApi {
         if entries.is_empty() {
             return Ok(Vec::new());
         }
-        self.upload_files(&entries).await
+        self.upload_files(entries).await
     }
 
-    async fn upload_files(&self, entries: &[WriteEntry]) -> Result<Vec<WriteInfo>> {
+    async fn upload_files(&self, mut entries: Vec<WriteEntry>) -> Result<Vec<WriteInfo>> {
         let rpc_client = self.get_rpc_client()?;
-        rpc_client.filesystem_upload(entries, "user").await
+        for entry in &mut entries {
+            entry.path = self.resolve(&entry.path)?;
+        }
+        rpc_client.filesystem_upload(entries, &self.username).await
     }
 
     fn parse_entry_info(value: &Value) -> Result<EntryInfo> {
-        let entry = value.as_object().ok_or_else(|| Error::Api {
-            status: 500,
-            message: "Invalid entry format".to_string(),
-        })?;
+        let entry = value
+            .as_object()
+            .ok_or_else(|| Error::invalid_response("Invalid entry format"))?;
 
         let path = entry
             .get("path")
@@ -116,20 +449,19 @@ impl FilesystemApi {
         let is_dir = entry_type == "FILE_TYPE_DIRECTORY";
         let size = Self::parse_size(entry.get("size"));
         let modified_at = Self::parse_timestamp(entry.get("modifiedTime"));
-        let permissions = entry
-            .get("permissions")
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string();
+        let created_at = Self::parse_timestamp(entry.get("createdTime")).or(modified_at);
+        let permissions = Self::parse_permissions(entry);
+        let symlink_target = Self::parse_symlink_target(entry);
 
         Ok(EntryInfo {
             path,
             name,
             is_dir,
             size,
-            created_at: modified_at,
+            created_at,
             updated_at: modified_at,
             permissions,
+            symlink_target,
         })
     }
 
@@ -151,11 +483,9 @@ impl FilesystemApi {
         let is_dir = entry_type == "FILE_TYPE_DIRECTORY";
         let size = Self::parse_size(entry.get("size"));
         let modified_at = Self::parse_timestamp(entry.get("modifiedTime"));
-        let created_at = entry
-            .get("createdTime")
-            .map(|v| Self::parse_timestamp(Some(v)))
-            .unwrap_or(modified_at);
-        let permissions = entry.get("mode").and_then(|v| v.as_i64()).unwrap_or(0) as u32;
+        let created_at = Self::parse_timestamp(entry.get("createdTime")).or(modified_at);
+        let permissions = Self::parse_permissions(entry);
+        let symlink_target = Self::parse_symlink_target(entry);
         let owner = entry
             .get("owner")
             .and_then(|v| v.as_str())
@@ -177,9 +507,38 @@ impl FilesystemApi {
             permissions,
             owner,
             group,
+            symlink_target,
         })
     }
 
+    // Permission bits as a number (e.g. 0o755), taken from the numeric `mode`
+    // when present and otherwise parsed from an `ls`-style string.
+    fn parse_permissions(entry: &serde_json::Map<String, Value>) -> u32 {
+        if let Some(mode) = entry.get("mode").and_then(|v| v.as_u64()) {
+            return (mode & 0o7777) as u32;
+        }
+
+        let Some(permissions) = entry.get("permissions").and_then(|v| v.as_str()) else {
+            return 0;
+        };
+        if let Ok(octal) = u32::from_str_radix(permissions, 8) {
+            return octal;
+        }
+
+        let chars: Vec<char> = permissions.chars().collect();
+        let bits = &chars[chars.len().saturating_sub(9)..];
+        bits.iter()
+            .fold(0, |acc, c| (acc << 1) | u32::from(*c != '-'))
+    }
+
+    fn parse_symlink_target(entry: &serde_json::Map<String, Value>) -> Option<String> {
+        entry
+            .get("symlinkTarget")
+            .and_then(|v| v.as_str())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+    }
+
     fn parse_size(value: Option<&Value>) -> u64 {
         if let Some(v) = value {
             if let Some(n) = v.as_u64() {
@@ -195,72 +554,108 @@ impl FilesystemApi {
         0
     }
 
-    fn parse_timestamp(value: Option<&Value>) -> DateTime<Utc> {
-        if let Some(Value::String(s)) = value {
-            if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
-                return dt.with_timezone(&Utc);
-            }
+    fn parse_timestamp(value: Option<&Value>) -> Option<DateTime<Utc>> {
+        match value {
+            Some(Value::String(s)) => DateTime::parse_from_rfc3339(s)
+                .ok()
+                .map(|dt| dt.with_timezone(&Utc)),
+            _ => None,
         }
-        Utc::now()
     }
 
     pub async fn list(&self, path: &str) -> Result<Vec<EntryInfo>> {
+        let path = self.resolve(path)?;
         let rpc_client = self.get_rpc_client()?;
 
         let params = json!({
             "path": path,
-            "username": "user"
+            "username": self.username
         });
 
         let response = rpc_client.filesystem_list(params).await?;
         tracing::debug!("filesystem list response: {}", response);
-        let entries = response["entries"].as_array().ok_or_else(|| Error::Api {
-            status: 500,
-            message: "Invalid response format: missing entries".to_string(),
-        })?;
+        let entries = response["entries"]
+            .as_array()
+            .ok_or_else(|| Error::invalid_response("Invalid response format: missing entries"))?;
 
         entries.iter().map(Self::parse_entry_info).collect()
     }
 
+    /// Lists `path`, then filters and sorts the entries according to `options`.
+    pub async fn list_with_options(
+        &self,
+        path: &str,
+        options: &ListOptions,
+    ) -> Result<Vec<EntryInfo>> {
+        let mut entries = self.list(path).await?;
+
+        if let Some(filter) = options.filter {
+            entries.retain(filter);
+        }
+
+        entries.sort_by(|a, b| {
+            let order = match options.sort_by {
+                SortKey::None => Ordering::Equal,
+                SortKey::Name => a.name.cmp(&b.name),
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::Modified => a.updated_at.cmp(&b.updated_at),
+            };
+            let order = if options.reverse {
+                order.reverse()
+            } else {
+                order
+            };
+            if options.dirs_first {
+                b.is_dir.cmp(&a.is_dir).then(order)
+            } else {
+                order
+            }
+        });
+
+        Ok(entries)
+    }
+
     pub async fn exists(&self, path: &str) -> Result<bool> {
+        let path = self.resolve(path)?;
         let params = json!({
             "path": path,
-            "username": "user"
+            "username": self.username
         });
 
         let rpc_client = self.get_rpc_client()?;
         match rpc_client.filesystem_stat(params).await {
             Ok(_) => Ok(true),
-            Err(Error::Api { status: 404, .. }) => Ok(false),
+            Err(Error::Envd { status: 404, .. }) => Ok(false),
             Err(e) => Err(e),
         }
     }
 
     pub async fn get_info(&self, path: &str) -> Result<FileInfo> {
+        let path = self.resolve(path)?;
         let rpc_client = self.get_rpc_client()?;
 
         let params = json!({
             "path": path,
-            "username": "user"
+            "username": self.username
         });
 
         let response = rpc_client.filesystem_stat(params).await?;
         tracing::debug!("filesystem stat response: {}", response);
 
-        let entry = response["entry"].as_object().ok_or_else(|| Error::Api {
-            status: 500,
-            message: "Invalid response format: missing entry".to_string(),
-        })?;
+        let entry = response["entry"]
+            .as_object()
+            .ok_or_else(|| Error::invalid_response("Invalid response format: missing entry"))?;
 
         Self::parse_file_info(entry)
     }
 
     pub async fn remove(&self, path: &str) -> Result<()> {
+        let path = self.resolve(path)?;
         let rpc_client = self.get_rpc_client()?;
 
         let params = json!({
             "path": path,
-            "username": "user"
+            "username": self.username
         });
 
         rpc_client.filesystem_remove(params).await?;
@@ -271,9 +666,9 @@ impl FilesystemApi {
         let rpc_client = self.get_rpc_client()?;
 
         let params = json!({
-            "source": from,
-            "destination": to,
-            "username": "user"
+            "source": self.resolve(from)?,
+            "destination": self.resolve(to)?,
+            "username": self.username
         });
 
         let response = rpc_client.filesystem_move(params).await?;
@@ -283,22 +678,740 @@ impl FilesystemApi {
         Ok(())
     }
 
+    /// Renames every `(from, to)` pair in order, so chained moves such as
+    /// `a -> b` then `b -> c` behave as written. Every move is attempted; if
+    /// any fail, `Error::MoveBatch` lists each failed move and its cause.
+    pub async fn move_batch(&self, moves: &[(String, String)]) -> Result<()> {
+        let mut failures = Vec::new();
+        for (from, to) in moves {
+            if let Err(error) = self.rename(from, to).await {
+                failures.push(MoveFailure {
+                    from: from.clone(),
+                    to: to.clone(),
+                    error: Box::new(error),
+                });
+            }
+        }
+
+        if failures.is_empty() {
+            return Ok(());
+        }
+
+        Err(Error::MoveBatch {
+            total: moves.len(),
+            failures,
+        })
+    }
+
     pub async fn make_dir(&self, path: &str) -> Result<()> {
+        let path = self.resolve(path)?;
         let rpc_client = self.get_rpc_client()?;
 
         let params = json!({
             "path": path,
-            "username": "user"
+            "username": self.username
         });
 
         rpc_client.filesystem_make_dir(params).await?;
         Ok(())
     }
 
+    /// Creates an empty file at a unique path under `/tmp` and returns the
+    /// path. The random token makes collisions with other callers
+    /// practically impossible, and creating the file reserves the name.
+    pub async fn create_temp(&self, prefix: &str, suffix: &str) -> Result<String> {
+        let path = temp_path(prefix, suffix);
+        self.write(WriteEntry::binary(path.clone(), Vec::new()))
+            .await?;
+        Ok(path)
+    }
+
+    /// Creates a uniquely named directory under `/tmp` and returns its path.
+    pub async fn temp_dir(&self, prefix: &str) -> Result<String> {
+        let path = temp_path(prefix, "");
+        self.make_dir(&path).await?;
+        Ok(path)
+    }
+
     pub async fn watch_dir(&self, path: &str) -> Result<WatchHandle> {
-        // For now, return a simple watch handle
-        // In a full implementation, this would set up streaming of filesystem events
-        let (handle, _event_sender, _stop_receiver) = WatchHandle::new(path.to_string());
+        self.watch_dir_with_options(path, WatchOptions::default())
+            .await
+    }
+
+    /// Watches `path`, emitting only events whose type is in `event_types`.
+    pub async fn watch_dir_filtered(
+        &self,
+        path: &str,
+        event_types: &[FilesystemEventType],
+    ) -> Result<WatchHandle> {
+        let options = WatchOptions::default().event_types(event_types);
+        self.watch_dir_with_options(path, options).await
+    }
+
+    pub async fn watch_dir_with_options(
+        &self,
+        path: &str,
+        options: WatchOptions,
+    ) -> Result<WatchHandle> {
+        let rpc_client = self.get_rpc_client()?;
+        let path = self.resolve(path)?;
+        let path = path.as_str();
+
+        let params = json!({
+            "path": path,
+            "recursive": options.recursive,
+            "username": self.username
+        });
+
+        let stream = rpc_client.filesystem_watch(params).await?;
+        let (handle, event_sender, stop_receiver) = WatchHandle::new(path.to_string());
+        let sink = WatchSink {
+            sender: event_sender,
+            drop_on_lag: options.drop_on_lag,
+            lag: handle.shared_lag_log(),
+        };
+
+        tokio::spawn(Self::forward_watch_events(
+            stream,
+            path.to_string(),
+            options,
+            sink,
+            stop_receiver,
+        ));
+
         Ok(handle)
     }
+
+    async fn forward_watch_events(
+        mut stream: WatchStream,
+        root: String,
+        options: WatchOptions,
+        sink: WatchSink,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        // Pending events keyed by path, flushed once their debounce window closes
+        let mut pending: HashMap<String, PendingEvent> = HashMap::new();
+
+        loop {
+            let next_deadline = pending.values().map(|p| p.deadline).min();
+            let flush = async {
+                match next_deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                _ = &mut stop => break,
+                _ = flush => {
+                    let now = Instant::now();
+                    let ready: Vec<String> = pending
+                        .iter()
+                        .filter(|(_, p)| p.deadline <= now)
+                        .map(|(path, _)| path.clone())
+                        .collect();
+                    for path in ready {
+                        if let Some(p) = pending.remove(&path) {
+                            if !sink.deliver(p.event).await {
+                                return;
+                            }
+                        }
+                    }
+                }
+                next = stream.next_event() => {
+                    let raw = match next {
+                        Ok(Some(raw)) => raw,
+                        Ok(None) => break,
+                        Err(e) => {
+                            tracing::warn!("Filesystem watch on {} failed: {}", root, e);
+                            break;
+                        }
+                    };
+                    let Some(event) = Self::parse_watch_event(&root, &raw) else {
+                        continue;
+                    };
+                    if !options.accepts(event.event_type) {
+                        continue;
+                    }
+
+                    match options.debounce {
+                        Some(window) => {
+                            let max_wait = options.debounce_max_wait.unwrap_or(window * 10);
+                            debounce(&mut pending, event, Instant::now(), window, max_wait);
+                        }
+                        None => {
+                            if !sink.deliver(event).await {
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for (_, p) in pending {
+            if !sink.deliver(p.event).await {
+                return;
+            }
+        }
+    }
+
+    fn parse_watch_event(root: &str, raw: &WatchEvent) -> Option<FilesystemEvent> {
+        let event_type = match raw.event_type.as_str() {
+            "EVENT_TYPE_CREATE" => FilesystemEventType::Create,
+            "EVENT_TYPE_WRITE" | "EVENT_TYPE_CHMOD" => FilesystemEventType::Modify,
+            "EVENT_TYPE_REMOVE" => FilesystemEventType::Delete,
+            "EVENT_TYPE_RENAME" => FilesystemEventType::Move,
+            other => {
+                tracing::debug!("Unknown filesystem event type: {}", other);
+                return None;
+            }
+        };
+
+        let path = if raw.name.starts_with('/') {
+            raw.name.clone()
+        } else {
+            format!("{}/{}", root.trim_end_matches('/'), raw.name)
+        };
+
+        Some(FilesystemEvent {
+            event_type,
+            path,
+            timestamp: Utc::now(),
+            old_path: None,
+        })
+    }
+}
+
+struct PendingEvent {
+    event: FilesystemEvent,
+    deadline: Instant,
+    // Set by the first event of a burst; later events can't push past it
+    flush_by: Instant,
+}
+
+// Restarts the path's debounce window, but never beyond `max_wait` after
+// the first event that is still held back
+fn debounce(
+    pending: &mut HashMap<String, PendingEvent>,
+    event: FilesystemEvent,
+    now: Instant,
+    window: Duration,
+    max_wait: Duration,
+) {
+    let flush_by = pending
+        .get(&event.path)
+        .map_or(now + max_wait, |p| p.flush_by);
+    pending.insert(
+        event.path.clone(),
+        PendingEvent {
+            event,
+            deadline: (now + window).min(flush_by),
+            flush_by,
+        },
+    );
+}
+
+struct TailState {
+    api: FilesystemApi,
+    path: String,
+    poll_interval: Duration,
+    position: Option<u64>,
+    partial: Vec<u8>,
+    ready: VecDeque<String>,
+    failed: bool,
+}
+
+impl TailState {
+    async fn next_line(&mut self) -> Result<String> {
+        loop {
+            if let Some(line) = self.ready.pop_front() {
+                return Ok(line);
+            }
+
+            let size = self.api.get_info(&self.path).await?.size;
+            let Some(position) = self.position else {
+                // Only lines written after the call are reported
+                self.position = Some(size);
+                continue;
+            };
+
+            if size < position {
+                self.position = Some(0);
+                self.partial.clear();
+                continue;
+            }
+            if size == position {
+                tokio::time::sleep(self.poll_interval).await;
+                continue;
+            }
+
+            let appended = self
+                .api
+                .read_range(&self.path, position, size - position)
+                .await?;
+            self.position = Some(position + appended.len() as u64);
+            self.partial.extend_from_slice(&appended);
+
+            while let Some(newline) = self.partial.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.partial.drain(..=newline).collect();
+                let line = String::from_utf8_lossy(&line);
+                self.ready
+                    .push_back(line.trim_end_matches(['\n', '\r']).to_string());
+            }
+        }
+    }
+}
+
+/// `AsyncWrite` that appends to a file in the sandbox, returned by
+/// `FilesystemApi::open_append_writer`.
+pub struct RemoteFileWriter {
+    rpc_client: Arc<RpcClient>,
+    username: String,
+    path: String,
+    buffer: Vec<u8>,
+    pending: Option<BoxFuture<'static, Result<()>>>,
+}
+
+impl RemoteFileWriter {
+    /// Buffered bytes that trigger an append.
+    pub const CHUNK_SIZE: usize = 256 * 1024;
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Drives the in-flight append, if any, to completion
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(pending) = self.pending.as_mut() {
+            let result = ready!(pending.as_mut().poll(cx));
+            self.pending = None;
+            result.map_err(io::Error::other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_append(&mut self) {
+        let rpc_client = self.rpc_client.clone();
+        let username = self.username.clone();
+        let path = self.path.clone();
+        let data = std::mem::take(&mut self.buffer);
+        self.pending = Some(Box::pin(async move {
+            rpc_client.filesystem_append(&path, data, &username).await
+        }));
+    }
+}
+
+impl AsyncWrite for RemoteFileWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_pending(cx))?;
+        if self.buffer.len() >= Self::CHUNK_SIZE {
+            self.start_append();
+            ready!(self.poll_pending(cx))?;
+        }
+        self.buffer.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        if !self.buffer.is_empty() {
+            self.start_append();
+            ready!(self.poll_pending(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::WriteEntry,
+        test_support::{live_sandbox, recording_endpoint},
+    };
+    use tokio::io::AsyncWriteExt;
+
+    fn modified(path: &str) -> FilesystemEvent {
+        FilesystemEvent {
+            event_type: FilesystemEventType::Modify,
+            path: path.to_string(),
+            timestamp: Utc::now(),
+            old_path: None,
+        }
+    }
+
+    // Serves `events` as a Connect watch stream that then ends
+    async fn watch_stream(events: &[(&str, &str)]) -> WatchStream {
+        use tokio::io::AsyncWriteExt;
+
+        let mut body = Vec::new();
+        for (name, event_type) in events {
+            let frame = json!({ "event": { "name": name, "type": event_type } }).to_string();
+            body.push(0);
+            body.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            body.extend_from_slice(frame.as_bytes());
+        }
+        body.extend_from_slice(&[2, 0, 0, 0, 2]);
+        body.extend_from_slice(b"{}");
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = socket.write_all(head.as_bytes()).await;
+            let _ = socket.write_all(&body).await;
+        });
+        WatchStream::new(reqwest::get(url).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn delete_events_are_suppressed_when_only_create_is_requested() {
+        let stream = watch_stream(&[
+            ("a.txt", "EVENT_TYPE_CREATE"),
+            ("a.txt", "EVENT_TYPE_REMOVE"),
+            ("b.txt", "EVENT_TYPE_CREATE"),
+            ("b.txt", "EVENT_TYPE_WRITE"),
+        ])
+        .await;
+        let (mut handle, sender, stop) = WatchHandle::new("/tmp".to_string());
+        let sink = WatchSink {
+            sender,
+            drop_on_lag: false,
+            lag: handle.shared_lag_log(),
+        };
+        let options = WatchOptions::default().event_types(&[FilesystemEventType::Create]);
+        tokio::spawn(FilesystemApi::forward_watch_events(
+            stream,
+            "/tmp".to_string(),
+            options,
+            sink,
+            stop,
+        ));
+
+        let mut received = Vec::new();
+        while let Some(event) = handle.recv().await {
+            received.push((event.event_type, event.path));
+        }
+        assert_eq!(
+            received,
+            [
+                (FilesystemEventType::Create, "/tmp/a.txt".to_string()),
+                (FilesystemEventType::Create, "/tmp/b.txt".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn lag_is_reported_where_events_were_dropped() {
+        let (mut handle, sender, _stop) = WatchHandle::new("/tmp".to_string());
+        let sink = WatchSink {
+            sender,
+            drop_on_lag: true,
+            lag: handle.shared_lag_log(),
+        };
+
+        // Outpace the consumer: the channel holds 100, the next 5 are dropped
+        for i in 0..105 {
+            assert!(sink.deliver(modified(&format!("/tmp/{}", i))).await);
+        }
+        assert_eq!(handle.lagged(), 5);
+
+        // Make room for one event that arrives after the gap
+        assert_eq!(handle.recv_checked().await.unwrap().unwrap().path, "/tmp/0");
+        assert!(sink.deliver(modified("/tmp/after")).await);
+
+        for i in 1..100 {
+            let event = handle.recv_checked().await.unwrap().unwrap();
+            assert_eq!(event.path, format!("/tmp/{}", i));
+        }
+        assert!(matches!(
+            handle.recv_checked().await,
+            Some(Err(crate::models::WatchError::Lagged(5)))
+        ));
+        assert_eq!(handle.lagged(), 0);
+        assert_eq!(
+            handle.recv_checked().await.unwrap().unwrap().path,
+            "/tmp/after"
+        );
+    }
+
+    #[test]
+    fn debounce_restarts_window_on_each_event() {
+        let mut pending = HashMap::new();
+        let start = Instant::now();
+        let window = Duration::from_millis(100);
+        let max_wait = Duration::from_secs(1);
+        debounce(&mut pending, modified("/a"), start, window, max_wait);
+        let later = start + Duration::from_millis(50);
+        debounce(&mut pending, modified("/a"), later, window, max_wait);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending["/a"].deadline, later + window);
+    }
+
+    #[test]
+    fn continuous_writes_flush_after_max_wait() {
+        let mut pending = HashMap::new();
+        let start = Instant::now();
+        let window = Duration::from_millis(100);
+        let max_wait = Duration::from_millis(500);
+        // A write every 50ms would keep a pure trailing debounce open forever
+        for step in 0..20 {
+            let now = start + Duration::from_millis(50 * step);
+            debounce(&mut pending, modified("/a"), now, window, max_wait);
+        }
+        assert_eq!(pending["/a"].deadline, start + max_wait);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn write_applies_mode() {
+        let sandbox = live_sandbox().await;
+        let files = sandbox.files();
+        files
+            .write(WriteEntry::text_with_mode(
+                "/tmp/run.sh",
+                "#!/bin/sh\necho hi\n",
+                0o755,
+            ))
+            .await
+            .unwrap();
+
+        let info = files.get_info("/tmp/run.sh").await.unwrap();
+        assert_eq!(info.permissions & 0o777, 0o755);
+        sandbox.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn copy_into_append_writer_writes_whole_file() {
+        let sandbox = live_sandbox().await;
+        let files = sandbox.files();
+        let data: Vec<u8> = (0..=255u8)
+            .cycle()
+            .take(2 * super::RemoteFileWriter::CHUNK_SIZE + 11)
+            .collect();
+        files
+            .write_binary("/tmp/appended.bin", b"head".to_vec())
+            .await
+            .unwrap();
+
+        let mut writer = files.open_append_writer("/tmp/appended.bin").await.unwrap();
+        tokio::io::copy(&mut data.as_slice(), &mut writer)
+            .await
+            .unwrap();
+        writer.shutdown().await.unwrap();
+
+        let mut expected = b"head".to_vec();
+        expected.extend_from_slice(&data);
+        assert_eq!(
+            files.read_binary("/tmp/appended.bin").await.unwrap(),
+            expected
+        );
+        sandbox.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn move_batch_applies_chained_moves_in_order() {
+        let sandbox = live_sandbox().await;
+        let files = sandbox.files();
+        files.write_text("/tmp/a.txt", "a").await.unwrap();
+        files.write_text("/tmp/b.txt", "b").await.unwrap();
+
+        let moves = [
+            ("/tmp/b.txt".to_string(), "/tmp/c.txt".to_string()),
+            ("/tmp/a.txt".to_string(), "/tmp/b.txt".to_string()),
+            ("/tmp/missing.txt".to_string(), "/tmp/d.txt".to_string()),
+        ];
+        match files.move_batch(&moves).await {
+            Err(crate::Error::MoveBatch { total, failures }) => {
+                assert_eq!(total, 3);
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0].from, "/tmp/missing.txt");
+            }
+            other => panic!("expected MoveBatch, got {:?}", other),
+        }
+        assert_eq!(files.read_text("/tmp/b.txt").await.unwrap(), "a");
+        assert_eq!(files.read_text("/tmp/c.txt").await.unwrap(), "b");
+        sandbox.delete().await.unwrap();
+    }
+
+    // Keeps the file part of the last upload and serves it back on reads
+    async fn file_store() -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut stored = Vec::new();
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !crate::test_support::request_complete(&request) {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let body = if request.starts_with(b"POST") {
+                    // The part's content sits between its headers and the
+                    // next boundary
+                    let headers_end = request
+                        .windows(4)
+                        .enumerate()
+                        .filter(|(_, w)| *w == b"\r\n\r\n")
+                        .nth(1)
+                        .unwrap()
+                        .0
+                        + 4;
+                    let len = request[headers_end..]
+                        .windows(4)
+                        .position(|w| w == b"\r\n--")
+                        .unwrap();
+                    stored = request[headers_end..headers_end + len].to_vec();
+                    br#"[{"path":"/tmp/blob.bin","name":"blob.bin"}]"#.to_vec()
+                } else {
+                    stored.clone()
+                };
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = socket.write_all(head.as_bytes()).await;
+                let _ = socket.write_all(&body).await;
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn binary_round_trip_keeps_non_utf8_bytes() {
+        let blob = vec![0xff, 0x00, 0x80, b'\r', b'\n', 0xfe];
+        let mut files = FilesystemApi::new();
+        files.set_rpc_client(RpcClient::connect(&file_store().await, None).await.unwrap());
+
+        files
+            .write(WriteEntry::binary("/tmp/blob.bin", blob.clone()))
+            .await
+            .unwrap();
+        match files
+            .read("/tmp/blob.bin", ReadFormat::Binary)
+            .await
+            .unwrap()
+        {
+            ReadResult::Binary(read) => assert_eq!(read, blob),
+            ReadResult::Text(_) => panic!("binary read returned text"),
+        }
+        assert_eq!(files.read_binary("/tmp/blob.bin").await.unwrap(), blob);
+    }
+
+    async fn read_as(mut files: FilesystemApi) -> String {
+        let (url, request) = recording_endpoint("contents").await;
+        files.set_rpc_client(RpcClient::connect(&url, None).await.unwrap());
+        assert_eq!(
+            files.read_text("/root/secret.txt").await.unwrap(),
+            "contents"
+        );
+
+        let request = request.await.unwrap();
+        let request = String::from_utf8_lossy(&request);
+        request.lines().next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn as_user_changes_the_username_param() {
+        let files = FilesystemApi::new();
+        assert!(read_as(files.clone()).await.contains("username=user"));
+
+        let line = read_as(files.as_user("root")).await;
+        assert!(line.contains("username=root"), "{}", line);
+        assert!(!line.contains("username=user"));
+    }
+
+    #[test]
+    fn stat_without_timestamps_has_none() {
+        let entry = serde_json::json!({
+            "path": "/tmp/a.txt",
+            "name": "a.txt",
+            "type": "FILE_TYPE_FILE",
+            "size": "12",
+            "mode": 0o100644,
+        });
+        let info = FilesystemApi::parse_file_info(entry.as_object().unwrap()).unwrap();
+        assert_eq!(info.created_at, None);
+        assert_eq!(info.modified_at, None);
+        assert_eq!(info.permissions, 0o644);
+        assert_eq!(info.size, 12);
+
+        let listed = FilesystemApi::parse_entry_info(&entry).unwrap();
+        assert_eq!(listed.created_at, None);
+        assert_eq!(listed.updated_at, None);
+        assert_eq!(listed.permissions, info.permissions);
+    }
+
+    #[test]
+    fn created_time_falls_back_to_modified_time() {
+        let entry = serde_json::json!({
+            "path": "/tmp/link",
+            "type": "FILE_TYPE_FILE",
+            "modifiedTime": "2024-01-02T03:04:05.123456789Z",
+            "permissions": "-rwxr-x---",
+            "symlinkTarget": "/tmp/target",
+        });
+        let info = FilesystemApi::parse_file_info(entry.as_object().unwrap()).unwrap();
+        assert_eq!(info.created_at, info.modified_at);
+        assert_eq!(
+            info.modified_at.unwrap().timestamp_subsec_nanos(),
+            123_456_789
+        );
+        assert_eq!(info.permissions, 0o750);
+        assert_eq!(info.symlink_target.as_deref(), Some("/tmp/target"));
+    }
+
+    #[test]
+    fn dot_segments_are_resolved() {
+        assert_eq!(normalize_path("/tmp/../etc/passwd"), "/etc/passwd");
+        assert_eq!(normalize_path("/a/./b//c/"), "/a/b/c");
+        assert_eq!(normalize_path("/../.."), "/");
+    }
+
+    #[test]
+    fn traversal_out_of_the_root_is_rejected() {
+        let files = FilesystemApi::new();
+        assert_eq!(files.resolve("/tmp/../etc/passwd").unwrap(), "/etc/passwd");
+
+        let restricted = files.restricted_to("/tmp");
+        assert!(restricted.resolve("/tmp/../etc/passwd").is_err());
+        assert!(restricted.resolve("/tmpfoo/x").is_err());
+        assert_eq!(restricted.resolve("/tmp/a/../b").unwrap(), "/tmp/b");
+        assert_eq!(restricted.resolve("/tmp").unwrap(), "/tmp");
+    }
+
+    #[test]
+    fn relative_paths_need_a_base_dir() {
+        let files = FilesystemApi::new();
+        assert!(files.resolve("notes.txt").is_err());
+
+        let based = files
+            .with_base_dir("/home/user")
+            .restricted_to("/home/user");
+        assert_eq!(
+            based.resolve("notes/../todo.txt").unwrap(),
+            "/home/user/todo.txt"
+        );
+        assert!(based.resolve("../../etc/passwd").is_err());
+    }
 }
@@ -5,7 +5,7 @@ pub mod sandbox;
 pub mod template;
 
 pub use code_interpreter::CodeInterpreterApi;
-pub use commands::CommandsApi;
-pub use filesystem::FilesystemApi;
-pub use sandbox::SandboxApi;
+pub use commands::{CommandsApi, RunningCommand};
+pub use filesystem::{FilesystemApi, RemoteFileWriter};
+pub use sandbox::{KeepaliveHandle, SandboxApi};
 pub use template::TemplateApi;
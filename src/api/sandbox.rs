@@ -1,19 +1,82 @@
 use crate::{
-    api::{CodeInterpreterApi, CommandsApi, FilesystemApi},
+    api::{commands::shell_escape, CodeInterpreterApi, CommandsApi, FilesystemApi, TemplateApi},
     client::Client,
+    config::Region,
     error::{Error, Result},
     models::{
-        CodeExecution, Execution, LogLevel, Sandbox, SandboxCreateRequest, SandboxLog,
-        SandboxMetrics,
+        CodeExecution, CodeInterpreterOptions, Execution, ExecutionMessage, LogLevel, Sandbox,
+        SandboxCreateRequest, SandboxLog, SandboxMetrics, SandboxNetwork, SandboxState,
     },
+    rpc::{temp_path, RpcClient},
 };
 use chrono::{DateTime, Utc};
 use reqwest::StatusCode;
 use serde_json::{json, Value};
 use std::collections::HashMap;
-use std::time::Duration;
+use std::future::Future;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, OnceCell};
 use tokio::time::timeout;
 
+const DEFAULT_RESUME_TIMEOUT: u32 = 300;
+const DEFAULT_TEMPLATE_BUILD_WAIT: Duration = Duration::from_secs(600);
+const ENVD_PORT: u16 = 49_983;
+const DENY_ALL_EGRESS: &str = "0.0.0.0/0";
+const JUPYTER_PORT: u16 = 49_999;
+
+/// Public hostname of `port` in a sandbox: `{port}-{id}.{domain}`.
+fn sandbox_host(port: u16, sandbox_id: &str, domain: &str) -> String {
+    format!("{}-{}.{}", port, sandbox_id, domain)
+}
+
+fn sandbox_url(port: u16, sandbox_id: &str, domain: &str) -> String {
+    format!("https://{}", sandbox_host(port, sandbox_id, domain))
+}
+
+type MetadataKey = (String, String);
+
+// Maps metadata key/value pairs to sandbox IDs for `get_or_create_by_metadata`.
+// Shared by every clone of a `Client`.
+#[derive(Default)]
+pub(crate) struct MetadataIndex {
+    entries: std::sync::Mutex<HashMap<MetadataKey, (String, Instant)>>,
+    locks: std::sync::Mutex<HashMap<MetadataKey, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl MetadataIndex {
+    fn get(&self, lookup: &MetadataKey, ttl: Duration) -> Option<String> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries
+            .get(lookup)
+            .filter(|(_, cached_at)| cached_at.elapsed() < ttl)
+            .map(|(sandbox_id, _)| sandbox_id.clone())
+    }
+
+    fn insert(&self, lookup: MetadataKey, sandbox_id: String) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(lookup, (sandbox_id, Instant::now()));
+    }
+
+    fn remove(&self, lookup: &MetadataKey) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(lookup);
+    }
+
+    fn lock_for(&self, lookup: &MetadataKey) -> Arc<tokio::sync::Mutex<()>> {
+        self.locks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(lookup.clone())
+            .or_default()
+            .clone()
+    }
+}
+
 #[derive(Clone)]
 pub struct SandboxApi {
     client: Client,
@@ -30,7 +93,7 @@ impl SandboxApi {
 
     pub async fn list(&self) -> Result<Vec<Sandbox>> {
         let url = self.client.build_url("/sandboxes");
-        let response = self.client.http().get(&url).send().await?;
+        let response = self.client.get(&url).send_with_retry().await?;
 
         match response.status() {
             StatusCode::OK => {
@@ -49,7 +112,7 @@ impl SandboxApi {
 
     pub async fn get(&self, sandbox_id: &str) -> Result<Sandbox> {
         let url = self.client.build_url(&format!("/sandboxes/{}", sandbox_id));
-        let response = self.client.http().get(&url).send().await?;
+        let response = self.client.get(&url).send_with_retry().await?;
 
         match response.status() {
             StatusCode::OK => {
@@ -67,16 +130,353 @@ impl SandboxApi {
         }
     }
 
-    async fn create_sandbox(&self, request: SandboxCreateRequest) -> Result<Sandbox> {
+    pub async fn set_timeout(&self, sandbox_id: &str, seconds: u32) -> Result<()> {
+        let url = self
+            .client
+            .build_url(&format!("/sandboxes/{}/timeout", sandbox_id));
+        let response = self
+            .client
+            .post(&url)
+            .json(&json!({ "timeout": seconds }))
+            .send()
+            .await?;
+
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        tracing::debug!("set timeout response status={} body={}", status, body);
+
+        match status {
+            StatusCode::OK | StatusCode::NO_CONTENT | StatusCode::CREATED => Ok(()),
+            StatusCode::NOT_FOUND => Err(Error::NotFound(format!("Sandbox {}", sandbox_id))),
+            _ => Err(Error::Api {
+                status: status.as_u16(),
+                message: body,
+            }),
+        }
+    }
+
+    /// Connects to an existing sandbox by ID, initializing the commands,
+    /// filesystem and interpreter APIs as `create` does.
+    pub async fn connect(&self, sandbox_id: &str) -> Result<SandboxInstance> {
+        let sandbox = self.get(sandbox_id).await?;
+        let region = self.client.config().region.clone();
+        self.attach(sandbox, region.as_ref(), false).await
+    }
+
+    /// Lists sandboxes whose metadata has `key` set to `value`.
+    pub async fn list_by_metadata(&self, key: &str, value: &str) -> Result<Vec<Sandbox>> {
         let url = self.client.build_url("/sandboxes");
-        let response = self.client.http().post(&url).json(&request).send().await?;
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("metadata", format!("{}={}", key, value))])
+            .send_with_retry()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => {
+                let sandboxes: Vec<Sandbox> = response.json().await?;
+                // Older API versions ignore the filter
+                Ok(sandboxes
+                    .into_iter()
+                    .filter(|s| {
+                        s.metadata
+                            .as_ref()
+                            .and_then(|m| m.get(key))
+                            .and_then(Value::as_str)
+                            == Some(value)
+                    })
+                    .collect())
+            }
+            status => {
+                let error_text = response.text().await.unwrap_or_default();
+                Err(Error::Api {
+                    status: status.as_u16(),
+                    message: error_text,
+                })
+            }
+        }
+    }
+
+    /// Returns a sandbox whose metadata has `key` set to `value`, creating one
+    /// from `builder` (with that entry added) if none exists.
+    ///
+    /// Lookups are answered from a client-side index while
+    /// `Config::metadata_cache_ttl` allows. Concurrent calls in this process
+    /// are serialized per key/value; if another process creates a matching
+    /// sandbox at the same time, the oldest one wins and the newer one is
+    /// deleted.
+    pub async fn get_or_create_by_metadata(
+        &self,
+        key: &str,
+        value: &str,
+        builder: SandboxBuilder,
+    ) -> Result<SandboxInstance> {
+        let index = self.client.metadata_index();
+        let ttl = self.client.config().metadata_cache_ttl;
+        let lookup = (key.to_string(), value.to_string());
+
+        if let Some(sandbox_id) = ttl.and_then(|ttl| index.get(&lookup, ttl)) {
+            match self.connect(&sandbox_id).await {
+                Ok(instance) => return Ok(instance),
+                Err(Error::NotFound(_)) => index.remove(&lookup),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let lock = index.lock_for(&lookup);
+        let _guard = lock.lock().await;
+        let remember = |sandbox_id: &str| {
+            if ttl.is_some() {
+                index.insert(lookup.clone(), sandbox_id.to_string());
+            }
+        };
+
+        if let Some(existing) = Self::oldest(self.list_by_metadata(key, value).await?) {
+            remember(&existing.sandbox_id);
+            return self.connect(&existing.sandbox_id).await;
+        }
+
+        let created = builder.metadata_entry(key, value).create().await?;
+
+        // Check again in case another process created one concurrently
+        match Self::oldest(self.list_by_metadata(key, value).await?) {
+            Some(winner) if winner.sandbox_id != created.id() => {
+                tracing::debug!(
+                    "Sandbox {} lost a create race to {}, deleting it",
+                    created.id(),
+                    winner.sandbox_id
+                );
+                self.discard(created.id()).await;
+                remember(&winner.sandbox_id);
+                self.connect(&winner.sandbox_id).await
+            }
+            _ => {
+                remember(created.id());
+                Ok(created)
+            }
+        }
+    }
+
+    fn oldest(sandboxes: Vec<Sandbox>) -> Option<Sandbox> {
+        sandboxes
+            .into_iter()
+            .min_by(|a, b| (a.created_at, &a.sandbox_id).cmp(&(b.created_at, &b.sandbox_id)))
+    }
+
+    /// Creates a sandbox from a previously built (or deserialized) request and
+    /// runs the same RPC initialization as `SandboxBuilder::create`.
+    pub async fn create_from_request(
+        &self,
+        request: SandboxCreateRequest,
+    ) -> Result<SandboxInstance> {
+        self.create_instance(request, None, true).await
+    }
+
+    async fn create_instance(
+        &self,
+        mut request: SandboxCreateRequest,
+        idempotency_key: Option<String>,
+        wait_ready: bool,
+    ) -> Result<SandboxInstance> {
+        if request.region.is_none() {
+            request.region = self.client.config().region.as_ref().map(Region::to_string);
+        }
+        let region = request.region.as_deref().map(Region::from);
+        let sandbox = self.create_sandbox(request, idempotency_key).await?;
+
+        // envd only enforces X-Access-Token on secure sandboxes, so a missing
+        // token there would leave every RPC call unauthenticated and failing.
+        let has_token = sandbox
+            .envd_access_token
+            .as_deref()
+            .is_some_and(|token| !token.is_empty());
+        if sandbox.secure == Some(true) && !has_token {
+            self.discard(&sandbox.sandbox_id).await;
+            return Err(Error::Authentication(format!(
+                "Secure sandbox {} was created without an envd access token",
+                sandbox.sandbox_id
+            )));
+        }
+
+        // Wait for sandbox to be fully ready before connecting RPC
+        if wait_ready {
+            tracing::debug!("Waiting for sandbox to be ready...");
+            tokio::time::sleep(Duration::from_secs(3)).await;
+        }
+
+        let sandbox_id = sandbox.sandbox_id.clone();
+        match self.attach(sandbox, region.as_ref(), !wait_ready).await {
+            Err(e @ Error::Configuration(_)) => {
+                self.discard(&sandbox_id).await;
+                Err(e)
+            }
+            attached => attached,
+        }
+    }
+
+    // Wires the envd-backed APIs to an existing sandbox
+    async fn attach(
+        &self,
+        sandbox: Sandbox,
+        region: Option<&Region>,
+        defer_rpc: bool,
+    ) -> Result<SandboxInstance> {
+        // Initialize Commands and Filesystem APIs with HTTP Connect protocol
+        let config = self.client.config();
+        let sandbox_domain = match config
+            .sandbox_domain_override
+            .clone()
+            .or_else(|| sandbox.sandbox_domain.clone())
+            .or_else(|| sandbox.domain.clone())
+        {
+            Some(domain) => domain,
+            None => config.resolve_sandbox_domain(region)?,
+        };
+
+        let envd_url = sandbox_url(ENVD_PORT, &sandbox.sandbox_id, &sandbox_domain);
+        tracing::debug!("Connecting to envd at: {}", envd_url);
+        let access_token = sandbox.envd_access_token.as_deref();
+        tracing::info!(
+            sandbox_id = %sandbox.sandbox_id,
+            envd_url = %envd_url,
+            domain = ?sandbox.domain,
+            sandbox_domain = %sandbox_domain,
+            has_access_token = access_token.is_some(),
+            "Configured sandbox envd endpoint"
+        );
+
+        let mut commands = CommandsApi::new();
+        commands.set_default_shell(self.client.config().default_shell);
+        let default_env = sandbox.env_vars.clone().unwrap_or_default();
+        commands.set_default_env(default_env.clone());
+        commands.set_rest_fallback(self.client.clone(), &sandbox.sandbox_id);
+        let mut files = FilesystemApi::new();
+
+        if defer_rpc {
+            // Nothing has checked that envd is up yet; the first call through
+            // either API waits for it, and the other then reuses the result
+            let ready = Arc::new(OnceCell::new());
+            commands
+                .init_rpc_deferred(&envd_url, access_token, config, ready.clone())
+                .await?;
+            files
+                .init_rpc_deferred(&envd_url, access_token, config, ready)
+                .await?;
+        } else {
+            // Connecting only builds the HTTP client; whether envd is up is
+            // checked separately, retried under the configured policy
+            match RpcClient::connect_with_config(&envd_url, access_token, config).await {
+                Ok(rpc_client) => {
+                    let policy = config.effective_retry_policy();
+                    if let Err(e) = policy.run(|| rpc_client.check_health()).await {
+                        tracing::warn!(
+                            "envd at {} is not answering health checks: {}",
+                            envd_url,
+                            e
+                        );
+                    }
+                    tracing::debug!("Commands and Filesystem RPC connected");
+                    commands.set_rpc_client(rpc_client.clone());
+                    files.set_rpc_client(rpc_client);
+                }
+                // Don't fail sandbox creation, just make commands and the
+                // filesystem unavailable
+                Err(e) => tracing::warn!(
+                    "Failed to connect RPC: {}. Commands and Filesystem APIs will not be available.",
+                    e
+                ),
+            }
+        }
+
+        // Initialize code interpreter if using the code-interpreter template
+        tracing::debug!(
+            "Template ID: {}, Template Alias: {:?}",
+            sandbox.template_id,
+            sandbox.alias
+        );
+        let is_code_interpreter = sandbox.template_id.contains("code-interpreter")
+            || sandbox
+                .alias
+                .as_ref()
+                .map_or(false, |alias| alias.contains("code-interpreter"));
+
+        let jupyter_url = is_code_interpreter
+            .then(|| sandbox_url(JUPYTER_PORT, &sandbox.sandbox_id, &sandbox_domain));
+        let code_interpreter = if let Some(jupyter_url) = &jupyter_url {
+            tracing::debug!(
+                "Initializing code interpreter for template: {} (alias: {:?})",
+                sandbox.template_id,
+                sandbox.alias
+            );
+            let mut api = CodeInterpreterApi::new(self.client.clone(), jupyter_url.clone());
+            if let Some(token) = access_token {
+                api.set_envd_access_token(token.to_string());
+            }
+            api.set_default_env(default_env);
+            api.set_transport(self.client.config().interpreter_transport);
+            tracing::info!(
+                sandbox_id = %sandbox.sandbox_id,
+                jupyter_url = %jupyter_url,
+                "Configured code interpreter endpoint"
+            );
+            Some(api)
+        } else {
+            tracing::debug!("Code interpreter not initialized - neither template_id nor alias contains 'code-interpreter'");
+            None
+        };
+
+        Ok(SandboxInstance {
+            api: self.clone(),
+            sandbox_id: sandbox.sandbox_id.clone(),
+            sandbox: RwLock::new(sandbox),
+            domain: sandbox_domain,
+            region: region.cloned(),
+            envd_url,
+            jupyter_url,
+            commands,
+            files,
+            code_interpreter,
+        })
+    }
+
+    /// Best-effort delete of a sandbox that failed post-create checks.
+    async fn discard(&self, sandbox_id: &str) {
+        let url = self.client.build_url(&format!("/sandboxes/{}", sandbox_id));
+        if let Err(e) = self.client.delete(&url).send().await {
+            tracing::warn!("Failed to delete sandbox {}: {}", sandbox_id, e);
+        }
+    }
+
+    async fn create_sandbox(
+        &self,
+        request: SandboxCreateRequest,
+        idempotency_key: Option<String>,
+    ) -> Result<Sandbox> {
+        let url = self.client.build_url("/sandboxes");
+        let builder = self.client.post(&url).json(&request);
+
+        // Creating is only safe to retry when the server can recognise a
+        // repeated request, so retries are tied to sending a key.
+        let retries_enabled = self.client.config().effective_retry_policy().max_retries > 0;
+        let idempotency_key =
+            idempotency_key.or_else(|| retries_enabled.then(|| uuid::Uuid::new_v4().to_string()));
+        let response = match idempotency_key {
+            Some(key) => {
+                builder
+                    .header("Idempotency-Key", &key)
+                    .send_with_retry()
+                    .await?
+            }
+            None => builder.send().await?,
+        };
 
         match response.status() {
             StatusCode::CREATED | StatusCode::OK => {
                 let response_text = response.text().await?;
                 tracing::debug!("Sandbox creation response: {}", response_text);
 
-                let sandbox: Sandbox =
+                let mut sandbox: Sandbox =
                     serde_json::from_str(&response_text).map_err(|e| Error::Api {
                         status: 500,
                         message: format!(
@@ -84,12 +484,35 @@ impl SandboxApi {
                             e, response_text
                         ),
                     })?;
+
+                // The create response may omit the security settings; fall
+                // back to what was requested so callers can still inspect them.
+                sandbox.secure = sandbox.secure.or(request.secure);
+                sandbox.env_vars = sandbox.env_vars.or(request.env_vars);
+                sandbox.allow_internet_access = sandbox
+                    .allow_internet_access
+                    .or(request.allow_internet_access);
+                sandbox.network = sandbox.network.or(request.network);
                 Ok(sandbox)
             }
             StatusCode::UNAUTHORIZED => Err(Error::Authentication("Invalid API key".to_string())),
             StatusCode::TOO_MANY_REQUESTS => Err(Error::RateLimit),
             status => {
                 let error_text = response.text().await.unwrap_or_default();
+                // Fail closed: a rejected allowlist must not be retried or
+                // mistaken for a transient error and dropped by the caller
+                let rejected_network = matches!(
+                    status,
+                    StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY
+                ) && request.network.is_some();
+                if rejected_network {
+                    return Err(Error::Configuration(format!(
+                        "The backend rejected the egress rules set by allow_domains \
+                         (it may not support egress filtering); no sandbox was \
+                         created: {}",
+                        error_text
+                    )));
+                }
                 Err(Error::Api {
                     status: status.as_u16(),
                     message: error_text,
@@ -102,6 +525,8 @@ impl SandboxApi {
 pub struct SandboxBuilder {
     client: Client,
     request: SandboxCreateRequest,
+    idempotency_key: Option<String>,
+    template_build_wait: Option<Duration>,
 }
 
 impl SandboxBuilder {
@@ -116,7 +541,11 @@ impl SandboxBuilder {
                 allow_internet_access: None,
                 metadata: None,
                 env_vars: None,
+                region: None,
+                network: None,
             },
+            idempotency_key: None,
+            template_build_wait: None,
         }
     }
 
@@ -125,6 +554,22 @@ impl SandboxBuilder {
         self
     }
 
+    /// Adds a single metadata key. Metadata that is unset, or not a JSON
+    /// object, is replaced with an object first.
+    pub fn metadata_entry(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        let metadata = self
+            .request
+            .metadata
+            .get_or_insert_with(|| Value::Object(Default::default()));
+        if !metadata.is_object() {
+            *metadata = Value::Object(Default::default());
+        }
+        if let Value::Object(map) = metadata {
+            map.insert(key.into(), value.into());
+        }
+        self
+    }
+
     pub fn timeout(mut self, seconds: u32) -> Self {
         self.request.timeout = Some(seconds);
         self
@@ -135,6 +580,10 @@ impl SandboxBuilder {
         self
     }
 
+    /// Requests a sandbox whose envd requires the access token returned at
+    /// creation. The token is sent as `X-Access-Token` by the commands,
+    /// filesystem and interpreter clients. With `secure(true)`, `create` fails
+    /// with `Error::Authentication` if no token comes back.
     pub fn secure(mut self, secure: bool) -> Self {
         self.request.secure = Some(secure);
         self
@@ -145,166 +594,145 @@ impl SandboxBuilder {
         self
     }
 
+    /// Allows outbound traffic only to `domains` (e.g. `pypi.org`), blocking
+    /// everything else by also sending a deny rule for `0.0.0.0/0`. Implies
+    /// `allow_internet_access(true)`. Requires a backend with egress
+    /// filtering; `create` fails with `Error::Configuration` when the
+    /// backend rejects the rules.
+    pub fn allow_domains(mut self, domains: &[&str]) -> Self {
+        self.request.allow_internet_access = Some(true);
+        let network = self
+            .request
+            .network
+            .get_or_insert_with(SandboxNetwork::default);
+        network
+            .allow_out
+            .extend(domains.iter().map(|d| d.to_string()));
+        // The allowlist only carves exceptions out of a deny rule
+        if !network.deny_out.iter().any(|d| d == DENY_ALL_EGRESS) {
+            network.deny_out.push(DENY_ALL_EGRESS.to_string());
+        }
+        self
+    }
+
     pub fn env_vars(mut self, env_vars: HashMap<String, String>) -> Self {
         self.request.env_vars = Some(env_vars);
         self
     }
 
+    /// Places this sandbox in `region`, overriding `Config::region`.
+    pub fn region(mut self, region: &str) -> Self {
+        self.request.region = Some(Region::from(region).to_string());
+        self
+    }
+
     pub fn env_var(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
         let env_vars = self.request.env_vars.get_or_insert_with(HashMap::new);
         env_vars.insert(key.into(), value.into());
         self
     }
 
-    pub async fn create(self) -> Result<SandboxInstance> {
-        let api = SandboxApi::new(self.client.clone());
-        let sandbox = api.create_sandbox(self.request).await?;
-
-        // Wait for sandbox to be fully ready before connecting RPC
-        tracing::debug!("Waiting for sandbox to be ready...");
-        tokio::time::sleep(Duration::from_secs(3)).await;
-
-        // Initialize Commands and Filesystem APIs with HTTP Connect protocol
-        const ENVD_PORT: u16 = 49_983;
-        let sandbox_domain = sandbox
-            .sandbox_domain
-            .clone()
-            .or_else(|| sandbox.domain.clone())
-            .unwrap_or_else(|| self.client.config().sandbox_domain());
-
-        let envd_host = format!(
-            "{}-{}.{}",
-            ENVD_PORT,
-            sandbox.sandbox_id,
-            sandbox_domain.as_str()
-        );
+    /// Key sent as `Idempotency-Key` so a retried create returns the sandbox
+    /// from the first attempt. A random key is used when retries are enabled.
+    pub fn idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
 
-        let envd_scheme = "https";
+    /// Before creating, waits up to ten minutes until the template has a
+    /// ready build, failing clearly if none is ready and the latest one
+    /// errored or was canceled. Templates whose builds the team can't list
+    /// (the API answers `404` or `403`, e.g. public aliases) are not checked.
+    pub fn wait_for_template_build(self, wait: bool) -> Self {
+        self.wait_for_template_build_timeout(if wait {
+            Some(DEFAULT_TEMPLATE_BUILD_WAIT)
+        } else {
+            None
+        })
+    }
 
-        let envd_url = format!("{}://{}", envd_scheme, envd_host);
-        tracing::debug!("Connecting to envd at: {}", envd_url);
-        let access_token = sandbox.envd_access_token.as_deref();
-        tracing::info!(
-            sandbox_id = %sandbox.sandbox_id,
-            envd_url = %envd_url,
-            domain = ?sandbox.domain,
-            sandbox_domain = ?sandbox.sandbox_domain,
-            has_access_token = access_token.is_some(),
-            "Configured sandbox envd endpoint"
-        );
+    pub fn wait_for_template_build_timeout(mut self, max_wait: Option<Duration>) -> Self {
+        self.template_build_wait = max_wait;
+        self
+    }
 
-        let mut commands = CommandsApi::new();
-        let mut files = FilesystemApi::new();
+    pub async fn create(self) -> Result<SandboxInstance> {
+        self.build(true).await
+    }
 
-        // Try to initialize RPC with retries
-        let mut retry_count = 0;
-        const MAX_RETRIES: u32 = 3;
-        const RETRY_DELAY: Duration = Duration::from_secs(2);
+    /// Like `create`, but returns as soon as the create call succeeds,
+    /// without waiting for envd to come up. The first `commands()` or
+    /// `files()` call waits for envd instead, polling its health check once
+    /// for both; interpreter calls are not gated and may fail with a
+    /// retryable connection error while the sandbox is still booting.
+    pub async fn create_lazy(self) -> Result<SandboxInstance> {
+        self.build(false).await
+    }
 
-        while retry_count < MAX_RETRIES {
-            match commands.init_rpc(&envd_url, access_token).await {
-                Ok(()) => {
-                    tracing::debug!("Commands RPC connected successfully");
-                    break;
-                }
-                Err(e) => {
-                    retry_count += 1;
-                    if retry_count >= MAX_RETRIES {
-                        tracing::warn!("Failed to connect Commands RPC after {} retries: {}. Commands API will not be available.", MAX_RETRIES, e);
-                        // Don't fail sandbox creation, just make commands unavailable
-                        break;
-                    }
-                    tracing::warn!(
-                        "Commands RPC connection failed (attempt {}/{}): {}",
-                        retry_count,
-                        MAX_RETRIES,
-                        e
-                    );
-                    tokio::time::sleep(RETRY_DELAY).await;
+    async fn build(self, wait_ready: bool) -> Result<SandboxInstance> {
+        if let Some(max_wait) = self.template_build_wait {
+            let templates = TemplateApi::new(self.client.clone());
+            match templates
+                .wait_for_build(&self.request.template_id, max_wait)
+                .await
+            {
+                Ok(()) | Err(Error::NotFound(_)) | Err(Error::Api { status: 403, .. }) => {}
+                Err(Error::Timeout) => {
+                    return Err(Error::Api {
+                        status: StatusCode::CONFLICT.as_u16(),
+                        message: format!(
+                            "Template {} is not built yet (still building after {:?})",
+                            self.request.template_id, max_wait
+                        ),
+                    })
                 }
+                Err(e) => return Err(e),
             }
         }
 
-        // Initialize filesystem RPC with same URL
-        retry_count = 0;
-        while retry_count < MAX_RETRIES {
-            match files.init_rpc(&envd_url, access_token).await {
-                Ok(()) => {
-                    tracing::debug!("Filesystem RPC connected successfully");
-                    break;
-                }
-                Err(e) => {
-                    retry_count += 1;
-                    if retry_count >= MAX_RETRIES {
-                        tracing::warn!("Failed to connect Filesystem RPC after {} retries: {}. Filesystem API will not be available.", MAX_RETRIES, e);
-                        // Don't fail sandbox creation, just make filesystem unavailable
-                        break;
-                    }
-                    tracing::warn!(
-                        "Filesystem RPC connection failed (attempt {}/{}): {}",
-                        retry_count,
-                        MAX_RETRIES,
-                        e
-                    );
-                    tokio::time::sleep(RETRY_DELAY).await;
-                }
-            }
-        }
+        let api = SandboxApi::new(self.client);
+        api.create_instance(self.request, self.idempotency_key, wait_ready)
+            .await
+    }
+}
 
-        // Initialize code interpreter if using the code-interpreter template
-        tracing::debug!(
-            "Template ID: {}, Template Alias: {:?}",
-            sandbox.template_id,
-            sandbox.alias
-        );
-        let is_code_interpreter = sandbox.template_id.contains("code-interpreter")
-            || sandbox
-                .alias
-                .as_ref()
-                .map_or(false, |alias| alias.contains("code-interpreter"));
+/// Background task started by `SandboxInstance::keepalive`. Stopping or
+/// dropping the handle cancels it.
+#[derive(Debug)]
+pub struct KeepaliveHandle {
+    task: tokio::task::JoinHandle<()>,
+}
 
-        let code_interpreter = if is_code_interpreter {
-            tracing::debug!(
-                "Initializing code interpreter for template: {} (alias: {:?})",
-                sandbox.template_id,
-                sandbox.alias
-            );
-            const JUPYTER_PORT: u16 = 49_999;
-            let jupyter_host = format!(
-                "{}-{}.{}",
-                JUPYTER_PORT,
-                sandbox.sandbox_id,
-                sandbox_domain.as_str()
-            );
-            let jupyter_url = format!("{}://{}", envd_scheme, jupyter_host);
-            let mut api = CodeInterpreterApi::new(self.client.clone(), jupyter_url.clone());
-            if let Some(token) = access_token {
-                api.set_envd_access_token(token.to_string());
-            }
-            tracing::info!(
-                sandbox_id = %sandbox.sandbox_id,
-                jupyter_url = %jupyter_url,
-                "Configured code interpreter endpoint"
-            );
-            Some(api)
-        } else {
-            tracing::debug!("Code interpreter not initialized - neither template_id nor alias contains 'code-interpreter'");
-            None
-        };
+impl KeepaliveHandle {
+    pub(crate) fn new(task: tokio::task::JoinHandle<()>) -> Self {
+        Self { task }
+    }
 
-        Ok(SandboxInstance {
-            api,
-            sandbox,
-            commands,
-            files,
-            code_interpreter,
-        })
+    pub fn stop(self) {
+        self.task.abort();
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+}
+
+impl Drop for KeepaliveHandle {
+    fn drop(&mut self) {
+        self.task.abort();
     }
 }
 
 pub struct SandboxInstance {
     api: SandboxApi,
-    sandbox: Sandbox,
+    sandbox_id: String,
+    sandbox: RwLock<Sandbox>,
+    // Resolved once on attach; `refresh` replaces `sandbox` but not this
+    domain: String,
+    // The region the sandbox was created or connected in, which `fork` reuses
+    region: Option<Region>,
+    envd_url: String,
+    jupyter_url: Option<String>,
     commands: CommandsApi,
     files: FilesystemApi,
     code_interpreter: Option<CodeInterpreterApi>,
@@ -312,11 +740,92 @@ pub struct SandboxInstance {
 
 impl SandboxInstance {
     pub fn id(&self) -> &str {
-        &self.sandbox.sandbox_id
+        &self.sandbox_id
+    }
+
+    /// Environment variables set when the sandbox was created. Commands and
+    /// interpreter runs start from these; per-call `CommandOptions::envs` and
+    /// `CodeInterpreterOptions::env_vars` override them key by key.
+    pub fn default_env(&self) -> HashMap<String, String> {
+        self.cached().env_vars.clone().unwrap_or_default()
+    }
+
+    /// Snapshot of the cached sandbox details, as of creation or the last `refresh`.
+    ///
+    /// Breaking change: this used to return `&Sandbox`. The cache now sits
+    /// behind a lock so `refresh` can take `&self`, and a borrow cannot outlive
+    /// the guard; callers that stored the reference should keep the returned
+    /// value instead.
+    pub fn sandbox(&self) -> Sandbox {
+        self.cached().clone()
+    }
+
+    fn cached(&self) -> RwLockReadGuard<'_, Sandbox> {
+        self.sandbox.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Public hostname for `port` inside the sandbox, e.g. `3000-<id>.e2b.dev`.
+    pub fn get_host(&self, port: u16) -> String {
+        sandbox_host(port, &self.sandbox_id, &self.domain)
+    }
+
+    pub fn get_url(&self, port: u16) -> String {
+        sandbox_url(port, &self.sandbox_id, &self.domain)
     }
 
-    pub fn sandbox(&self) -> &Sandbox {
-        &self.sandbox
+    /// envd endpoint resolved when the sandbox was created or connected.
+    pub fn envd_url(&self) -> &str {
+        &self.envd_url
+    }
+
+    /// Jupyter endpoint, for code-interpreter templates only.
+    pub fn jupyter_url(&self) -> Option<&str> {
+        self.jupyter_url.as_deref()
+    }
+
+    /// Polls the public URL for `port` until a server inside the sandbox
+    /// answers, returning the URL, or `Error::Timeout` once `timeout` elapses.
+    pub async fn wait_for_port(&self, port: u16, timeout_duration: Duration) -> Result<String> {
+        const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+        const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+        let url = self.get_url(port);
+        let probe = async {
+            loop {
+                let response = self
+                    .api
+                    .client
+                    .http()
+                    .get(&url)
+                    .timeout(PROBE_TIMEOUT)
+                    .send()
+                    .await;
+
+                match response {
+                    // The edge proxy answers with a gateway error until the port is open
+                    Ok(response)
+                        if !matches!(
+                            response.status(),
+                            StatusCode::BAD_GATEWAY
+                                | StatusCode::SERVICE_UNAVAILABLE
+                                | StatusCode::GATEWAY_TIMEOUT
+                        ) =>
+                    {
+                        return;
+                    }
+                    Ok(response) => {
+                        tracing::debug!("port {} not ready: {}", port, response.status());
+                    }
+                    Err(e) => tracing::debug!("port {} not ready: {}", port, e),
+                }
+                tokio::time::sleep(PROBE_INTERVAL).await;
+            }
+        };
+
+        timeout(timeout_duration, probe)
+            .await
+            .map_err(|_| Error::Timeout)?;
+        Ok(url)
     }
 
     pub fn commands(&self) -> &CommandsApi {
@@ -336,16 +845,24 @@ impl SandboxInstance {
             .await
     }
 
+    /// Like `run_code`, but returns `Error::ExecutionError` when the run
+    /// failed by the same rule as `CodeExecution::has_error`, so `?`
+    /// propagates runtime failures.
+    pub async fn run_code_checked(&self, code: &str) -> Result<CodeExecution> {
+        self.run_code(code).await?.into_result()
+    }
+
     pub async fn run_code_with_language(&self, code: &str, language: &str) -> Result<Execution> {
         if let Some(interpreter) = &self.code_interpreter {
             // Add a small delay to ensure Jupyter server is ready
             tokio::time::sleep(std::time::Duration::from_millis(500)).await;
             interpreter.run_code_with_language(code, language).await
         } else {
+            let sandbox = self.cached();
             Err(Error::Api {
                 status: 400,
                 message: format!("Code interpreter not available. Template ID: '{}', Alias: '{:?}'. Use 'code-interpreter-v1' template to enable code execution with language support.",
-                    self.sandbox.template_id, self.sandbox.alias),
+                    sandbox.template_id, sandbox.alias),
             })
         }
     }
@@ -354,6 +871,33 @@ impl SandboxInstance {
         self.run_code_with_language(code, "python").await
     }
 
+    /// Runs Python code, streaming typed output messages through the returned
+    /// channel while the returned future resolves to the full `Execution`.
+    /// See `CodeInterpreterApi::run_code_streaming`.
+    pub fn run_python_streaming(
+        &self,
+        code: &str,
+    ) -> Result<(
+        mpsc::Receiver<ExecutionMessage>,
+        impl Future<Output = Result<Execution>> + Send + 'static,
+    )> {
+        let interpreter = self.code_interpreter.as_ref().ok_or_else(|| {
+            let sandbox = self.cached();
+            Error::Api {
+                status: 400,
+                message: format!(
+                    "Code interpreter not available for template '{}'",
+                    sandbox.template_id
+                ),
+            }
+        })?;
+        let options = CodeInterpreterOptions {
+            language: Some("python".to_string()),
+            ..Default::default()
+        };
+        Ok(interpreter.run_code_streaming(code, &options))
+    }
+
     pub async fn run_javascript(&self, code: &str) -> Result<Execution> {
         self.run_code_with_language(code, "javascript").await
     }
@@ -366,7 +910,7 @@ impl SandboxInstance {
         let url = self
             .api
             .client
-            .build_url(&format!("/sandboxes/{}/code", self.sandbox.sandbox_id));
+            .build_url(&format!("/sandboxes/{}/code", self.sandbox_id));
 
         let request_body = serde_json::json!({
             "code": code
@@ -376,7 +920,6 @@ impl SandboxInstance {
             let response = self
                 .api
                 .client
-                .http()
                 .post(&url)
                 .json(&request_body)
                 .send()
@@ -387,10 +930,9 @@ impl SandboxInstance {
                     let execution: CodeExecution = response.json().await?;
                     Ok(execution)
                 }
-                StatusCode::NOT_FOUND => Err(Error::NotFound(format!(
-                    "Sandbox {}",
-                    self.sandbox.sandbox_id
-                ))),
+                StatusCode::NOT_FOUND => {
+                    Err(Error::NotFound(format!("Sandbox {}", self.sandbox_id)))
+                }
                 status => {
                     let error_text = response.text().await.unwrap_or_default();
                     Err(Error::Api {
@@ -406,19 +948,39 @@ impl SandboxInstance {
             .map_err(|_| Error::Timeout)?
     }
 
+    pub async fn set_timeout(&self, seconds: u32) -> Result<()> {
+        self.api.set_timeout(&self.sandbox_id, seconds).await
+    }
+
+    /// Spawns a background task that calls `set_timeout(extend_to)` every
+    /// `interval`, keeping the sandbox alive until the handle is stopped or dropped.
+    pub fn keepalive(&self, interval: Duration, extend_to: u32) -> KeepaliveHandle {
+        let api = self.api.clone();
+        let sandbox_id = self.sandbox_id.clone();
+
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = api.set_timeout(&sandbox_id, extend_to).await {
+                    tracing::warn!(
+                        sandbox_id = %sandbox_id,
+                        "Failed to extend sandbox timeout: {}",
+                        e
+                    );
+                }
+            }
+        });
+
+        KeepaliveHandle::new(task)
+    }
+
     pub async fn pause(&self) -> Result<()> {
         let url = self
             .api
             .client
-            .build_url(&format!("/sandboxes/{}/pause", self.sandbox.sandbox_id));
-        let response = self
-            .api
-            .client
-            .http()
-            .post(&url)
-            .json(&json!({}))
-            .send()
-            .await?;
+            .build_url(&format!("/sandboxes/{}/pause", self.sandbox_id));
+        let response = self.api.client.post(&url).json(&json!({})).send().await?;
 
         let status = response.status();
         let body = response.text().await.unwrap_or_default();
@@ -426,10 +988,7 @@ impl SandboxInstance {
 
         match status {
             StatusCode::OK | StatusCode::NO_CONTENT | StatusCode::CREATED => Ok(()),
-            StatusCode::NOT_FOUND => Err(Error::NotFound(format!(
-                "Sandbox {}",
-                self.sandbox.sandbox_id
-            ))),
+            StatusCode::NOT_FOUND => Err(Error::NotFound(format!("Sandbox {}", self.sandbox_id))),
             _ => Err(Error::Api {
                 status: status.as_u16(),
                 message: body,
@@ -437,17 +996,24 @@ impl SandboxInstance {
         }
     }
 
+    /// Resumes a paused sandbox with the default five-minute timeout.
     pub async fn resume(&self) -> Result<()> {
+        self.resume_with_timeout(DEFAULT_RESUME_TIMEOUT).await
+    }
+
+    /// Resumes a paused sandbox and sets its timeout in the same call, so it
+    /// isn't paused again right away. The cached details are refreshed
+    /// afterwards so `paused_at` is cleared.
+    pub async fn resume_with_timeout(&self, seconds: u32) -> Result<()> {
         let url = self
             .api
             .client
-            .build_url(&format!("/sandboxes/{}/resume", self.sandbox.sandbox_id));
+            .build_url(&format!("/sandboxes/{}/resume", self.sandbox_id));
         let response = self
             .api
             .client
-            .http()
             .post(&url)
-            .json(&json!({}))
+            .json(&json!({ "timeout": seconds }))
             .send()
             .await?;
 
@@ -456,11 +1022,17 @@ impl SandboxInstance {
         tracing::debug!("resume response status={} body={}", status, body);
 
         match status {
-            StatusCode::OK | StatusCode::NO_CONTENT | StatusCode::CREATED => Ok(()),
-            StatusCode::NOT_FOUND => Err(Error::NotFound(format!(
-                "Sandbox {}",
-                self.sandbox.sandbox_id
-            ))),
+            StatusCode::OK | StatusCode::NO_CONTENT | StatusCode::CREATED => {
+                if let Err(e) = self.refresh().await {
+                    tracing::warn!(
+                        sandbox_id = %self.sandbox_id,
+                        "Failed to refresh sandbox after resume: {}",
+                        e
+                    );
+                }
+                Ok(())
+            }
+            StatusCode::NOT_FOUND => Err(Error::NotFound(format!("Sandbox {}", self.sandbox_id))),
             _ => Err(Error::Api {
                 status: status.as_u16(),
                 message: body,
@@ -468,19 +1040,97 @@ impl SandboxInstance {
         }
     }
 
+    /// Creates a new sandbox from the same template and copies `/home/user`
+    /// into it.
+    ///
+    /// The API has no native clone-from-snapshot, so this is an approximation:
+    /// the directory is archived with `tar`, transferred and unpacked in the
+    /// new sandbox. Running processes, memory state, interpreter contexts and
+    /// files outside `/home/user` are not carried over, and the new sandbox gets
+    /// the default timeout. A pause-based snapshot would preserve all of that,
+    /// but a paused sandbox can only be resumed under its own ID.
+    pub async fn fork(&self) -> Result<SandboxInstance> {
+        const FORK_ROOT: &str = "/home/user";
+        let archive = temp_path("e2b-fork-", ".tar.gz");
+        let archive_arg = shell_escape(&archive);
+
+        let packed = self
+            .commands
+            .run(&format!(
+                "tar -czf {} -C {} .",
+                archive_arg,
+                shell_escape(FORK_ROOT)
+            ))
+            .await?;
+        if packed.exit_code != 0 {
+            return Err(Error::Envd {
+                status: 500,
+                message: format!("Failed to archive {}: {}", FORK_ROOT, packed.stderr),
+            });
+        }
+        let data = self.files.read_binary(&archive).await;
+        if let Err(e) = self.files.remove(&archive).await {
+            tracing::warn!("Failed to remove fork archive {}: {}", archive, e);
+        }
+        let data = data?;
+
+        let source = self.sandbox();
+        let fork = self
+            .api
+            .create_from_request(SandboxCreateRequest {
+                template_id: source.template_id,
+                timeout: None,
+                auto_pause: None,
+                secure: source.secure,
+                allow_internet_access: source.allow_internet_access,
+                metadata: source.metadata,
+                env_vars: source.env_vars,
+                region: self.region.as_ref().map(Region::to_string),
+                network: source.network,
+            })
+            .await?;
+
+        let restored = async {
+            fork.files.write_binary(&archive, data).await?;
+            let unpacked = fork
+                .commands
+                .run(&format!(
+                    "mkdir -p {root} && tar -xzf {archive} -C {root}; status=$?; rm -f {archive}; exit $status",
+                    root = shell_escape(FORK_ROOT),
+                    archive = archive_arg
+                ))
+                .await?;
+            if unpacked.exit_code != 0 {
+                return Err(Error::Envd {
+                    status: 500,
+                    message: format!("Failed to restore {}: {}", FORK_ROOT, unpacked.stderr),
+                });
+            }
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = restored {
+            let fork_id = fork.id().to_string();
+            if let Err(cleanup) = fork.delete().await {
+                tracing::warn!("Failed to delete incomplete fork {}: {}", fork_id, cleanup);
+            }
+            return Err(e);
+        }
+
+        Ok(fork)
+    }
+
     pub async fn delete(self) -> Result<()> {
         let url = self
             .api
             .client
-            .build_url(&format!("/sandboxes/{}", self.sandbox.sandbox_id));
-        let response = self.api.client.http().delete(&url).send().await?;
+            .build_url(&format!("/sandboxes/{}", self.sandbox_id));
+        let response = self.api.client.delete(&url).send().await?;
 
         match response.status() {
             StatusCode::OK | StatusCode::NO_CONTENT => Ok(()),
-            StatusCode::NOT_FOUND => Err(Error::NotFound(format!(
-                "Sandbox {}",
-                self.sandbox.sandbox_id
-            ))),
+            StatusCode::NOT_FOUND => Err(Error::NotFound(format!("Sandbox {}", self.sandbox_id))),
             status => {
                 let error_text = response.text().await.unwrap_or_default();
                 Err(Error::Api {
@@ -495,8 +1145,8 @@ impl SandboxInstance {
         let url = self
             .api
             .client
-            .build_url(&format!("/sandboxes/{}/logs", self.sandbox.sandbox_id));
-        let response = self.api.client.http().get(&url).send().await?;
+            .build_url(&format!("/sandboxes/{}/logs", self.sandbox_id));
+        let response = self.api.client.get(&url).send().await?;
         let status = response.status();
         let body = response.text().await.unwrap_or_default();
         tracing::debug!("sandbox logs response: {}", body);
@@ -508,10 +1158,8 @@ impl SandboxInstance {
             });
         }
 
-        let value: Value = serde_json::from_str(&body).map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to parse logs response: {}", e),
-        })?;
+        let value: Value = serde_json::from_str(&body)
+            .map_err(|e| Error::wrap(500, "Failed to parse logs response", e))?;
 
         let mut entries = Vec::new();
 
@@ -545,8 +1193,8 @@ impl SandboxInstance {
         let url = self
             .api
             .client
-            .build_url(&format!("/sandboxes/{}/metrics", self.sandbox.sandbox_id));
-        let response = self.api.client.http().get(&url).send().await?;
+            .build_url(&format!("/sandboxes/{}/metrics", self.sandbox_id));
+        let response = self.api.client.get(&url).send().await?;
         let status = response.status();
         let body = response.text().await.unwrap_or_default();
         tracing::debug!("sandbox metrics response: {}", body);
@@ -558,10 +1206,8 @@ impl SandboxInstance {
             });
         }
 
-        let value: Value = serde_json::from_str(&body).map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to parse metrics response: {}", e),
-        })?;
+        let value: Value = serde_json::from_str(&body)
+            .map_err(|e| Error::wrap(500, "Failed to parse metrics response", e))?;
 
         if let Some(array) = value.as_array() {
             if let Some(first) = array.first() {
@@ -573,8 +1219,64 @@ impl SandboxInstance {
         Self::parse_metrics(&value)
     }
 
-    pub async fn refresh(&mut self) -> Result<()> {
-        self.sandbox = self.api.get(&self.sandbox.sandbox_id).await?;
+    /// Fetches the current lifecycle state, leaving the cached `Sandbox`
+    /// untouched. The API has no dedicated status endpoint, so this reads the
+    /// sandbox details but only decodes the state fields.
+    pub async fn state(&self) -> Result<SandboxState> {
+        #[derive(serde::Deserialize)]
+        struct StateOnly {
+            state: Option<SandboxState>,
+            #[serde(alias = "isLive")]
+            is_live: Option<bool>,
+        }
+
+        let url = self
+            .api
+            .client
+            .build_url(&format!("/sandboxes/{}", self.sandbox_id));
+        let response = self.api.client.get(&url).send_with_retry().await?;
+
+        let status = match response.status() {
+            StatusCode::OK => response.json::<StateOnly>().await?,
+            StatusCode::NOT_FOUND => {
+                return Err(Error::NotFound(format!("Sandbox {}", self.sandbox_id)))
+            }
+            status => {
+                let error_text = response.text().await.unwrap_or_default();
+                return Err(Error::Api {
+                    status: status.as_u16(),
+                    message: error_text,
+                });
+            }
+        };
+
+        match (status.state, status.is_live) {
+            (Some(state), _) => Ok(state),
+            (None, Some(true)) => Ok(SandboxState::Running),
+            (None, Some(false)) => Ok(SandboxState::Paused),
+            (None, None) => Err(Error::invalid_response(format!(
+                "Sandbox {} reported neither a state nor isLive",
+                self.sandbox_id
+            ))),
+        }
+    }
+
+    /// Whether the sandbox currently exists and is running, as reported by
+    /// the API rather than the cached `Sandbox::is_live`. A sandbox that no
+    /// longer exists yields `Ok(false)`.
+    pub async fn is_live_fresh(&self) -> Result<bool> {
+        match self.state().await {
+            Ok(state) => Ok(state == SandboxState::Running),
+            Err(Error::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Re-fetches the sandbox details. Takes `&self`, so an `Arc<SandboxInstance>`
+    /// shared between tasks can be refreshed without an outer lock.
+    pub async fn refresh(&self) -> Result<()> {
+        let sandbox = self.api.get(&self.sandbox_id).await?;
+        *self.sandbox.write().unwrap_or_else(PoisonError::into_inner) = sandbox;
         Ok(())
     }
 
@@ -667,3 +1369,253 @@ impl SandboxInstance {
         Utc::now()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder() -> SandboxBuilder {
+        SandboxBuilder::new(Client::with_api_key("test"), "base".to_string())
+    }
+
+    #[test]
+    fn allow_domains_denies_everything_else() {
+        let builder = builder()
+            .allow_domains(&["pypi.org"])
+            .allow_domains(&["npmjs.org"]);
+        let body = serde_json::to_value(&builder.request).unwrap();
+
+        assert_eq!(body["allow_internet_access"], json!(true));
+        assert_eq!(
+            body["network"]["allowOut"],
+            json!(["pypi.org", "npmjs.org"])
+        );
+        assert_eq!(body["network"]["denyOut"], json!([DENY_ALL_EGRESS]));
+    }
+
+    #[test]
+    fn network_is_omitted_without_allowlist() {
+        let body = serde_json::to_value(&builder().request).unwrap();
+        assert!(body.get("network").is_none());
+    }
+
+    #[tokio::test]
+    async fn attach_keeps_the_region_for_forks() {
+        let config =
+            crate::config::Config::with_api_key("test").sandbox_domain_override("sandbox.test");
+        let api = SandboxApi::new(Client::with_config(config).unwrap());
+        let sandbox: Sandbox =
+            serde_json::from_value(json!({ "sandboxID": "abc", "templateID": "base" })).unwrap();
+        let region = Region::from("eu-west");
+        let instance = api.attach(sandbox, Some(&region), true).await.unwrap();
+        assert_eq!(instance.region, Some(region));
+    }
+
+    #[tokio::test]
+    async fn resolved_domain_survives_a_refreshed_sandbox() {
+        let config =
+            crate::config::Config::with_api_key("test").sandbox_domain_override("sandbox.test");
+        let api = SandboxApi::new(Client::with_config(config).unwrap());
+        let sandbox: Sandbox =
+            serde_json::from_value(json!({ "sandboxID": "abc", "templateID": "base" })).unwrap();
+        let instance = api.attach(sandbox.clone(), None, true).await.unwrap();
+        assert_eq!(instance.get_url(3000), "https://3000-abc.sandbox.test");
+        assert_eq!(
+            instance.envd_url(),
+            sandbox_url(ENVD_PORT, "abc", "sandbox.test")
+        );
+
+        // What `refresh` stores: the API's view, without the override
+        *instance.sandbox.write().unwrap() = Sandbox {
+            domain: Some("e2b.app".to_string()),
+            ..sandbox
+        };
+        assert_eq!(instance.get_host(3000), "3000-abc.sandbox.test");
+    }
+
+    async fn state_reported_as(
+        reply: fn(&str) -> (&'static str, &'static str),
+    ) -> Result<SandboxState> {
+        let (url, _requests) = crate::test_support::serve(reply).await;
+        let config = crate::config::Config::with_api_key("test")
+            .base_url(url)
+            .sandbox_domain_override("sandbox.test");
+        let api = SandboxApi::new(Client::with_config(config).unwrap());
+        let sandbox: Sandbox =
+            serde_json::from_value(json!({ "sandboxID": "abc", "templateID": "base" })).unwrap();
+        let instance = api.attach(sandbox, None, true).await.unwrap();
+        let state = instance.state().await;
+        assert!(instance.sandbox().is_live, "state() wrote to the cache");
+        state
+    }
+
+    #[tokio::test]
+    async fn state_falls_back_to_is_live_without_touching_the_cache() {
+        let paused = state_reported_as(|_| ("200 OK", r#"{"isLive":false}"#)).await;
+        assert_eq!(paused.unwrap(), SandboxState::Paused);
+        let running = state_reported_as(|_| ("200 OK", r#"{"isLive":true}"#)).await;
+        assert_eq!(running.unwrap(), SandboxState::Running);
+        let reported =
+            state_reported_as(|_| ("200 OK", r#"{"state":"paused","isLive":true}"#)).await;
+        assert_eq!(reported.unwrap(), SandboxState::Paused);
+    }
+
+    #[tokio::test]
+    async fn state_without_state_fields_is_an_invalid_response() {
+        match state_reported_as(|_| ("200 OK", "{}")).await {
+            Err(Error::InvalidResponse { message, .. }) => assert!(message.contains("abc")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn secure_sandbox_without_token_fails_create() {
+        // Every sandbox comes back secure but without an access token
+        let (url, mut requests) = crate::test_support::serve(|line| {
+            if line.starts_with("POST") {
+                (
+                    "200 OK",
+                    r#"{"sandboxID":"abc","templateID":"base","secure":true}"#,
+                )
+            } else {
+                ("200 OK", "")
+            }
+        })
+        .await;
+        let config = crate::config::Config::with_api_key("test").base_url(url);
+        let result = Client::with_config(config)
+            .unwrap()
+            .sandbox()
+            .template("base")
+            .secure(true)
+            .create()
+            .await;
+
+        match result {
+            Err(Error::Authentication(message)) => assert!(message.contains("access token")),
+            Err(other) => panic!("unexpected error {:?}", other),
+            Ok(_) => panic!("created a secure sandbox without a token"),
+        }
+        assert!(requests
+            .recv()
+            .await
+            .unwrap()
+            .starts_with("POST /sandboxes"));
+        // The sandbox that could not be used is deleted again
+        assert!(requests
+            .recv()
+            .await
+            .unwrap()
+            .starts_with("DELETE /sandboxes/abc"));
+    }
+
+    #[tokio::test]
+    async fn resume_sends_the_timeout_and_clears_paused_at() {
+        let (url, mut requests) = crate::test_support::serve(|line| {
+            if line.starts_with("GET") {
+                ("200 OK", r#"{"sandboxID":"abc","templateID":"base"}"#)
+            } else {
+                ("200 OK", "")
+            }
+        })
+        .await;
+        let config = crate::config::Config::with_api_key("test")
+            .base_url(url)
+            .sandbox_domain_override("sandbox.test");
+        let api = SandboxApi::new(Client::with_config(config).unwrap());
+        let sandbox: Sandbox = serde_json::from_value(json!({
+            "sandboxID": "abc",
+            "templateID": "base",
+            "pausedAt": "2024-01-01T00:00:00Z",
+        }))
+        .unwrap();
+        let instance = api.attach(sandbox, None, true).await.unwrap();
+
+        instance.resume_with_timeout(600).await.unwrap();
+
+        let resume = requests.recv().await.unwrap();
+        assert!(resume.starts_with("POST /sandboxes/abc/resume"));
+        assert!(resume.ends_with(r#"{"timeout":600}"#));
+        assert!(requests
+            .recv()
+            .await
+            .unwrap()
+            .starts_with("GET /sandboxes/abc"));
+        assert!(instance.sandbox().paused_at.is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn resumed_sandbox_runs_commands_without_set_timeout() {
+        let sandbox = crate::test_support::live_sandbox().await;
+        sandbox.pause().await.unwrap();
+        sandbox.resume_with_timeout(120).await.unwrap();
+
+        assert!(sandbox.sandbox().paused_at.is_none());
+        let echoed = sandbox.commands().run("echo resumed").await.unwrap();
+        assert_eq!(echoed.stdout.trim(), "resumed");
+        sandbox.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keepalive_extends_the_timeout_until_stopped_or_dropped() {
+        let (url, mut requests) = crate::test_support::serve(|_| ("200 OK", "")).await;
+        let config = crate::config::Config::with_api_key("test")
+            .base_url(url)
+            .sandbox_domain_override("sandbox.test");
+        let api = SandboxApi::new(Client::with_config(config).unwrap());
+        let sandbox: Sandbox =
+            serde_json::from_value(json!({ "sandboxID": "abc", "templateID": "base" })).unwrap();
+        let instance = api.attach(sandbox, None, true).await.unwrap();
+
+        let stop: fn(KeepaliveHandle) = KeepaliveHandle::stop;
+        for end in [stop, drop] {
+            let handle = instance.keepalive(Duration::from_millis(20), 300);
+            for _ in 0..2 {
+                let request = requests.recv().await.unwrap();
+                assert!(request.starts_with("POST /sandboxes/abc/timeout"));
+                assert!(request.ends_with(r#"{"timeout":300}"#));
+            }
+            end(handle);
+
+            // Let a request already in flight land, then expect silence
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            while requests.try_recv().is_ok() {}
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(requests.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn dropping_keepalive_handle_cancels_the_task() {
+        let (tx, mut rx) = mpsc::unbounded_channel::<()>();
+        let handle = KeepaliveHandle::new(tokio::spawn(async move {
+            let _tx = tx;
+            std::future::pending::<()>().await;
+        }));
+        assert!(handle.is_running());
+        drop(handle);
+        // The sender is dropped with the aborted task
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn lazy_sandbox_waits_for_envd_on_first_use() {
+        let sandbox = Client::new()
+            .unwrap()
+            .sandbox()
+            .template("base")
+            .create_lazy()
+            .await
+            .unwrap();
+
+        let (echoed, listed) = tokio::join!(
+            sandbox.commands().run("echo ready"),
+            sandbox.files().list("/tmp")
+        );
+        assert_eq!(echoed.unwrap().stdout.trim(), "ready");
+        listed.unwrap();
+        sandbox.delete().await.unwrap();
+    }
+}
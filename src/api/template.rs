@@ -1,9 +1,16 @@
 use crate::{
     client::Client,
     error::{Error, Result},
-    models::{Template, TemplateBuild, TemplateCreateRequest},
+    models::{
+        BuildLog, BuildLogLevel, BuildStatus, Template, TemplateBuild, TemplateCreateRequest,
+        TemplateFilter,
+    },
 };
+use chrono::{DateTime, Utc};
 use reqwest::StatusCode;
+use std::time::Duration;
+
+const BUILD_POLL_INTERVAL: Duration = Duration::from_secs(2);
 
 #[derive(Clone)]
 pub struct TemplateApi {
@@ -17,7 +24,7 @@ impl TemplateApi {
 
     pub async fn list(&self) -> Result<Vec<Template>> {
         let url = self.client.build_url("/templates");
-        let response = self.client.http().get(&url).send().await?;
+        let response = self.client.get(&url).send_with_retry().await?;
 
         match response.status() {
             StatusCode::OK => {
@@ -34,11 +41,33 @@ impl TemplateApi {
         }
     }
 
+    /// Public templates from the shared catalog.
+    pub async fn list_public(&self) -> Result<Vec<Template>> {
+        self.list_filtered(TemplateFilter::Public).await
+    }
+
+    /// The team's own private templates.
+    pub async fn list_team(&self) -> Result<Vec<Template>> {
+        self.list_filtered(TemplateFilter::Team).await
+    }
+
+    /// Lists templates by visibility. The endpoint has no visibility
+    /// parameter, so this filters the full list on `Template::public`.
+    pub async fn list_filtered(&self, filter: TemplateFilter) -> Result<Vec<Template>> {
+        let mut templates = self.list().await?;
+        match filter {
+            TemplateFilter::All => {}
+            TemplateFilter::Public => templates.retain(|t| t.public),
+            TemplateFilter::Team => templates.retain(|t| !t.public),
+        }
+        Ok(templates)
+    }
+
     pub async fn get(&self, template_id: &str) -> Result<Template> {
         let url = self
             .client
             .build_url(&format!("/templates/{}", template_id));
-        let response = self.client.http().get(&url).send().await?;
+        let response = self.client.get(&url).send_with_retry().await?;
 
         match response.status() {
             StatusCode::OK => {
@@ -56,9 +85,78 @@ impl TemplateApi {
         }
     }
 
+    pub async fn builds(&self, template_id: &str) -> Result<Vec<TemplateBuild>> {
+        let url = self
+            .client
+            .build_url(&format!("/templates/{}/builds", template_id));
+        let response = self.client.get(&url).send_with_retry().await?;
+
+        match response.status() {
+            StatusCode::OK => {
+                let builds: Vec<TemplateBuild> = response.json().await?;
+                Ok(builds)
+            }
+            StatusCode::NOT_FOUND => Err(Error::NotFound(format!("Template {}", template_id))),
+            status => {
+                let error_text = response.text().await.unwrap_or_default();
+                Err(Error::Api {
+                    status: status.as_u16(),
+                    message: error_text,
+                })
+            }
+        }
+    }
+
+    /// Status of the most recently started build of `template_id`, or `None`
+    /// if it has never been built.
+    pub async fn build_status(&self, template_id: &str) -> Result<Option<BuildStatus>> {
+        let builds = self.builds(template_id).await?;
+        Ok(builds
+            .into_iter()
+            .max_by_key(|b| b.created_at)
+            .map(|b| b.status))
+    }
+
+    /// Polls until `template_id` is usable: some build is `Ready` (an older
+    /// one counts even while a newer build is running or has failed) or it
+    /// has never been built. Fails with `Error::Timeout` after `max_wait`, and
+    /// with a `409` API error if no build is ready and the latest one ended
+    /// in `Error` or `Canceled`.
+    pub async fn wait_for_build(&self, template_id: &str, max_wait: Duration) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + max_wait;
+        loop {
+            let builds = self.builds(template_id).await?;
+            match usable_status(&builds) {
+                None | Some(BuildStatus::Ready) => return Ok(()),
+                Some(BuildStatus::Building) => {}
+                Some(status) => {
+                    return Err(Error::Api {
+                        status: StatusCode::CONFLICT.as_u16(),
+                        message: format!(
+                            "Template {} is not built: no build is ready and the latest is {:?}",
+                            template_id, status
+                        ),
+                    })
+                }
+            }
+            if tokio::time::Instant::now() + BUILD_POLL_INTERVAL > deadline {
+                return Err(Error::Timeout);
+            }
+            tracing::debug!("Template {} is still building", template_id);
+            tokio::time::sleep(BUILD_POLL_INTERVAL).await;
+        }
+    }
+
+    pub async fn get_with_builds(
+        &self,
+        template_id: &str,
+    ) -> Result<(Template, Vec<TemplateBuild>)> {
+        tokio::try_join!(self.get(template_id), self.builds(template_id))
+    }
+
     pub async fn create(&self, request: TemplateCreateRequest) -> Result<TemplateInstance> {
         let url = self.client.build_url("/templates");
-        let response = self.client.http().post(&url).json(&request).send().await?;
+        let response = self.client.post(&url).json(&request).send().await?;
 
         match response.status() {
             StatusCode::CREATED | StatusCode::OK => {
@@ -121,25 +219,66 @@ impl TemplateBuilder {
         self
     }
 
+    /// Number of vCPUs; must be at least 1.
     pub fn cpu_count(mut self, count: u32) -> Self {
         self.request.cpu_count = Some(count);
         self
     }
 
+    /// Memory in MiB; must be at least 128.
     pub fn memory_mb(mut self, memory: u32) -> Self {
         self.request.memory_mb = Some(memory);
         self
     }
 
+    /// Disk size in MiB; must be non-zero and no smaller than the memory size.
     pub fn disk_mb(mut self, disk: u32) -> Self {
         self.request.disk_mb = Some(disk);
         self
     }
 
     pub async fn create(self) -> Result<TemplateInstance> {
+        self.validate()?;
         let api = TemplateApi::new(self.client);
         api.create(self.request).await
     }
+
+    fn validate(&self) -> Result<()> {
+        const MIN_MEMORY_MB: u32 = 128;
+
+        if self.request.cpu_count == Some(0) {
+            return Err(Error::Configuration(
+                "cpu_count must be at least 1".to_string(),
+            ));
+        }
+
+        if let Some(memory) = self.request.memory_mb {
+            if memory < MIN_MEMORY_MB {
+                return Err(Error::Configuration(format!(
+                    "memory_mb must be at least {} (got {})",
+                    MIN_MEMORY_MB, memory
+                )));
+            }
+        }
+
+        if let Some(disk) = self.request.disk_mb {
+            if disk == 0 {
+                return Err(Error::Configuration(
+                    "disk_mb must be greater than 0".to_string(),
+                ));
+            }
+            if let Some(memory) = self.request.memory_mb {
+                if disk < memory {
+                    return Err(Error::Configuration(format!(
+                        "disk_mb ({}) must not be smaller than memory_mb ({})",
+                        disk, memory
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 pub struct TemplateInstance {
@@ -161,7 +300,7 @@ impl TemplateInstance {
             .api
             .client
             .build_url(&format!("/templates/{}/builds", self.template.template_id));
-        let response = self.api.client.http().post(&url).send().await?;
+        let response = self.api.client.post(&url).send().await?;
 
         match response.status() {
             StatusCode::CREATED | StatusCode::OK => {
@@ -183,21 +322,37 @@ impl TemplateInstance {
     }
 
     pub async fn builds(&self) -> Result<Vec<TemplateBuild>> {
-        let url = self
-            .api
-            .client
-            .build_url(&format!("/templates/{}/builds", self.template.template_id));
-        let response = self.api.client.http().get(&url).send().await?;
+        self.api.builds(&self.template.template_id).await
+    }
+
+    pub async fn cancel_build(&self, build_id: &str) -> Result<TemplateBuild> {
+        let url = self.api.client.build_url(&format!(
+            "/templates/{}/builds/{}/cancel",
+            self.template.template_id, build_id
+        ));
+        let response = self.api.client.post(&url).send().await?;
 
         match response.status() {
-            StatusCode::OK => {
-                let builds: Vec<TemplateBuild> = response.json().await?;
-                Ok(builds)
+            StatusCode::OK | StatusCode::CREATED | StatusCode::ACCEPTED => {
+                let build: TemplateBuild = response.json().await?;
+                Ok(build)
             }
             StatusCode::NOT_FOUND => Err(Error::NotFound(format!(
-                "Template {}",
-                self.template.template_id
+                "Build {} of template {}",
+                build_id, self.template.template_id
             ))),
+            status @ (StatusCode::CONFLICT | StatusCode::BAD_REQUEST) => {
+                // The build may have finished between the caller's status check
+                // and the cancel request; report its final state in that case.
+                let error_text = response.text().await.unwrap_or_default();
+                match self.find_build(build_id).await? {
+                    Some(build) if build.status != BuildStatus::Building => Ok(build),
+                    _ => Err(Error::Api {
+                        status: status.as_u16(),
+                        message: error_text,
+                    }),
+                }
+            }
             status => {
                 let error_text = response.text().await.unwrap_or_default();
                 Err(Error::Api {
@@ -208,12 +363,76 @@ impl TemplateInstance {
         }
     }
 
+    /// Logs of `build_id`, skipping the first `logs_offset` entries the
+    /// status endpoint would return for the same `min_level`. Pass the number
+    /// of entries already received to fetch only new ones. `since` further
+    /// drops entries older than it; it is inclusive, so entries sharing the
+    /// cursor's timestamp are kept.
+    pub async fn build_logs(
+        &self,
+        build_id: &str,
+        logs_offset: usize,
+        since: Option<DateTime<Utc>>,
+        min_level: Option<BuildLogLevel>,
+    ) -> Result<Vec<BuildLog>> {
+        #[derive(serde::Deserialize)]
+        struct BuildStatusResponse {
+            #[serde(default, alias = "logEntries")]
+            log_entries: Vec<BuildLog>,
+        }
+
+        let url = self.api.client.build_url(&format!(
+            "/templates/{}/builds/{}/status",
+            self.template.template_id, build_id
+        ));
+        let mut query = vec![("logsOffset", logs_offset.to_string())];
+        if let Some(level) = min_level.and_then(BuildLogLevel::as_query) {
+            query.push(("level", level.to_string()));
+        }
+        let response = self
+            .api
+            .client
+            .get(&url)
+            .query(&query)
+            .send_with_retry()
+            .await?;
+
+        let logs = match response.status() {
+            StatusCode::OK => response.json::<BuildStatusResponse>().await?.log_entries,
+            StatusCode::NOT_FOUND => {
+                return Err(Error::NotFound(format!(
+                    "Build {} of template {}",
+                    build_id, self.template.template_id
+                )))
+            }
+            status => {
+                let error_text = response.text().await.unwrap_or_default();
+                return Err(Error::Api {
+                    status: status.as_u16(),
+                    message: error_text,
+                });
+            }
+        };
+
+        // Older servers ignore the level parameter
+        Ok(logs
+            .into_iter()
+            .filter(|log| since.is_none_or(|since| log.timestamp >= since))
+            .filter(|log| min_level.is_none_or(|min| log.level.at_least(min)))
+            .collect())
+    }
+
+    async fn find_build(&self, build_id: &str) -> Result<Option<TemplateBuild>> {
+        let builds = self.builds().await?;
+        Ok(builds.into_iter().find(|b| b.build_id == build_id))
+    }
+
     pub async fn delete(self) -> Result<()> {
         let url = self
             .api
             .client
             .build_url(&format!("/templates/{}", self.template.template_id));
-        let response = self.api.client.http().delete(&url).send().await?;
+        let response = self.api.client.delete(&url).send().await?;
 
         match response.status() {
             StatusCode::OK | StatusCode::NO_CONTENT => Ok(()),
@@ -236,3 +455,98 @@ impl TemplateInstance {
         Ok(())
     }
 }
+
+// `Ready` if any build is, so a failed rebuild doesn't hide a usable one;
+// otherwise the status of the latest build
+fn usable_status(builds: &[TemplateBuild]) -> Option<BuildStatus> {
+    if builds.iter().any(|b| b.status == BuildStatus::Ready) {
+        return Some(BuildStatus::Ready);
+    }
+    builds
+        .iter()
+        .max_by_key(|b| b.created_at)
+        .map(|b| b.status.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn build(status: BuildStatus, minute: u32) -> TemplateBuild {
+        let at = Utc.with_ymd_and_hms(2024, 1, 1, 0, minute, 0).unwrap();
+        TemplateBuild {
+            build_id: format!("b{}", minute),
+            template_id: "t".to_string(),
+            status,
+            dockerfile: String::new(),
+            logs: Vec::new(),
+            created_at: at,
+            updated_at: at,
+            finished_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_build_keeps_the_api_status() {
+        // The cancel request is rejected; the builds listing is empty
+        let (url, _requests) = crate::test_support::serve(|line| {
+            if line.starts_with("POST") {
+                ("400 Bad Request", "not cancellable")
+            } else {
+                ("200 OK", "[]")
+            }
+        })
+        .await;
+        let config = crate::config::Config::with_api_key("test").base_url(url);
+        let client = Client::with_config(config).unwrap();
+        let now = Utc::now();
+        let instance = TemplateInstance {
+            api: TemplateApi::new(client),
+            template: Template {
+                template_id: "t".to_string(),
+                name: "t".to_string(),
+                description: None,
+                team_id: String::new(),
+                build_id: None,
+                public: false,
+                cpu_count: 1,
+                memory_mb: 512,
+                disk_mb: 512,
+                created_at: now,
+                updated_at: now,
+            },
+        };
+
+        match instance.cancel_build("b").await {
+            Err(Error::Api { status, message }) => {
+                assert_eq!(status, 400);
+                assert_eq!(message, "not cancellable");
+            }
+            other => panic!(
+                "expected a 400 API error, got {:?}",
+                other.map(|b| b.build_id)
+            ),
+        }
+    }
+
+    #[test]
+    fn older_ready_build_keeps_template_usable() {
+        let builds = [build(BuildStatus::Ready, 0), build(BuildStatus::Error, 5)];
+        assert_eq!(usable_status(&builds), Some(BuildStatus::Ready));
+    }
+
+    #[test]
+    fn latest_build_decides_without_a_ready_one() {
+        let builds = [
+            build(BuildStatus::Error, 0),
+            build(BuildStatus::Building, 5),
+        ];
+        assert_eq!(usable_status(&builds), Some(BuildStatus::Building));
+        assert_eq!(
+            usable_status(&[build(BuildStatus::Canceled, 0)]),
+            Some(BuildStatus::Canceled)
+        );
+        assert_eq!(usable_status(&[]), None);
+    }
+}
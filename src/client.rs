@@ -1,16 +1,94 @@
 use crate::{
-    api::{SandboxApi, TemplateApi},
+    api::{
+        sandbox::{MetadataIndex, SandboxBuilder},
+        SandboxApi, TemplateApi,
+    },
     config::Config,
     error::{Error, Result},
+    retry::RetryPolicy,
 };
-use reqwest::{header, Client as HttpClient};
-use std::time::Duration;
+use reqwest::{header, Client as HttpClient, RequestBuilder, Response, StatusCode};
+use serde::Serialize;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 use tracing::debug;
 
+pub struct RequestCapture<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    pub body: Option<&'a [u8]>,
+}
+
+pub struct ResponseCapture<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    pub status: u16,
+    pub body: &'a [u8],
+}
+
+// Backoff window shared by every clone of a `Client`, so a `429` seen by one
+// clone pauses requests from all of them.
+#[derive(Debug, Default)]
+struct RateLimitState {
+    until: Mutex<Option<Instant>>,
+}
+
+impl RateLimitState {
+    fn remaining(&self) -> Option<Duration> {
+        let until = (*self.until.lock().unwrap_or_else(PoisonError::into_inner))?;
+        until.checked_duration_since(Instant::now())
+    }
+
+    async fn wait(&self) {
+        if let Some(remaining) = self.remaining() {
+            debug!("Rate limited, waiting {:?} before sending", remaining);
+            tokio::time::sleep(remaining).await;
+        }
+    }
+
+    fn back_off(&self, window: Duration) {
+        let candidate = Instant::now() + window;
+        let mut until = self.until.lock().unwrap_or_else(PoisonError::into_inner);
+        if until.is_none_or(|current| current < candidate) {
+            *until = Some(candidate);
+        }
+    }
+}
+
+// `Retry-After` is either a number of seconds or an HTTP date
+fn retry_after(headers: &header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+// How long a `429` pauses all clones: `Retry-After` when present, capped so a
+// huge or hostile value cannot stall the client indefinitely
+fn backoff_window(headers: &header::HeaderMap, policy: &RetryPolicy) -> Duration {
+    retry_after(headers)
+        .unwrap_or(policy.base_delay)
+        .min(policy.max_delay)
+}
+
+type RequestHook = Arc<dyn Fn(&RequestCapture<'_>) + Send + Sync>;
+type ResponseHook = Arc<dyn Fn(&ResponseCapture<'_>) + Send + Sync>;
+
 #[derive(Clone)]
 pub struct Client {
     http: HttpClient,
     config: Config,
+    on_request: Option<RequestHook>,
+    on_response: Option<ResponseHook>,
+    request_timeout: Option<Duration>,
+    metadata_index: Arc<MetadataIndex>,
+    rate_limit: Arc<RateLimitState>,
+    // Shared by clones of this client only, see `set_api_key`
+    api_key: Arc<RwLock<header::HeaderValue>>,
 }
 
 impl Client {
@@ -27,36 +105,127 @@ impl Client {
     pub fn with_config(config: Config) -> Result<Self> {
         let mut headers = header::HeaderMap::new();
 
-        let api_key_header = header::HeaderValue::from_str(&config.api_key)
-            .map_err(|_| Error::Configuration("Invalid API key format".to_string()))?;
-        headers.insert("X-API-Key", api_key_header);
+        let api_key = Self::api_key_header(&config.api_key)?;
         headers.insert(
             header::USER_AGENT,
             header::HeaderValue::from_static("e2b-rust-sdk/0.1.0"),
         );
 
-        let http = HttpClient::builder()
+        let builder = HttpClient::builder()
             .default_headers(headers)
-            .timeout(Duration::from_secs(config.timeout_seconds))
-            .build()?;
+            .timeout(Duration::from_secs(config.timeout_seconds));
+        let http = config.apply_tls(builder)?.build()?;
 
         debug!("E2B client initialized with base URL: {}", config.base_url);
 
-        Ok(Self { http, config })
+        Ok(Self {
+            http,
+            config,
+            on_request: None,
+            on_response: None,
+            request_timeout: None,
+            metadata_index: Arc::default(),
+            rate_limit: Arc::default(),
+            api_key: Arc::new(RwLock::new(api_key)),
+        })
+    }
+
+    /// Replaces the API key used for subsequent requests without rebuilding
+    /// the underlying HTTP client, so the connection pool is kept.
+    ///
+    /// The key is shared by the clones of this `Client` (and by the APIs and
+    /// sandboxes created from it), so rotating it on one handle rotates it
+    /// for all of them; it is safe to call while other clones are sending.
+    /// Clients built separately, even from a clone of the same `Config`,
+    /// keep their own key, and `Config::api_key` keeps the key the client was
+    /// built with. Requests already in flight keep the key they were sent with.
+    pub fn set_api_key(&mut self, key: impl Into<String>) -> Result<()> {
+        let value = Self::api_key_header(&key.into())?;
+        *self.api_key.write().unwrap_or_else(PoisonError::into_inner) = value;
+        Ok(())
+    }
+
+    fn api_key_header(key: &str) -> Result<header::HeaderValue> {
+        let mut value = header::HeaderValue::from_str(key)
+            .map_err(|_| Error::Configuration("Invalid API key format".to_string()))?;
+        value.set_sensitive(true);
+        Ok(value)
     }
 
     pub fn sandbox(&self) -> SandboxApi {
         SandboxApi::new(self.clone())
     }
 
+    /// Starts a sandbox builder for `Config::default_template`, falling back to
+    /// the `base` template when none is configured.
+    pub fn sandbox_default(&self) -> SandboxBuilder {
+        let template = self.config.default_template.as_deref().unwrap_or("base");
+        self.sandbox().template(template)
+    }
+
     pub fn template(&self) -> TemplateApi {
         TemplateApi::new(self.clone())
     }
 
+    /// Returns a handle whose REST requests use `timeout` instead of
+    /// `Config::timeout_seconds`. The connection pool, API key and hooks are
+    /// shared with `self`, e.g. `client.with_timeout(Duration::from_secs(5)).sandbox().list()`.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Self {
+            request_timeout: Some(timeout),
+            ..self.clone()
+        }
+    }
+
     pub(crate) fn http(&self) -> &HttpClient {
         &self.http
     }
 
+    /// Registers a callback invoked with the method, URL and body of every
+    /// REST request before it is sent.
+    pub fn on_request<F>(&mut self, callback: F)
+    where
+        F: Fn(&RequestCapture<'_>) + Send + Sync + 'static,
+    {
+        self.on_request = Some(Arc::new(callback));
+    }
+
+    /// Registers a callback invoked with the method, URL, status and body of
+    /// every REST response. While set, response bodies are buffered in full
+    /// before being handed back to the SDK, which disables incremental
+    /// streaming of large responses.
+    pub fn on_response<F>(&mut self, callback: F)
+    where
+        F: Fn(&ResponseCapture<'_>) + Send + Sync + 'static,
+    {
+        self.on_response = Some(Arc::new(callback));
+    }
+
+    pub(crate) fn get(&self, url: &str) -> ApiRequest<'_> {
+        ApiRequest::new(self, self.http.get(url))
+    }
+
+    pub(crate) fn post(&self, url: &str) -> ApiRequest<'_> {
+        ApiRequest::new(self, self.http.post(url))
+    }
+
+    pub(crate) fn delete(&self, url: &str) -> ApiRequest<'_> {
+        ApiRequest::new(self, self.http.delete(url))
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        let key = self
+            .api_key
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        request.header("X-API-Key", key)
+    }
+
+    pub(crate) fn metadata_index(&self) -> &MetadataIndex {
+        &self.metadata_index
+    }
+
     pub(crate) fn config(&self) -> &Config {
         &self.config
     }
@@ -65,3 +234,298 @@ impl Client {
         format!("{}{}", self.config.base_url, path)
     }
 }
+
+// A REST request bound to the `Client` that created it, so sending can apply
+// the current API key, debug hooks and retry policy in one place.
+pub(crate) struct ApiRequest<'a> {
+    client: &'a Client,
+    inner: RequestBuilder,
+}
+
+impl<'a> ApiRequest<'a> {
+    fn new(client: &'a Client, inner: RequestBuilder) -> Self {
+        let inner = match client.request_timeout {
+            Some(timeout) => inner.timeout(timeout),
+            None => inner,
+        };
+        Self { client, inner }
+    }
+
+    pub(crate) fn timeout(mut self, timeout: Duration) -> Self {
+        self.inner = self.inner.timeout(timeout);
+        self
+    }
+
+    pub(crate) fn json<T: Serialize + ?Sized>(mut self, body: &T) -> Self {
+        self.inner = self.inner.json(body);
+        self
+    }
+
+    pub(crate) fn query<T: Serialize + ?Sized>(mut self, query: &T) -> Self {
+        self.inner = self.inner.query(query);
+        self
+    }
+
+    pub(crate) fn header(mut self, name: &str, value: &str) -> Self {
+        self.inner = self.inner.header(name, value);
+        self
+    }
+
+    fn try_clone(&self) -> Option<Self> {
+        self.inner
+            .try_clone()
+            .map(|inner| Self::new(self.client, inner))
+    }
+
+    /// Sends the request once. While a `429` received by any clone of the
+    /// client is still within its `Retry-After` window (at most
+    /// `RetryPolicy::max_delay`), this waits for the window to pass first.
+    pub(crate) async fn send(self) -> Result<Response> {
+        let client = self.client;
+        let response = self.send_after_backoff().await?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            let window =
+                backoff_window(response.headers(), &client.config.effective_retry_policy());
+            client.rate_limit.back_off(window);
+        }
+        Ok(response)
+    }
+
+    // Waits out the shared backoff window, then sends through the hooks
+    async fn send_after_backoff(self) -> Result<Response> {
+        let client = self.client;
+        let (http, request) = client.authorize(self.inner).build_split();
+        let request = request?;
+        client.rate_limit.wait().await;
+        let _permit = client.config.acquire_request_permit().await;
+
+        if client.on_request.is_none() && client.on_response.is_none() {
+            return Ok(http.execute(request).await?);
+        }
+
+        let method = request.method().to_string();
+        let url = request.url().to_string();
+
+        if let Some(hook) = &client.on_request {
+            hook(&RequestCapture {
+                method: &method,
+                url: &url,
+                body: request.body().and_then(|b| b.as_bytes()),
+            });
+        }
+
+        let response = http.execute(request).await?;
+        let Some(hook) = &client.on_response else {
+            return Ok(response);
+        };
+
+        // Buffer the body for the hook, then rebuild an equivalent response
+        let status = response.status();
+        let version = response.version();
+        let headers = response.headers().clone();
+        let body = response.bytes().await?;
+
+        hook(&ResponseCapture {
+            method: &method,
+            url: &url,
+            status: status.as_u16(),
+            body: &body,
+        });
+
+        let mut rebuilt = http::Response::new(body);
+        *rebuilt.status_mut() = status;
+        *rebuilt.version_mut() = version;
+        *rebuilt.headers_mut() = headers;
+        Ok(Response::from(rebuilt))
+    }
+
+    /// Sends the request, retrying transport failures, `429`s and `5xx`
+    /// responses according to the configured `RetryPolicy`. The final
+    /// response is returned as-is so callers can map its status themselves.
+    pub(crate) async fn send_with_retry(self) -> Result<Response> {
+        let policy = self.client.config.effective_retry_policy();
+        let mut attempt = 0;
+
+        loop {
+            let Some(current) = self.try_clone() else {
+                return self.send().await;
+            };
+
+            let error = match current.send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    let error = if status == StatusCode::TOO_MANY_REQUESTS {
+                        Error::RateLimit
+                    } else if status.is_server_error() {
+                        Error::Api {
+                            status: status.as_u16(),
+                            message: String::new(),
+                        }
+                    } else {
+                        return Ok(response);
+                    };
+                    if !policy.should_retry(attempt, &error) {
+                        return Ok(response);
+                    }
+                    error
+                }
+                Err(error) => {
+                    if !policy.should_retry(attempt, &error) {
+                        return Err(error);
+                    }
+                    error
+                }
+            };
+
+            let delay = policy.delay_for(attempt);
+            debug!(
+                "Request attempt {} failed: {}. Retrying in {:?}",
+                attempt + 1,
+                error,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn current_key(client: &Client) -> String {
+        client.api_key.read().unwrap().to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn set_api_key_is_seen_by_clones_only() {
+        let config = Config::with_api_key("old");
+        let mut client = Client::with_config(config.clone()).unwrap();
+        let clone = client.clone();
+        let separate = Client::with_config(config).unwrap();
+
+        client.set_api_key("new").unwrap();
+        assert_eq!(current_key(&clone), "new");
+        assert_eq!(current_key(&separate), "old");
+        assert_eq!(client.config().api_key, "old");
+    }
+
+    #[tokio::test]
+    async fn rotated_key_is_sent() {
+        let (url, request) = crate::test_support::recording_endpoint("").await;
+        let mut client = Client::with_api_key("old");
+        client.set_api_key("new").unwrap();
+        client.get(&url).send().await.unwrap();
+
+        let request = String::from_utf8(request.await.unwrap()).unwrap();
+        assert!(request.to_ascii_lowercase().contains("x-api-key: new\r\n"));
+    }
+
+    #[test]
+    fn set_api_key_rejects_invalid_header() {
+        let mut client = Client::with_api_key("old");
+        assert!(client.set_api_key("bad\nkey").is_err());
+        assert_eq!(current_key(&client), "old");
+    }
+
+    fn headers(retry_after: &str) -> header::HeaderMap {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, retry_after.parse().unwrap());
+        headers
+    }
+
+    // Serves `first` to the first request and `200 OK` to every later one
+    async fn serve(first: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut served = 0;
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let status = if served == 0 { first } else { "200 OK\r\n" };
+                served += 1;
+                let response = format!(
+                    "HTTP/1.1 {}Content-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    fn client(url: &str, max_delay: Duration) -> Client {
+        let mut config = Config::with_api_key("test");
+        config.base_url = url.to_string();
+        config.retry_policy = RetryPolicy::none().max_delay(max_delay);
+        Client::with_config(config).unwrap()
+    }
+
+    #[test]
+    fn backoff_window_is_capped_at_max_delay() {
+        let policy = RetryPolicy::default().max_delay(Duration::from_secs(5));
+        assert_eq!(
+            backoff_window(&headers("2"), &policy),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            backoff_window(&headers("86400"), &policy),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            backoff_window(&header::HeaderMap::new(), &policy),
+            policy.base_delay
+        );
+    }
+
+    #[test]
+    fn back_off_never_shortens_the_window() {
+        let state = RateLimitState::default();
+        state.back_off(Duration::from_secs(60));
+        state.back_off(Duration::from_millis(1));
+        assert!(state.remaining().unwrap() > Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_on_one_clone_delays_the_others() {
+        let url = serve("429 Too Many Requests\r\nRetry-After: 1\r\n").await;
+        let client = client(&url, Duration::from_secs(10));
+        let limited = client.get(&client.build_url("/")).send().await.unwrap();
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let started = Instant::now();
+        let clones: Vec<Client> = (0..8).map(|_| client.clone()).collect();
+        let sends = clones
+            .iter()
+            .map(|clone| clone.get(&clone.build_url("/")).send());
+        for response in futures::future::join_all(sends).await {
+            assert_eq!(response.unwrap().status(), StatusCode::OK);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn hostile_retry_after_only_pauses_for_max_delay() {
+        let url = serve("429 Too Many Requests\r\nRetry-After: 86400\r\n").await;
+        let client = client(&url, Duration::from_millis(200));
+        client.get(&client.build_url("/")).send().await.unwrap();
+
+        let clone = client.clone();
+        let response = tokio::time::timeout(
+            Duration::from_secs(5),
+            clone.get(&clone.build_url("/")).send(),
+        )
+        .await
+        .expect("backoff was not capped");
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+    }
+}
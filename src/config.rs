@@ -1,15 +1,93 @@
 use crate::error::{Error, Result};
+use crate::models::{InterpreterTransport, ShellKind};
+use crate::retry::RetryPolicy;
+use std::collections::HashMap;
 use std::env;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub api_key: String,
     pub base_url: String,
     pub timeout_seconds: u64,
+    #[deprecated(note = "use `retry_policy.max_retries`")]
+    /// Assigning this directly overrides `retry_policy.max_retries`.
     pub max_retries: u32,
+    pub retry_policy: RetryPolicy,
+    pub default_shell: ShellKind,
+    pub interpreter_transport: InterpreterTransport,
+    pub region: Option<Region>,
+    /// Sandbox domain per region, see `Config::region_domain`.
+    pub region_domains: HashMap<Region, String>,
+    /// Extra PEM-encoded root certificates trusted for API and envd
+    /// connections, e.g. a private CA of a self-hosted deployment.
+    pub root_certificates: Vec<Vec<u8>>,
+    pub danger_accept_invalid_certs: bool,
+    /// Template used by `Client::sandbox_default`. Read from
+    /// `E2B_DEFAULT_TEMPLATE` when the config is created.
+    pub default_template: Option<String>,
+    /// Upper bound on REST and envd requests in flight at once across every
+    /// client, sandbox and RPC connection built from this config. `None`
+    /// (the default) means unlimited. The limiter is created from this value
+    /// on first use, so set it before the config builds a client.
+    pub max_concurrent_requests: Option<usize>,
+    /// Domain sandboxes are reached under, taking precedence over the domain
+    /// reported by the API, `E2B_SANDBOX_DOMAIN`/`E2B_DOMAIN` and the region.
+    pub sandbox_domain_override: Option<String>,
+    /// How long `SandboxApi::get_or_create_by_metadata` trusts its cached
+    /// metadata-to-sandbox mapping. `None` (the default) disables the cache.
+    pub metadata_cache_ttl: Option<std::time::Duration>,
+    /// Fail sandbox creation with `Error::Configuration` when no sandbox
+    /// domain is configured or reported by the API, instead of assuming
+    /// `e2b.dev`.
+    pub require_explicit_domain: bool,
+    /// Fails an envd process output stream with `Error::Timeout` once no
+    /// frame has arrived for this long. `None` (the default) waits forever,
+    /// which long-running quiet commands need.
+    pub rpc_idle_timeout: Option<std::time::Duration>,
+    // `max_retries` as last set by a constructor or builder, so a direct
+    // assignment to the deprecated field can be told apart
+    synced_max_retries: u32,
+    // Shared by clones so they draw from the same pool of permits
+    request_limiter: Arc<OnceLock<Option<Arc<Semaphore>>>>,
+}
+
+/// Name of an E2B deployment region, passed through as given. The SDK keeps
+/// no list of regions, so new ones work without an update.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Region(String);
+
+impl Region {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self(name.into().trim().to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Region {
+    fn from(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
+impl From<String> for Region {
+    fn from(name: String) -> Self {
+        Self::new(name)
+    }
+}
+
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 impl Config {
+    #[allow(deprecated)]
     pub fn new() -> Result<Self> {
         let api_key = env::var("E2B_API_KEY").map_err(|_| Error::ApiKeyNotFound)?;
 
@@ -17,16 +95,47 @@ impl Config {
             api_key,
             base_url: "https://api.e2b.app".to_string(),
             timeout_seconds: 300,
-            max_retries: 3,
+            max_retries: RetryPolicy::default().max_retries,
+            retry_policy: RetryPolicy::default(),
+            default_shell: ShellKind::default(),
+            interpreter_transport: InterpreterTransport::default(),
+            region: None,
+            region_domains: HashMap::new(),
+            root_certificates: Vec::new(),
+            danger_accept_invalid_certs: false,
+            default_template: Self::default_template_from_env(),
+            max_concurrent_requests: None,
+            sandbox_domain_override: None,
+            metadata_cache_ttl: None,
+            require_explicit_domain: false,
+            rpc_idle_timeout: None,
+            synced_max_retries: RetryPolicy::default().max_retries,
+            request_limiter: Arc::default(),
         })
     }
 
+    #[allow(deprecated)]
     pub fn with_api_key(api_key: impl Into<String>) -> Self {
         Self {
             api_key: api_key.into(),
             base_url: "https://api.e2b.app".to_string(),
             timeout_seconds: 300,
-            max_retries: 3,
+            max_retries: RetryPolicy::default().max_retries,
+            retry_policy: RetryPolicy::default(),
+            default_shell: ShellKind::default(),
+            interpreter_transport: InterpreterTransport::default(),
+            region: None,
+            region_domains: HashMap::new(),
+            root_certificates: Vec::new(),
+            danger_accept_invalid_certs: false,
+            default_template: Self::default_template_from_env(),
+            max_concurrent_requests: None,
+            sandbox_domain_override: None,
+            metadata_cache_ttl: None,
+            require_explicit_domain: false,
+            rpc_idle_timeout: None,
+            synced_max_retries: RetryPolicy::default().max_retries,
+            request_limiter: Arc::default(),
         }
     }
 
@@ -40,15 +149,223 @@ impl Config {
         self
     }
 
+    pub fn rpc_idle_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.rpc_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Shorthand for setting `retry_policy.max_retries`.
     pub fn max_retries(mut self, retries: u32) -> Self {
-        self.max_retries = retries;
+        self.retry_policy.max_retries = retries;
+        self.sync_max_retries();
         self
     }
 
-    pub fn sandbox_domain(&self) -> String {
-        let domain = env::var("E2B_SANDBOX_DOMAIN")
-            .or_else(|_| env::var("E2B_DOMAIN"))
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self.sync_max_retries();
+        self
+    }
+
+    /// `retry_policy`, with `max_retries` taken from the deprecated field
+    /// when that was assigned directly.
+    pub(crate) fn effective_retry_policy(&self) -> RetryPolicy {
+        #[allow(deprecated)]
+        let max_retries = self.max_retries;
+        if max_retries == self.synced_max_retries {
+            return self.retry_policy.clone();
+        }
+        RetryPolicy {
+            max_retries,
+            ..self.retry_policy.clone()
+        }
+    }
+
+    #[allow(deprecated)]
+    fn sync_max_retries(&mut self) {
+        self.max_retries = self.retry_policy.max_retries;
+        self.synced_max_retries = self.retry_policy.max_retries;
+    }
+
+    /// Shell used for string commands that don't set `CommandOptions::shell`.
+    /// Use `ShellKind::NonLogin` to avoid sourcing broken profile scripts.
+    pub fn default_shell(mut self, shell: ShellKind) -> Self {
+        self.default_shell = shell;
+        self
+    }
+
+    /// Transport used by code interpreters of sandboxes created with this config.
+    pub fn interpreter_transport(mut self, transport: InterpreterTransport) -> Self {
+        self.interpreter_transport = transport;
+        self
+    }
+
+    /// Region new sandboxes are placed in, sent with the create request.
+    /// The API reports the domain of each sandbox it creates; to reach
+    /// sandboxes whose domain isn't reported, map the region with
+    /// `region_domain`. `E2B_SANDBOX_DOMAIN` / `E2B_DOMAIN` always win.
+    pub fn region(mut self, region: &str) -> Self {
+        self.region = Some(Region::from(region));
+        self
+    }
+
+    /// Sandbox domain to use for sandboxes in `region` when neither an
+    /// override, the environment nor the API names one.
+    pub fn region_domain(mut self, region: &str, domain: impl Into<String>) -> Self {
+        self.region_domains
+            .insert(Region::from(region), domain.into());
+        self
+    }
+
+    /// Trusts `pem` in addition to the system roots. The certificate is
+    /// parsed when the `Client` is built.
+    pub fn add_root_certificate(mut self, pem: &[u8]) -> Self {
+        self.root_certificates.push(pem.to_vec());
+        self
+    }
+
+    /// DANGER: disables TLS certificate validation entirely, exposing every
+    /// connection to interception. Only for local testing.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.danger_accept_invalid_certs = accept;
+        self
+    }
+
+    pub(crate) fn apply_tls(
+        &self,
+        mut builder: reqwest::ClientBuilder,
+    ) -> Result<reqwest::ClientBuilder> {
+        for pem in &self.root_certificates {
+            let certificate = reqwest::Certificate::from_pem(pem)
+                .map_err(|e| Error::Configuration(format!("Invalid root certificate: {}", e)))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        if self.danger_accept_invalid_certs {
+            tracing::warn!("TLS certificate validation is disabled");
+        }
+        Ok(builder.danger_accept_invalid_certs(self.danger_accept_invalid_certs))
+    }
+
+    /// The WebSocket counterpart of `apply_tls`: a connector trusting the same
+    /// extra roots, or `None` to use the system defaults.
+    pub(crate) fn ws_connector(
+        &self,
+    ) -> std::result::Result<Option<tokio_tungstenite::Connector>, native_tls::Error> {
+        if self.root_certificates.is_empty() && !self.danger_accept_invalid_certs {
+            return Ok(None);
+        }
+        let mut builder = native_tls::TlsConnector::builder();
+        for pem in &self.root_certificates {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(pem)?);
+        }
+        builder.danger_accept_invalid_certs(self.danger_accept_invalid_certs);
+        Ok(Some(tokio_tungstenite::Connector::NativeTls(
+            builder.build()?,
+        )))
+    }
+
+    pub fn default_template(mut self, template_id: impl Into<String>) -> Self {
+        self.default_template = Some(template_id.into());
+        self
+    }
+
+    /// Limits the number of requests in flight at once to `n`. Calls over
+    /// the limit wait for a slot instead of failing. A permit is held only
+    /// while a request is being sent and its response headers received, so
+    /// long-lived process and watch streams do not occupy a slot. Retries
+    /// acquire a fresh permit per attempt and release it during backoff.
+    pub fn max_concurrent_requests(mut self, n: usize) -> Self {
+        let n = n.max(1);
+        self.max_concurrent_requests = Some(n);
+        self.request_limiter = Arc::default();
+        self
+    }
+
+    pub(crate) fn request_limiter(&self) -> Option<Arc<Semaphore>> {
+        self.request_limiter
+            .get_or_init(|| {
+                self.max_concurrent_requests
+                    .map(|n| Arc::new(Semaphore::new(n.max(1))))
+            })
+            .clone()
+    }
+
+    pub(crate) async fn acquire_request_permit(&self) -> Option<OwnedSemaphorePermit> {
+        acquire_permit(self.request_limiter().as_ref()).await
+    }
+
+    fn default_template_from_env() -> Option<String> {
+        env::var("E2B_DEFAULT_TEMPLATE")
             .ok()
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+    }
+
+    /// Reaches sandboxes under `domain` (e.g. `localhost` or a self-hosted
+    /// cluster), independently of `base_url`.
+    pub fn sandbox_domain_override(mut self, domain: impl Into<String>) -> Self {
+        self.sandbox_domain_override = Some(domain.into());
+        self
+    }
+
+    pub fn metadata_cache_ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.metadata_cache_ttl = Some(ttl);
+        self
+    }
+
+    pub fn require_explicit_domain(mut self, require: bool) -> Self {
+        self.require_explicit_domain = require;
+        self
+    }
+
+    /// Domain sandboxes are reached under, resolved in this order:
+    /// `sandbox_domain_override`, then `E2B_SANDBOX_DOMAIN` or `E2B_DOMAIN`,
+    /// then the `region_domain` of the region when one is set, then `e2b.dev`.
+    pub fn sandbox_domain(&self) -> String {
+        self.sandbox_domain_in(self.region.as_ref())
+    }
+
+    pub(crate) fn sandbox_domain_in(&self, region: Option<&Region>) -> String {
+        self.sandbox_domain_with_env(region, |name| env::var(name).ok())
+    }
+
+    // `sandbox_domain_in` reading the environment through `var`
+    fn sandbox_domain_with_env(
+        &self,
+        region: Option<&Region>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> String {
+        self.explicit_sandbox_domain(region, var)
+            .unwrap_or_else(|| "e2b.dev".to_string())
+    }
+
+    /// Like `sandbox_domain_in`, but the `e2b.dev` fallback is an error under
+    /// `require_explicit_domain` and a warning otherwise.
+    pub(crate) fn resolve_sandbox_domain(&self, region: Option<&Region>) -> Result<String> {
+        if let Some(domain) = self.explicit_sandbox_domain(region, |name| env::var(name).ok()) {
+            return Ok(domain);
+        }
+        if self.require_explicit_domain {
+            return Err(Error::Configuration(
+                "No sandbox domain configured: set E2B_SANDBOX_DOMAIN, a region domain or \
+                 Config::sandbox_domain_override"
+                    .to_string(),
+            ));
+        }
+        tracing::warn!("No sandbox domain configured or reported by the API, assuming e2b.dev");
+        Ok("e2b.dev".to_string())
+    }
+
+    fn explicit_sandbox_domain(
+        &self,
+        region: Option<&Region>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Option<String> {
+        if let Some(domain) = &self.sandbox_domain_override {
+            return Some(domain.clone());
+        }
+        let domain = var("E2B_SANDBOX_DOMAIN")
+            .or_else(|| var("E2B_DOMAIN"))
             .and_then(|d| {
                 let trimmed = d.trim().trim_start_matches("api.").to_string();
                 if trimmed.is_empty() {
@@ -58,6 +375,116 @@ impl Config {
                 }
             });
 
-        domain.unwrap_or_else(|| "e2b.dev".to_string())
+        domain.or_else(|| self.region_domains.get(region?).cloned())
+    }
+}
+
+// The limiter is never closed, so acquiring only fails if it is absent.
+pub(crate) async fn acquire_permit(
+    limiter: Option<&Arc<Semaphore>>,
+) -> Option<OwnedSemaphorePermit> {
+    match limiter {
+        Some(limiter) => limiter.clone().acquire_owned().await.ok(),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limiter_follows_directly_set_field() {
+        let mut config = Config::with_api_key("key");
+        config.max_concurrent_requests = Some(2);
+        let limiter = config.request_limiter().expect("limiter from field");
+        assert_eq!(limiter.available_permits(), 2);
+    }
+
+    #[test]
+    fn clones_share_one_limiter() {
+        let config = Config::with_api_key("key").max_concurrent_requests(3);
+        let clone = config.clone();
+        let a = config.request_limiter().unwrap();
+        let b = clone.request_limiter().unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+    }
+
+    #[test]
+    fn ws_connector_only_for_custom_tls() {
+        let config = Config::with_api_key("key");
+        assert!(config.ws_connector().unwrap().is_none());
+        assert!(config
+            .add_root_certificate(b"not a certificate")
+            .ws_connector()
+            .is_err());
+        let insecure = Config::with_api_key("key").danger_accept_invalid_certs(true);
+        assert!(insecure.ws_connector().unwrap().is_some());
+    }
+
+    #[test]
+    fn region_names_pass_through_unchanged() {
+        assert_eq!(Region::from(" ap-south-9 ").as_str(), "ap-south-9");
+    }
+
+    #[test]
+    fn region_selects_only_a_configured_domain() {
+        let config = Config::with_api_key("key")
+            .region("eu")
+            .region_domain("eu", "eu.sandbox.test");
+        let eu = Region::from("eu");
+        let other = Region::from("us");
+        let unset = |_: &str| None;
+        assert_eq!(
+            config.sandbox_domain_with_env(Some(&eu), unset),
+            "eu.sandbox.test"
+        );
+        assert_eq!(
+            config.sandbox_domain_with_env(Some(&other), unset),
+            "e2b.dev"
+        );
+        assert_eq!(config.sandbox_domain_with_env(None, unset), "e2b.dev");
+    }
+
+    #[test]
+    fn environment_domain_wins_over_the_region() {
+        let config = Config::with_api_key("key").region_domain("eu", "eu.sandbox.test");
+        let eu = Region::from("eu");
+        let e2b_domain = |name: &str| (name == "E2B_DOMAIN").then(|| "api.env.test".to_string());
+        assert_eq!(
+            config.sandbox_domain_with_env(Some(&eu), e2b_domain),
+            "env.test"
+        );
+        let both = |name: &str| Some(format!("{}.test", name.to_lowercase()));
+        assert_eq!(
+            config.sandbox_domain_with_env(Some(&eu), both),
+            "e2b_sandbox_domain.test"
+        );
+        let blank = |_: &str| Some("  ".to_string());
+        assert_eq!(
+            config.sandbox_domain_with_env(Some(&eu), blank),
+            "eu.sandbox.test"
+        );
+    }
+
+    #[test]
+    fn no_limit_by_default() {
+        assert!(Config::with_api_key("key").request_limiter().is_none());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_max_retries_feeds_the_policy() {
+        let built = Config::with_api_key("key").max_retries(5);
+        assert_eq!(built.max_retries, 5);
+        assert_eq!(built.effective_retry_policy().max_retries, 5);
+
+        let mut assigned = Config::with_api_key("key");
+        assigned.max_retries = 0;
+        assert_eq!(assigned.effective_retry_policy().max_retries, 0);
+
+        let mut policy_only = Config::with_api_key("key");
+        policy_only.retry_policy.max_retries = 7;
+        assert_eq!(policy_only.effective_retry_policy().max_retries, 7);
     }
 }
@@ -13,12 +13,45 @@ pub enum Error {
     #[error("URL parsing failed: {0}")]
     Url(#[from] url::ParseError),
 
+    /// Local I/O failure, e.g. reading a file to upload.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
     #[error("API key not found. Set E2B_API_KEY environment variable or provide it explicitly")]
     ApiKeyNotFound,
 
     #[error("API error ({status}): {message}")]
     Api { status: u16, message: String },
 
+    /// Failure reported by envd or the Jupyter server inside the sandbox,
+    /// rather than by the control-plane API.
+    #[error("envd error ({status}): {message}")]
+    Envd { status: u16, message: String },
+
+    /// Like `Api`, but keeps the underlying error available via `source()`.
+    #[error("API error ({status}): {message}")]
+    Wrapped {
+        status: u16,
+        message: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// A response that could not be decoded, or that lacked a part the SDK
+    /// needs. No HTTP status is involved, and sending the same request again
+    /// gets the same response, so it is not retryable.
+    #[error("Invalid response: {message}")]
+    InvalidResponse {
+        message: String,
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+
+    /// A helper command envd ran inside the sandbox on the SDK's behalf
+    /// (e.g. `chmod` for `WriteEntry::mode`) exited unsuccessfully.
+    #[error("Sandbox operation failed: {0}")]
+    Operation(String),
+
     #[error("Authentication failed: {0}")]
     Authentication(String),
 
@@ -33,4 +66,209 @@ pub enum Error {
 
     #[error("Invalid configuration: {0}")]
     Configuration(String),
+
+    /// Some moves of `FilesystemApi::move_batch` failed. The moves not
+    /// listed were applied.
+    #[error("{} of {total} moves failed: {}", failures.len(), join_failures(failures))]
+    MoveBatch {
+        total: usize,
+        failures: Vec<MoveFailure>,
+    },
+
+    #[error("Code execution failed: {name}: {value}")]
+    ExecutionError {
+        name: String,
+        value: String,
+        traceback: String,
+    },
+}
+
+/// One failed `(from, to)` pair of a batch move.
+#[derive(Error, Debug)]
+#[error("{from} -> {to}: {error}")]
+pub struct MoveFailure {
+    pub from: String,
+    pub to: String,
+    #[source]
+    pub error: Box<Error>,
+}
+
+fn join_failures(failures: &[MoveFailure]) -> String {
+    failures
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+impl Error {
+    /// Whether retrying the same request may succeed: rate limits, timeouts,
+    /// transport failures and `5xx` responses. Client errors such as
+    /// authentication, missing resources, bad configuration and other `4xx`
+    /// responses are not retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::RateLimit | Error::Timeout => true,
+            Error::Http(e) => match e.status() {
+                Some(status) => status.is_server_error() || status.as_u16() == 429,
+                None => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            },
+            Error::Api { status, .. }
+            | Error::Envd { status, .. }
+            | Error::Wrapped { status, .. } => Self::is_retryable_status(*status),
+            _ => false,
+        }
+    }
+
+    /// HTTP status associated with the error, when there is one.
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            Error::Api { status, .. }
+            | Error::Envd { status, .. }
+            | Error::Wrapped { status, .. } => Some(*status),
+            Error::Http(e) => e.status().map(|s| s.as_u16()),
+            Error::RateLimit => Some(429),
+            Error::Authentication(_) => Some(401),
+            Error::NotFound(_) => Some(404),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn wrap(
+        status: u16,
+        message: impl Into<String>,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Error::Wrapped {
+            status,
+            message: message.into(),
+            source: source.into(),
+        }
+    }
+
+    pub(crate) fn invalid_response(message: impl Into<String>) -> Self {
+        Error::InvalidResponse {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub(crate) fn undecodable(
+        message: impl Into<String>,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Error::InvalidResponse {
+            message: message.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    fn is_retryable_status(status: u16) -> bool {
+        matches!(status, 408 | 429) || status >= 500
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn move_batch_lists_each_failure() {
+        let error = Error::MoveBatch {
+            total: 3,
+            failures: vec![
+                MoveFailure {
+                    from: "/a".to_string(),
+                    to: "/b".to_string(),
+                    error: Box::new(Error::NotFound("/a".to_string())),
+                },
+                MoveFailure {
+                    from: "/c".to_string(),
+                    to: "/d".to_string(),
+                    error: Box::new(Error::Timeout),
+                },
+            ],
+        };
+        assert_eq!(
+            error.to_string(),
+            "2 of 3 moves failed: /a -> /b: Resource not found: /a; /c -> /d: Sandbox timeout"
+        );
+    }
+
+    #[test]
+    fn wrapped_display_leaves_the_cause_to_source() {
+        let error = Error::wrap(500, "Failed to decode message", "bad utf-8");
+        assert_eq!(
+            error.to_string(),
+            "API error (500): Failed to decode message"
+        );
+        assert_eq!(error.source().unwrap().to_string(), "bad utf-8");
+    }
+
+    fn api(status: u16) -> Error {
+        Error::Api {
+            status,
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn classifies_retryable_errors() {
+        for error in [
+            Error::RateLimit,
+            Error::Timeout,
+            api(500),
+            api(503),
+            api(429),
+        ] {
+            assert!(error.is_retryable(), "{:?}", error);
+        }
+        for error in [
+            Error::Authentication("bad key".to_string()),
+            Error::NotFound("sandbox".to_string()),
+            Error::Configuration("bad".to_string()),
+            Error::ApiKeyNotFound,
+            api(400),
+            api(404),
+            api(409),
+        ] {
+            assert!(!error.is_retryable(), "{:?}", error);
+        }
+        assert!(Error::wrap(502, "bad gateway", "upstream").is_retryable());
+        // Decoding the same response again fails the same way
+        let undecodable = Error::undecodable("Failed to parse response", "expected value");
+        assert!(!undecodable.is_retryable());
+        assert_eq!(undecodable.status_code(), None);
+        assert!(!Error::invalid_response("missing entries").is_retryable());
+        assert!(!Error::Operation("chmod: Operation not permitted".to_string()).is_retryable());
+    }
+
+    #[tokio::test]
+    async fn connection_failures_are_retryable() {
+        // Nothing listens on a port we bound and released
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let error: Error = reqwest::get(format!("http://127.0.0.1:{}", port))
+            .await
+            .unwrap_err()
+            .into();
+        assert!(error.is_retryable());
+        assert_eq!(error.status_code(), None);
+    }
+
+    #[test]
+    fn status_code_follows_the_variant() {
+        assert_eq!(api(418).status_code(), Some(418));
+        assert_eq!(Error::RateLimit.status_code(), Some(429));
+        assert_eq!(Error::NotFound(String::new()).status_code(), Some(404));
+        assert_eq!(
+            Error::Authentication(String::new()).status_code(),
+            Some(401)
+        );
+        assert_eq!(Error::Timeout.status_code(), None);
+    }
 }
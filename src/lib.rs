@@ -30,8 +30,12 @@ pub mod client;
 pub mod config;
 pub mod error;
 pub mod models;
+pub mod retry;
 pub mod rpc;
 
+#[cfg(test)]
+mod test_support;
+
 pub use client::Client;
 pub use error::{Error, Result};
 
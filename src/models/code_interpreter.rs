@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -19,15 +20,179 @@ pub struct Execution {
     pub results: Vec<Result>,
     pub error: Option<ExecutionError>,
     pub is_main_result: bool,
+    #[serde(default)]
+    pub output: Vec<OutputMessage>,
+    /// Set when stdout/stderr exceeded `CodeInterpreterOptions::max_output_bytes`
+    /// and the remainder was discarded.
+    #[serde(default)]
+    pub truncated: bool,
+    /// The variable named by `CodeInterpreterOptions::capture_variable`,
+    /// serialized to JSON after the run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub captured: Option<serde_json::Value>,
+}
+
+impl Execution {
+    /// Reconstructs stdout and stderr as a single stream ordered by the
+    /// timestamp of each chunk, taken by the client as the chunk arrives.
+    /// Interleaving is best-effort: chunks emitted close together may be
+    /// delivered out of order by the kernel.
+    pub fn combined_output(&self) -> String {
+        let mut messages: Vec<&OutputMessage> = self.output.iter().collect();
+        messages.sort_by_key(|m| m.timestamp);
+        messages.into_iter().map(|m| m.line.as_str()).collect()
+    }
+
+    /// Whether the code raised an error in the kernel.
+    pub fn has_error(&self) -> bool {
+        self.error.is_some()
+    }
+
+    /// `true` when the code ran without raising an error.
+    pub fn is_success(&self) -> bool {
+        !self.has_error()
+    }
+
+    /// Converts a runtime error raised by the code into `Err`, so callers can
+    /// write `sandbox.run_python(code).await?.into_result()?`.
+    pub fn into_result(self) -> crate::Result<Execution> {
+        match self.error {
+            Some(error) => Err(crate::Error::ExecutionError {
+                name: error.name,
+                value: error.value,
+                traceback: error.traceback,
+            }),
+            None => Ok(self),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Result {
     #[serde(rename = "type")]
     pub result_type: String,
+    /// Raw MIME type to value map as sent by the kernel.
     pub data: HashMap<String, String>,
 }
 
+impl Result {
+    /// The data sorted into known MIME types in `ResultData` declaration
+    /// order, with anything unrecognised gathered into a trailing
+    /// `ResultData::Other`.
+    pub fn typed_data(&self) -> Vec<ResultData> {
+        ResultData::sort(&self.data)
+    }
+
+    pub fn text_plain(&self) -> Option<&str> {
+        self.mime("text/plain")
+    }
+
+    pub fn text_html(&self) -> Option<&str> {
+        self.mime("text/html")
+    }
+
+    pub fn text_markdown(&self) -> Option<&str> {
+        self.mime("text/markdown")
+    }
+
+    pub fn text_latex(&self) -> Option<&str> {
+        self.mime("text/latex")
+    }
+
+    /// Base64-encoded PNG image.
+    pub fn image_png(&self) -> Option<&str> {
+        self.mime("image/png")
+    }
+
+    /// Base64-encoded JPEG image.
+    pub fn image_jpeg(&self) -> Option<&str> {
+        self.mime("image/jpeg")
+    }
+
+    pub fn image_svg(&self) -> Option<&str> {
+        self.mime("image/svg+xml")
+    }
+
+    pub fn application_json(&self) -> Option<&str> {
+        self.mime("application/json")
+    }
+
+    /// Base64-encoded PDF document.
+    pub fn application_pdf(&self) -> Option<&str> {
+        self.mime("application/pdf")
+    }
+
+    fn mime(&self, mime: &str) -> Option<&str> {
+        self.data.get(mime).map(String::as_str)
+    }
+}
+
+/// One representation of an interpreter result, keyed by MIME type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResultData {
+    TextPlain(String),
+    TextHtml(String),
+    TextMarkdown(String),
+    TextLatex(String),
+    ImagePng(String),
+    ImageJpeg(String),
+    ImageSvg(String),
+    ApplicationJson(String),
+    ApplicationPdf(String),
+    Javascript(String),
+    Other(HashMap<String, String>),
+}
+
+impl ResultData {
+    // Declaration order of the known variants, which `typed_data` follows
+    const KNOWN_MIMES: [&'static str; 10] = [
+        "text/plain",
+        "text/html",
+        "text/markdown",
+        "text/latex",
+        "image/png",
+        "image/jpeg",
+        "image/svg+xml",
+        "application/json",
+        "application/pdf",
+        "application/javascript",
+    ];
+
+    fn sort(data: &HashMap<String, String>) -> Vec<Self> {
+        let mut typed: Vec<Self> = Self::KNOWN_MIMES
+            .iter()
+            .filter_map(|mime| Self::from_mime(mime, data.get(*mime)?.clone()))
+            .collect();
+        let other: HashMap<String, String> = data
+            .iter()
+            .filter(|(mime, _)| !Self::KNOWN_MIMES.contains(&mime.as_str()))
+            .map(|(mime, value)| (mime.clone(), value.clone()))
+            .collect();
+        if !other.is_empty() {
+            typed.push(Self::Other(other));
+        }
+        typed
+    }
+
+    /// Maps a known MIME type to its variant, or `None` for anything else.
+    pub fn from_mime(mime: &str, value: String) -> Option<Self> {
+        let data = match mime {
+            "text/plain" => Self::TextPlain(value),
+            "text/html" => Self::TextHtml(value),
+            "text/markdown" => Self::TextMarkdown(value),
+            "text/latex" => Self::TextLatex(value),
+            "image/png" => Self::ImagePng(value),
+            "image/jpeg" => Self::ImageJpeg(value),
+            "image/svg+xml" => Self::ImageSvg(value),
+            "application/json" => Self::ApplicationJson(value),
+            "application/pdf" => Self::ApplicationPdf(value),
+            "application/javascript" => Self::Javascript(value),
+            _ => return None,
+        };
+        Some(data)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExecutionError {
     pub name: String,
@@ -35,9 +200,91 @@ pub struct ExecutionError {
     pub traceback: String,
 }
 
+impl ExecutionError {
+    /// Builds an error from a flat message such as `"NameError: name 'x' is
+    /// not defined"`, optionally preceded by a traceback. The last line is
+    /// split into name and value when it starts with an identifier followed
+    /// by `": "`; otherwise the whole message becomes `value`.
+    pub fn from_message(message: &str) -> Self {
+        let message = message.trim_end();
+        let (traceback, last) = match message.rsplit_once('\n') {
+            Some(_) => (message.to_string(), message.lines().last().unwrap_or("")),
+            None => (String::new(), message),
+        };
+
+        let split = last.split_once(": ").filter(|(name, _)| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+        });
+        match split {
+            Some((name, value)) => Self {
+                name: name.to_string(),
+                value: value.to_string(),
+                traceback,
+            },
+            None => Self {
+                name: String::new(),
+                value: message.to_string(),
+                traceback: String::new(),
+            },
+        }
+    }
+}
+
+// The REST `/code` endpoint reports errors either as a plain string or as an
+// object with name/value/traceback
+pub(crate) fn deserialize_execution_error<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<ExecutionError>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawError {
+        Message(String),
+        Structured {
+            #[serde(default)]
+            name: String,
+            #[serde(default)]
+            value: String,
+            #[serde(default)]
+            traceback: String,
+        },
+    }
+
+    Ok(match Option::<RawError>::deserialize(deserializer)? {
+        None => None,
+        Some(RawError::Message(message)) if message.is_empty() => None,
+        Some(RawError::Message(message)) => Some(ExecutionError::from_message(&message)),
+        Some(RawError::Structured {
+            name,
+            value,
+            traceback,
+        }) => Some(ExecutionError {
+            name,
+            value,
+            traceback,
+        }),
+    })
+}
+
+/// Incremental output of a streamed run, see
+/// `CodeInterpreterApi::run_code_streaming`.
+#[derive(Debug, Clone)]
+pub enum ExecutionMessage {
+    Stdout(String),
+    Stderr(String),
+    Result(Result),
+    Error(ExecutionError),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OutputMessage {
     pub line: String,
+    /// Client time the chunk arrived, in microseconds since the Unix epoch.
     pub timestamp: i64,
     pub error: bool,
 }
@@ -47,20 +294,78 @@ pub struct Context {
     pub id: String,
     pub language: String,
     pub cwd: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    /// Whether a cell is currently executing in this context.
+    #[serde(default)]
+    pub busy: bool,
 }
 
 impl Context {
     pub fn new(id: String, language: String, cwd: String) -> Self {
-        Self { id, language, cwd }
+        Self {
+            id,
+            language,
+            cwd,
+            created_at: None,
+            busy: false,
+        }
     }
 }
 
+/// How `run_code` calls reach the interpreter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InterpreterTransport {
+    /// One HTTP POST to `/execute` per call.
+    #[default]
+    Http,
+    /// A WebSocket kept open across calls. Falls back to HTTP when the
+    /// interpreter doesn't expose the WebSocket endpoint.
+    WebSocket,
+}
+
 #[derive(Debug, Clone)]
 pub struct CodeInterpreterOptions {
     pub language: Option<String>,
     pub context: Option<Context>,
     pub env_vars: Option<HashMap<String, String>>,
+    /// Total time limit for a run. When `None` and `idle_timeout` is set, the
+    /// run has no total limit; when both are `None` (the default), five
+    /// minutes apply.
     pub timeout: Option<std::time::Duration>,
+    /// Fails the run with `Error::Timeout` once no output has arrived for this
+    /// long, so long cells that keep printing progress aren't cut off.
+    pub idle_timeout: Option<std::time::Duration>,
+    pub max_output_bytes: Option<usize>,
+    /// Working directory to run the code in. The kernel switches to it in a
+    /// separate cell before the run and back to its previous directory after,
+    /// so later runs in the same context are unaffected.
+    pub cwd: Option<String>,
+    /// Python variable to read back as JSON into `Execution::captured` once
+    /// the code has run. pandas DataFrames and Series are converted with
+    /// `to_json(orient="split")`; other values go through `json.dumps`,
+    /// falling back to `str` for unsupported types. Runs with this set are
+    /// not cached.
+    pub capture_variable: Option<String>,
+    /// Before the first run in the context, run a separate cell that selects
+    /// a headless matplotlib backend: `%matplotlib inline` in IPython kernels,
+    /// which renders with Agg and sends figures as display data at the end of
+    /// each cell, or plain `Agg` elsewhere, where figures are only kept by
+    /// `savefig`. Python only; ignored for other languages and when
+    /// matplotlib isn't installed.
+    pub setup_plotting: bool,
+}
+
+impl CodeInterpreterOptions {
+    // With only an idle timeout set, a run may take as long as it keeps
+    // producing output
+    pub(crate) fn total_timeout(&self) -> Option<std::time::Duration> {
+        match (self.timeout, self.idle_timeout) {
+            (Some(total), _) => Some(total),
+            (None, Some(_)) => None,
+            (None, None) => Some(std::time::Duration::from_secs(300)),
+        }
+    }
 }
 
 impl Default for CodeInterpreterOptions {
@@ -69,7 +374,116 @@ impl Default for CodeInterpreterOptions {
             language: Some("python".to_string()),
             context: None,
             env_vars: None,
-            timeout: Some(std::time::Duration::from_secs(300)),
+            timeout: None,
+            idle_timeout: None,
+            max_output_bytes: Some(10 * 1024 * 1024),
+            cwd: None,
+            capture_variable: None,
+            setup_plotting: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn result() -> Result {
+        serde_json::from_value(json!({
+            "type": "execute_result",
+            "data": {
+                "image/png": "iVBORw0KGgo=",
+                "application/x-custom": "custom",
+                "text/plain": "<Figure>",
+                "text/html": "<b>hi</b>"
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn typed_data_follows_declaration_order() {
+        let other = HashMap::from([("application/x-custom".to_string(), "custom".to_string())]);
+        assert_eq!(
+            result().typed_data(),
+            [
+                ResultData::TextPlain("<Figure>".to_string()),
+                ResultData::TextHtml("<b>hi</b>".to_string()),
+                ResultData::ImagePng("iVBORw0KGgo=".to_string()),
+                ResultData::Other(other),
+            ]
+        );
+    }
+
+    #[test]
+    fn typed_data_follows_edits_to_the_raw_map() {
+        let mut result = result();
+        result.data.clear();
+        result
+            .data
+            .insert("text/latex".to_string(), "$x$".to_string());
+        assert_eq!(
+            result.typed_data(),
+            [ResultData::TextLatex("$x$".to_string())]
+        );
+    }
+
+    #[test]
+    fn result_round_trips_through_json() {
+        let original = result();
+        let json = serde_json::to_value(&original).unwrap();
+        assert_eq!(json["type"], "execute_result");
+
+        let decoded: Result = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.result_type, original.result_type);
+        assert_eq!(decoded.data, original.data);
+        assert_eq!(decoded.typed_data(), original.typed_data());
+        assert_eq!(decoded.text_plain(), Some("<Figure>"));
+    }
+
+    #[test]
+    fn execution_round_trips_through_json() {
+        let execution = Execution {
+            stdout: "hi\n".to_string(),
+            stderr: String::new(),
+            results: vec![result()],
+            error: None,
+            is_main_result: true,
+            output: Vec::new(),
+            truncated: false,
+            captured: None,
+        };
+        let json = serde_json::to_value(&execution).unwrap();
+        let decoded: Execution = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), json);
+        assert_eq!(
+            decoded.results[0].typed_data(),
+            execution.results[0].typed_data()
+        );
+    }
+
+    #[test]
+    fn idle_timeout_alone_lifts_the_total_limit() {
+        let options = CodeInterpreterOptions {
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        assert_eq!(options.total_timeout(), None);
+    }
+
+    #[test]
+    fn default_options_keep_five_minute_limit() {
+        assert_eq!(
+            CodeInterpreterOptions::default().total_timeout(),
+            Some(Duration::from_secs(300))
+        );
+        let options = CodeInterpreterOptions {
+            timeout: Some(Duration::from_secs(10)),
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        assert_eq!(options.total_timeout(), Some(Duration::from_secs(10)));
+    }
+}
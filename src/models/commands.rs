@@ -1,4 +1,5 @@
 use chrono::{DateTime, Utc};
+use futures::stream::{self, Stream};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tokio::sync::{mpsc, oneshot};
@@ -11,6 +12,11 @@ pub struct ProcessInfo {
     pub args: Vec<String>,
     pub envs: HashMap<String, String>,
     pub cwd: Option<String>,
+    #[serde(default)]
+    pub started_at: Option<DateTime<Utc>>,
+    /// Process state as reported by envd, e.g. `"running"`.
+    #[serde(default)]
+    pub status: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,6 +25,79 @@ pub struct CommandResult {
     pub stderr: String,
     pub exit_code: i32,
     pub execution_time: Option<std::time::Duration>,
+    /// Raw stdout, set when the command ran with `OutputEncoding::Binary`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout_bytes: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr_bytes: Option<Vec<u8>>,
+    /// Output chunks of both streams in arrival order, recorded when the
+    /// command ran with `CommandOptions::capture_transcript`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transcript: Vec<(StdStream, String, DateTime<Utc>)>,
+}
+
+impl CommandResult {
+    /// Output kept per stream by the `Display` impl.
+    pub const DISPLAY_LIMIT: usize = 4096;
+
+    /// Exit code plus stdout and stderr, each cut down to its last `max_bytes`
+    /// bytes, since the end of the output is usually what explains a failure.
+    pub fn summary(&self, max_bytes: usize) -> String {
+        format!(
+            "exit={}\n--stdout--\n{}\n--stderr--\n{}",
+            self.exit_code,
+            Self::tail(&self.stdout, max_bytes),
+            Self::tail(&self.stderr, max_bytes)
+        )
+    }
+
+    /// One-line description of a failure, `exit N: <last non-empty stderr
+    /// line>`, or `None` when the command exited with code 0.
+    pub fn error_context(&self) -> Option<String> {
+        if self.exit_code == 0 {
+            return None;
+        }
+        match self
+            .stderr
+            .lines()
+            .rev()
+            .map(str::trim)
+            .find(|l| !l.is_empty())
+        {
+            Some(line) => Some(format!("exit {}: {}", self.exit_code, line)),
+            None => Some(format!("exit {}", self.exit_code)),
+        }
+    }
+
+    fn tail(output: &str, max_bytes: usize) -> String {
+        if output.len() <= max_bytes {
+            return output.to_string();
+        }
+        let mut start = output.len() - max_bytes;
+        while !output.is_char_boundary(start) {
+            start += 1;
+        }
+        format!("[{} bytes truncated]...{}", start, &output[start..])
+    }
+}
+
+impl std::fmt::Display for CommandResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.summary(Self::DISPLAY_LIMIT))
+    }
+}
+
+/// One command recorded by `CommandsApi::enable_history`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandAuditEntry {
+    pub command: String,
+    pub started_at: DateTime<Utc>,
+    /// `None` for runs that failed before exiting (e.g. timeouts), and for
+    /// background commands that are still running.
+    pub exit_code: Option<i32>,
+    /// `None` for background commands that are still running.
+    pub duration: Option<std::time::Duration>,
+    pub background: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +106,12 @@ pub struct CommandOutput {
     pub timestamp: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StdStream {
+    Stdout,
+    Stderr,
+}
+
 #[derive(Debug)]
 pub struct CommandHandle {
     pub pid: u32,
@@ -75,6 +160,83 @@ impl CommandHandle {
         self.result.take()
     }
 
+    /// Waits for the command to finish and returns its exit code. Output
+    /// receivers that haven't been taken are dropped first, so unread output
+    /// can't stall the command.
+    pub async fn exit_code(&mut self) -> crate::Result<i32> {
+        Ok(self.finish().await?.exit_code)
+    }
+
+    /// Waits for the command and fails unless it exited with code 0. A
+    /// non-zero exit is reported as `Error::ExecutionError` named
+    /// `NonZeroExit`, with stderr as the traceback.
+    pub async fn wait_success(&mut self) -> crate::Result<()> {
+        let result = self.finish().await?;
+        if result.exit_code == 0 {
+            return Ok(());
+        }
+        Err(crate::Error::ExecutionError {
+            name: "NonZeroExit".to_string(),
+            value: format!("exit code {}", result.exit_code),
+            traceback: result.stderr,
+        })
+    }
+
+    async fn finish(&mut self) -> crate::Result<CommandResult> {
+        self.stdout = None;
+        self.stderr = None;
+        let rx = self.result.take().ok_or_else(|| {
+            crate::Error::Configuration(format!("Result of command {} was already taken", self.pid))
+        })?;
+        rx.await.map_err(|_| crate::Error::Envd {
+            status: 500,
+            message: "Process stream closed before the command finished".to_string(),
+        })
+    }
+
+    /// Drains and returns whatever stdout has arrived so far without waiting.
+    /// Returns an empty string once the receiver has been taken.
+    pub fn read_stdout_buffered(&mut self) -> String {
+        Self::drain(self.stdout.as_mut())
+    }
+
+    pub fn read_stderr_buffered(&mut self) -> String {
+        Self::drain(self.stderr.as_mut())
+    }
+
+    fn drain(receiver: Option<&mut mpsc::Receiver<CommandOutput>>) -> String {
+        let mut buffered = String::new();
+        if let Some(rx) = receiver {
+            while let Ok(item) = rx.try_recv() {
+                buffered.push_str(&item.data);
+            }
+        }
+        buffered
+    }
+
+    /// Merges stdout and stderr into a single stream driven by the caller,
+    /// yielding chunks in the order they arrive. Takes both receivers, so the
+    /// callbacks and `take_stdout`/`take_stderr` have nothing left to consume.
+    pub fn stream_output(&mut self) -> impl Stream<Item = (StdStream, CommandOutput)> {
+        let stdout = Self::receiver_stream(self.stdout.take(), StdStream::Stdout);
+        let stderr = Self::receiver_stream(self.stderr.take(), StdStream::Stderr);
+        stream::select(stdout, stderr)
+    }
+
+    fn receiver_stream(
+        receiver: Option<mpsc::Receiver<CommandOutput>>,
+        kind: StdStream,
+    ) -> impl Stream<Item = (StdStream, CommandOutput)> {
+        stream::unfold(receiver, move |receiver| async move {
+            let mut rx = receiver?;
+            let item = rx.recv().await?;
+            Some(((kind, item), Some(rx)))
+        })
+    }
+
+    /// Spawns a detached task that invokes `callback` for every stdout chunk.
+    /// The task runs until the command's output ends and can't be joined or
+    /// cancelled; prefer `stream_output` when ordering or lifetime matters.
     pub fn on_stdout<F>(&mut self, mut callback: F)
     where
         F: FnMut(CommandOutput) + Send + 'static,
@@ -88,6 +250,8 @@ impl CommandHandle {
         }
     }
 
+    /// Spawns a detached task that invokes `callback` for every stderr chunk.
+    /// See `on_stdout` for the task lifetime caveats.
     pub fn on_stderr<F>(&mut self, mut callback: F)
     where
         F: FnMut(CommandOutput) + Send + 'static,
@@ -102,12 +266,57 @@ impl CommandHandle {
     }
 }
 
+/// Shell used to interpret string commands.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShellKind {
+    /// `bash -l -c`, sourcing the login profile (`/etc/profile`, `~/.profile`).
+    #[default]
+    Login,
+    /// `bash -c`, skipping profile scripts.
+    NonLogin,
+    /// `/bin/sh -c`, for images without bash.
+    Sh,
+}
+
 #[derive(Debug, Clone)]
 pub struct CommandOptions {
     pub envs: Option<HashMap<String, String>>,
     pub cwd: Option<String>,
     pub timeout: Option<std::time::Duration>,
     pub background: bool,
+    /// Overrides the sandbox-wide default shell (`Config::default_shell`).
+    pub shell: Option<ShellKind>,
+    /// Check that `cwd` exists before starting the process, failing with
+    /// `Error::NotFound` instead of a shell error. Costs an extra round-trip.
+    pub verify_cwd: bool,
+    /// How stdout and stderr bytes are turned into `CommandResult` strings.
+    pub output_encoding: OutputEncoding,
+    /// When `true` (the default), `envs` extend the environment the process
+    /// would otherwise get (envd's and the sandbox's defaults). When `false`,
+    /// the process starts from an empty environment holding only `envs`:
+    /// the sandbox's default env vars are dropped too, and there is no `PATH`
+    /// unless `envs` provides one, so commands must then be given by absolute
+    /// path. The shell also runs without `-l` (even for `ShellKind::Login`),
+    /// so profile scripts cannot repopulate the environment.
+    pub inherit_env: bool,
+    /// Record every output chunk in `CommandResult::transcript`.
+    pub capture_transcript: bool,
+}
+
+/// Decoding applied to command output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputEncoding {
+    /// Fail the command if the output isn't valid UTF-8. Background runs,
+    /// which can't fail once started, replace invalid sequences instead.
+    /// Streamed chunks are always decoded lossily, without splitting
+    /// characters across chunks.
+    #[default]
+    Utf8,
+    /// Replace invalid sequences with U+FFFD.
+    Utf8Lossy,
+    /// Decode lossily and also keep the raw bytes in
+    /// `CommandResult::stdout_bytes` / `stderr_bytes`.
+    Binary,
 }
 
 impl Default for CommandOptions {
@@ -117,6 +326,93 @@ impl Default for CommandOptions {
             cwd: None,
             timeout: Some(std::time::Duration::from_secs(60)),
             background: false,
+            shell: None,
+            verify_cwd: false,
+            output_encoding: OutputEncoding::default(),
+            inherit_env: true,
+            capture_transcript: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(exit_code: i32, stdout: &str, stderr: &str) -> CommandResult {
+        CommandResult {
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            exit_code,
+            execution_time: None,
+            stdout_bytes: None,
+            stderr_bytes: None,
+            transcript: Vec::new(),
         }
     }
+
+    #[test]
+    fn summary_shows_exit_code_and_both_streams() {
+        let result = result(2, "out", "err");
+        assert_eq!(
+            result.to_string(),
+            "exit=2\n--stdout--\nout\n--stderr--\nerr"
+        );
+        assert_eq!(result.error_context().as_deref(), Some("exit 2: err"));
+    }
+
+    #[test]
+    fn summary_keeps_the_tail_of_long_output() {
+        let result = result(1, "abcdef", "€b");
+        assert_eq!(
+            result.summary(3),
+            // The cut inside '€' moves forward to the next character boundary
+            "exit=1\n--stdout--\n[3 bytes truncated]...def\n--stderr--\n[3 bytes truncated]...b"
+        );
+    }
+
+    fn handle_with(result: Option<CommandResult>) -> CommandHandle {
+        let (_, stdout) = mpsc::channel(1);
+        let (_, stderr) = mpsc::channel(1);
+        let (tx, rx) = oneshot::channel();
+        if let Some(result) = result {
+            tx.send(result).unwrap();
+        }
+        CommandHandle::new(7, stdout, stderr, rx)
+    }
+
+    #[tokio::test]
+    async fn non_zero_exit_is_not_retryable() {
+        let mut handle = handle_with(Some(result(3, "", "no such file")));
+        match handle.wait_success().await {
+            Err(crate::Error::ExecutionError {
+                name,
+                value,
+                traceback,
+            }) => {
+                assert_eq!(name, "NonZeroExit");
+                assert_eq!(value, "exit code 3");
+                assert_eq!(traceback, "no such file");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // The result was consumed by the first wait
+        let taken = handle.wait_success().await.unwrap_err();
+        assert!(matches!(taken, crate::Error::Configuration(_)));
+        assert!(!taken.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn closed_stream_is_an_envd_error() {
+        let mut handle = handle_with(None);
+        assert!(matches!(
+            handle.exit_code().await,
+            Err(crate::Error::Envd { .. })
+        ));
+        assert!(handle_with(Some(result(0, "", "")))
+            .wait_success()
+            .await
+            .is_ok());
+    }
 }
@@ -1,5 +1,16 @@
+use bytes::{Bytes, BytesMut};
 use chrono::{DateTime, Utc};
+use futures::stream::{self, BoxStream, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Read size used by `WriteEntry::reader`.
+pub const READER_CHUNK_SIZE: usize = 256 * 1024;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EntryInfo {
@@ -7,9 +18,10 @@ pub struct EntryInfo {
     pub name: String,
     pub is_dir: bool,
     pub size: u64,
-    pub created_at: DateTime<Utc>,
-    pub updated_at: DateTime<Utc>,
-    pub permissions: String,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub permissions: u32,
+    pub symlink_target: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,12 +38,84 @@ pub struct WriteInfo {
 pub struct WriteEntry {
     pub path: String,
     pub data: WriteData,
+    /// Permission bits (e.g. `0o755`) applied once the file is written.
+    pub mode: Option<u32>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub enum WriteData {
     Text(String),
     Binary(Vec<u8>),
+    /// Bytes piped into the file as they arrive, without buffering the whole
+    /// content. An error from the stream aborts the upload.
+    Stream(WriteStream),
+}
+
+/// The source of a `WriteData::Stream`. Clones share one underlying stream:
+/// the first clone to be polled takes it, and any other clone then yields a
+/// single error, since the bytes can only be read once.
+pub struct WriteStream {
+    shared: Arc<Mutex<Option<BoxStream<'static, std::io::Result<Bytes>>>>>,
+    taken: Option<BoxStream<'static, std::io::Result<Bytes>>>,
+    polled: bool,
+}
+
+impl WriteStream {
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: Stream<Item = std::io::Result<Bytes>> + Send + 'static,
+    {
+        Self {
+            shared: Arc::new(Mutex::new(Some(stream.boxed()))),
+            taken: None,
+            polled: false,
+        }
+    }
+}
+
+impl Clone for WriteStream {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            taken: None,
+            polled: false,
+        }
+    }
+}
+
+impl Stream for WriteStream {
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if !self.polled {
+            self.polled = true;
+            let taken = self
+                .shared
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take();
+            self.taken = taken;
+            if self.taken.is_none() {
+                return Poll::Ready(Some(Err(std::io::Error::other(
+                    "stream already consumed by a clone of this entry",
+                ))));
+            }
+        }
+        match self.taken.as_mut() {
+            Some(stream) => stream.as_mut().poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl std::fmt::Debug for WriteData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteData::Text(text) => f.debug_tuple("Text").field(text).finish(),
+            WriteData::Binary(bytes) => f.debug_tuple("Binary").field(bytes).finish(),
+            WriteData::Stream(_) => f.write_str("Stream(..)"),
+        }
+    }
 }
 
 impl WriteEntry {
@@ -39,15 +123,56 @@ impl WriteEntry {
         Self {
             path: path.into(),
             data: WriteData::Text(data.into()),
+            mode: None,
         }
     }
 
+    pub fn text_with_mode(path: impl Into<String>, data: impl Into<String>, mode: u32) -> Self {
+        Self::text(path, data).with_mode(mode)
+    }
+
     pub fn binary(path: impl Into<String>, data: Vec<u8>) -> Self {
         Self {
             path: path.into(),
             data: WriteData::Binary(data),
+            mode: None,
+        }
+    }
+
+    pub fn stream<S>(path: impl Into<String>, stream: S) -> Self
+    where
+        S: Stream<Item = std::io::Result<Bytes>> + Send + 'static,
+    {
+        Self {
+            path: path.into(),
+            data: WriteData::Stream(WriteStream::new(stream)),
+            mode: None,
         }
     }
+
+    /// Uploads whatever `reader` yields, read in `READER_CHUNK_SIZE` pieces
+    /// as the request is sent, so the content never has to fit in memory.
+    pub fn reader<R>(path: impl Into<String>, reader: R) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        let chunks = stream::try_unfold(reader, |mut reader| async move {
+            let mut chunk = BytesMut::with_capacity(READER_CHUNK_SIZE);
+            let read = reader.read_buf(&mut chunk).await?;
+            Ok((read > 0).then(|| (chunk.freeze(), reader)))
+        });
+        Self::stream(path, chunks)
+    }
+
+    pub fn binary_with_mode(path: impl Into<String>, data: Vec<u8>, mode: u32) -> Self {
+        Self::binary(path, data).with_mode(mode)
+    }
+
+    /// Sets `mode` on any kind of entry.
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,14 +181,15 @@ pub struct FileInfo {
     pub name: String,
     pub size: u64,
     pub is_dir: bool,
-    pub created_at: DateTime<Utc>,
-    pub modified_at: DateTime<Utc>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub modified_at: Option<DateTime<Utc>>,
     pub permissions: u32,
     pub owner: String,
     pub group: String,
+    pub symlink_target: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum FilesystemEventType {
     Create,
     Modify,
@@ -79,11 +205,139 @@ pub struct FilesystemEvent {
     pub old_path: Option<String>,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortKey {
+    /// Keep the order returned by the server.
+    #[default]
+    None,
+    Name,
+    Size,
+    /// Last modification time; entries without one sort first.
+    Modified,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    pub sort_by: SortKey,
+    pub reverse: bool,
+    /// Group directories before files, regardless of `reverse`.
+    pub dirs_first: bool,
+    /// Keep only entries for which the predicate returns `true`.
+    pub filter: Option<fn(&EntryInfo) -> bool>,
+}
+
+impl ListOptions {
+    pub fn sort_by(mut self, key: SortKey) -> Self {
+        self.sort_by = key;
+        self
+    }
+
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+
+    pub fn dirs_first(mut self, dirs_first: bool) -> Self {
+        self.dirs_first = dirs_first;
+        self
+    }
+
+    pub fn filter(mut self, filter: fn(&EntryInfo) -> bool) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+    /// Also report events from subdirectories, including ones created after
+    /// the watch started.
+    pub recursive: bool,
+    /// Coalesce events on the same path that arrive within this window into a
+    /// single event carrying the most recent event type.
+    pub debounce: Option<Duration>,
+    /// Longest a debounced event is held back, so a path that keeps changing
+    /// faster than `debounce` is still reported. Defaults to ten times the
+    /// debounce window.
+    pub debounce_max_wait: Option<Duration>,
+    /// Only emit events of these types; `None` emits everything. envd has no
+    /// per-type subscription, so other events are dropped client-side.
+    pub event_types: Option<Vec<FilesystemEventType>>,
+    /// When the consumer falls behind and the event buffer is full, drop new
+    /// events and report them through `WatchHandle::recv_checked` instead of
+    /// pausing the envd stream until there is room (the default).
+    pub drop_on_lag: bool,
+}
+
+impl WatchOptions {
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    pub fn debounce(mut self, window: Duration) -> Self {
+        self.debounce = Some(window);
+        self
+    }
+
+    pub fn debounce_max_wait(mut self, max_wait: Duration) -> Self {
+        self.debounce_max_wait = Some(max_wait);
+        self
+    }
+
+    pub fn event_types(mut self, event_types: &[FilesystemEventType]) -> Self {
+        self.event_types = Some(event_types.to_vec());
+        self
+    }
+
+    pub fn drop_on_lag(mut self, drop: bool) -> Self {
+        self.drop_on_lag = drop;
+        self
+    }
+
+    pub(crate) fn accepts(&self, event_type: FilesystemEventType) -> bool {
+        self.event_types
+            .as_ref()
+            .is_none_or(|types| types.contains(&event_type))
+    }
+}
+
+/// Problems reported alongside watch events.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum WatchError {
+    /// This many events were dropped because the consumer fell behind.
+    #[error("watch lagged behind, {0} events dropped")]
+    Lagged(usize),
+}
+
+// Where events were dropped, as positions in the sequence of delivered
+// events, so `recv_checked` can report each gap where it happened
+#[derive(Debug, Default)]
+pub(crate) struct LagLog {
+    delivered: u64,
+    gaps: VecDeque<(u64, usize)>,
+}
+
+impl LagLog {
+    pub(crate) fn record_delivered(&mut self) {
+        self.delivered += 1;
+    }
+
+    pub(crate) fn record_dropped(&mut self) {
+        match self.gaps.back_mut() {
+            Some((position, dropped)) if *position == self.delivered => *dropped += 1,
+            _ => self.gaps.push_back((self.delivered, 1)),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct WatchHandle {
     pub path: String,
     event_receiver: tokio::sync::mpsc::Receiver<FilesystemEvent>,
     stop_sender: tokio::sync::oneshot::Sender<()>,
+    lag: Arc<Mutex<LagLog>>,
+    received: u64,
 }
 
 impl WatchHandle {
@@ -101,6 +355,8 @@ impl WatchHandle {
             path,
             event_receiver,
             stop_sender,
+            lag: Arc::default(),
+            received: 0,
         };
 
         (handle, event_sender, stop_receiver)
@@ -115,7 +371,58 @@ impl WatchHandle {
     }
 
     pub async fn recv(&mut self) -> Option<FilesystemEvent> {
-        self.event_receiver.recv().await
+        let event = self.event_receiver.recv().await?;
+        self.received += 1;
+        Some(event)
+    }
+
+    /// Like `recv`, but reports `WatchError::Lagged` with the number of
+    /// events dropped (see `WatchOptions::drop_on_lag`) at the point in the
+    /// sequence where they went missing: after the events received before
+    /// the gap and before the ones that follow it.
+    pub async fn recv_checked(&mut self) -> Option<Result<FilesystemEvent, WatchError>> {
+        if let Some(dropped) = self.take_gap() {
+            return Some(Err(WatchError::Lagged(dropped)));
+        }
+        self.recv().await.map(Ok)
+    }
+
+    /// Number of events dropped and not yet reported by `recv_checked`.
+    pub fn lagged(&self) -> usize {
+        self.lag_log().gaps.iter().map(|(_, dropped)| dropped).sum()
+    }
+
+    pub(crate) fn lag_log(&self) -> std::sync::MutexGuard<'_, LagLog> {
+        self.lag.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn shared_lag_log(&self) -> Arc<Mutex<LagLog>> {
+        self.lag.clone()
+    }
+
+    fn take_gap(&self) -> Option<usize> {
+        let mut log = self.lag_log();
+        match log.gaps.front() {
+            Some((position, _)) if *position <= self.received => {
+                log.gaps.pop_front().map(|(_, dropped)| dropped)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl futures::Stream for WatchHandle {
+    type Item = FilesystemEvent;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let polled = self.event_receiver.poll_recv(cx);
+        if let std::task::Poll::Ready(Some(_)) = polled {
+            self.received += 1;
+        }
+        polled
     }
 }
 
@@ -125,8 +432,98 @@ pub enum ReadFormat {
     Binary,
 }
 
+/// Hash used by `FilesystemApi::checksum`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumAlgo {
+    #[default]
+    Sha256,
+    Md5,
+}
+
+impl ChecksumAlgo {
+    /// coreutils tool computing this hash inside the sandbox.
+    pub(crate) fn command(self) -> &'static str {
+        match self {
+            ChecksumAlgo::Sha256 => "sha256sum",
+            ChecksumAlgo::Md5 => "md5sum",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ReadResult {
     Text(String),
     Binary(Vec<u8>),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_mode_sets_mode_on_any_entry() {
+        assert_eq!(WriteEntry::text("/tmp/a", "x").mode, None);
+        assert_eq!(
+            WriteEntry::text_with_mode("/tmp/a", "x", 0o755).mode,
+            Some(0o755)
+        );
+        let entry = WriteEntry::stream("/tmp/a", stream::empty()).with_mode(0o600);
+        assert_eq!(entry.mode, Some(0o600));
+    }
+
+    #[tokio::test]
+    async fn cloned_stream_entry_is_read_once() {
+        let entry = WriteEntry::stream("/tmp/a", stream::iter([Ok(Bytes::from_static(b"hello"))]));
+        let clone = entry.clone();
+
+        let WriteData::Stream(first) = entry.data else {
+            panic!("expected a streamed entry");
+        };
+        let WriteData::Stream(second) = clone.data else {
+            panic!("expected a streamed entry");
+        };
+        let read: Vec<_> = first.collect().await;
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].as_ref().unwrap(), &Bytes::from_static(b"hello"));
+
+        let read: Vec<_> = second.collect().await;
+        assert_eq!(read.len(), 1);
+        assert!(read[0].is_err());
+    }
+
+    #[test]
+    fn in_memory_entries_clone() {
+        let entry = WriteEntry::text("/tmp/a", "x").with_mode(0o644);
+        let clone = entry.clone();
+        assert_eq!(clone.path, "/tmp/a");
+        assert_eq!(clone.mode, Some(0o644));
+        assert!(matches!(clone.data, WriteData::Text(ref t) if t == "x"));
+    }
+
+    #[tokio::test]
+    async fn reader_entry_streams_the_whole_source_in_chunks() {
+        let data: Vec<u8> = (0..=255u8)
+            .cycle()
+            .take(READER_CHUNK_SIZE * 2 + 5)
+            .collect();
+        let entry = WriteEntry::reader("/tmp/big.bin", std::io::Cursor::new(data.clone()));
+
+        let WriteData::Stream(chunks) = entry.data else {
+            panic!("expected a streamed entry");
+        };
+        let chunks: Vec<Bytes> = chunks.map(|chunk| chunk.unwrap()).collect().await;
+        assert!(chunks.iter().all(|chunk| chunk.len() <= READER_CHUNK_SIZE));
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn event_types_limit_what_is_accepted() {
+        let all = WatchOptions::default();
+        assert!(all.accepts(FilesystemEventType::Delete));
+
+        let creates = WatchOptions::default().event_types(&[FilesystemEventType::Create]);
+        assert!(creates.accepts(FilesystemEventType::Create));
+        assert!(!creates.accepts(FilesystemEventType::Delete));
+        assert!(!creates.accepts(FilesystemEventType::Modify));
+    }
+}
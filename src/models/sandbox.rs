@@ -1,3 +1,4 @@
+use base64::{engine::general_purpose, Engine};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -21,8 +22,8 @@ pub struct Sandbox {
     #[serde(alias = "templateID")]
     pub template_id: String,
     pub alias: Option<String>,
-    #[serde(alias = "clientID")]
-    pub client_id: String,
+    #[serde(alias = "clientID", default)]
+    pub client_id: Option<String>,
     #[serde(alias = "teamID", default = "default_team_id")]
     pub team_id: String,
     pub name: Option<String>,
@@ -51,6 +52,35 @@ pub struct Sandbox {
     pub updated_at: DateTime<Utc>,
     #[serde(alias = "pausedAt")]
     pub paused_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub secure: Option<bool>,
+    #[serde(alias = "allowInternetAccess", default)]
+    pub allow_internet_access: Option<bool>,
+    #[serde(default)]
+    pub network: Option<SandboxNetwork>,
+}
+
+/// Egress rules sent as the `network` field of the create request. Only
+/// backends with egress filtering accept it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SandboxNetwork {
+    /// Hosts outbound traffic is allowed to, even when `deny_out` matches.
+    #[serde(rename = "allowOut", default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_out: Vec<String>,
+    /// Destinations (CIDR ranges) outbound traffic is blocked to.
+    #[serde(rename = "denyOut", default, skip_serializing_if = "Vec::is_empty")]
+    pub deny_out: Vec<String>,
+}
+
+/// Lifecycle state reported by the API. States the SDK doesn't know about
+/// are kept as `Other`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SandboxState {
+    Running,
+    Paused,
+    #[serde(untagged)]
+    Other(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -69,6 +99,10 @@ pub struct SandboxCreateRequest {
     pub metadata: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none", rename = "envVars")]
     pub env_vars: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<SandboxNetwork>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -76,10 +110,50 @@ pub struct CodeExecution {
     pub stdout: String,
     pub stderr: String,
     pub exit_code: i32,
-    pub error: Option<String>,
+    /// Parsed into the same shape as the interpreter's errors; a plain error
+    /// string becomes `value` unless a name can be split off it.
+    #[serde(
+        default,
+        deserialize_with = "crate::models::code_interpreter::deserialize_execution_error"
+    )]
+    pub error: Option<crate::models::ExecutionError>,
     pub results: Vec<ExecutionResult>,
 }
 
+impl CodeExecution {
+    /// Whether the run failed by either signal: an error was reported or the
+    /// process exited non-zero.
+    pub fn has_error(&self) -> bool {
+        self.error.is_some() || self.exit_code != 0
+    }
+
+    /// `true` only when no error was reported and the exit code is 0.
+    pub fn is_success(&self) -> bool {
+        !self.has_error()
+    }
+
+    /// Converts a failure by either signal of `has_error` into
+    /// `Error::ExecutionError`. A bare non-zero exit is reported with the
+    /// name `NonZeroExit` and stderr as the traceback.
+    pub fn into_result(self) -> crate::Result<CodeExecution> {
+        if let Some(error) = self.error {
+            return Err(crate::Error::ExecutionError {
+                name: error.name,
+                value: error.value,
+                traceback: error.traceback,
+            });
+        }
+        if self.exit_code != 0 {
+            return Err(crate::Error::ExecutionError {
+                name: "NonZeroExit".to_string(),
+                value: format!("exit code {}", self.exit_code),
+                traceback: self.stderr,
+            });
+        }
+        Ok(self)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExecutionResult {
     #[serde(rename = "type")]
@@ -95,6 +169,44 @@ pub struct ExecutionResult {
     pub json: Option<serde_json::Value>,
 }
 
+impl ExecutionResult {
+    pub fn png_bytes(&self) -> crate::Result<Option<Vec<u8>>> {
+        Self::decode_base64("png", self.png.as_deref())
+    }
+
+    pub fn jpeg_bytes(&self) -> crate::Result<Option<Vec<u8>>> {
+        Self::decode_base64("jpeg", self.jpeg.as_deref())
+    }
+
+    pub fn pdf_bytes(&self) -> crate::Result<Option<Vec<u8>>> {
+        Self::decode_base64("pdf", self.pdf.as_deref())
+    }
+
+    pub fn json_value(&self) -> Option<&serde_json::Value> {
+        self.json.as_ref()
+    }
+
+    /// Returns the most readable textual representation, preferring plain
+    /// text, then markdown, then HTML.
+    pub fn best_text(&self) -> Option<&str> {
+        self.text
+            .as_deref()
+            .or(self.markdown.as_deref())
+            .or(self.html.as_deref())
+    }
+
+    fn decode_base64(kind: &str, data: Option<&str>) -> crate::Result<Option<Vec<u8>>> {
+        let Some(data) = data else {
+            return Ok(None);
+        };
+
+        general_purpose::STANDARD
+            .decode(data.trim())
+            .map(Some)
+            .map_err(|e| crate::Error::undecodable(format!("Failed to decode {} result", kind), e))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SandboxMetrics {
     pub cpu_count: u32,
@@ -106,6 +218,44 @@ pub struct SandboxMetrics {
     pub timestamp: DateTime<Utc>,
 }
 
+impl SandboxMetrics {
+    /// Memory usage as a percentage of the total, or `0.0` when the total is unknown.
+    pub fn memory_usage_percent(&self) -> f64 {
+        Self::percent(self.mem_used, self.mem_total)
+    }
+
+    /// Disk usage as a percentage of the total, or `0.0` when the total is unknown.
+    pub fn disk_usage_percent(&self) -> f64 {
+        Self::percent(self.disk_used, self.disk_total)
+    }
+
+    pub fn to_human(&self) -> String {
+        self.to_string()
+    }
+
+    fn percent(used: u64, total: u64) -> f64 {
+        if total == 0 {
+            return 0.0;
+        }
+        used as f64 / total as f64 * 100.0
+    }
+}
+
+impl std::fmt::Display for SandboxMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const MB: u64 = 1_048_576;
+        write!(
+            f,
+            "CPU {:.1}% | Mem {}/{} MB | Disk {}/{} MB",
+            self.cpu_used_pct,
+            self.mem_used / MB,
+            self.mem_total / MB,
+            self.disk_used / MB,
+            self.disk_total / MB,
+        )
+    }
+}
+
 impl Default for SandboxMetrics {
     fn default() -> Self {
         Self {
@@ -136,3 +286,144 @@ pub enum LogLevel {
     Warn,
     Error,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn execution(exit_code: i32, error: Option<&str>) -> CodeExecution {
+        serde_json::from_value(serde_json::json!({
+            "stdout": "",
+            "stderr": "boom",
+            "exit_code": exit_code,
+            "error": error,
+            "results": [],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn into_result_agrees_with_has_error() {
+        for run in [
+            execution(0, None),
+            execution(1, None),
+            execution(0, Some("ValueError: x")),
+        ] {
+            assert_eq!(run.has_error(), run.clone().into_result().is_err());
+        }
+    }
+
+    #[test]
+    fn non_zero_exit_without_error_is_reported() {
+        match execution(2, None).into_result() {
+            Err(crate::Error::ExecutionError {
+                name,
+                value,
+                traceback,
+            }) => {
+                assert_eq!(name, "NonZeroExit");
+                assert_eq!(value, "exit code 2");
+                assert_eq!(traceback, "boom");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    fn execution_result(fields: serde_json::Value) -> ExecutionResult {
+        let mut value = serde_json::json!({ "type": "execute_result" });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn binary_results_decode_from_base64() {
+        let result = execution_result(serde_json::json!({
+            "png": "iVBORw0KGgo=",
+            "pdf": " JVBERi0= ",
+        }));
+        assert_eq!(
+            result.png_bytes().unwrap().unwrap(),
+            b"\x89PNG\r\n\x1a\n".to_vec()
+        );
+        assert_eq!(result.pdf_bytes().unwrap().unwrap(), b"%PDF-".to_vec());
+        assert_eq!(result.jpeg_bytes().unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_base64_is_an_invalid_response() {
+        let result = execution_result(serde_json::json!({ "png": "not base64!" }));
+        let error = result.png_bytes().unwrap_err();
+        assert!(matches!(error, crate::Error::InvalidResponse { .. }));
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn json_value_returns_the_raw_json() {
+        let result = execution_result(serde_json::json!({ "json": { "a": [1, 2] } }));
+        assert_eq!(
+            result.json_value(),
+            Some(&serde_json::json!({ "a": [1, 2] }))
+        );
+        assert_eq!(execution_result(serde_json::json!({})).json_value(), None);
+    }
+
+    #[test]
+    fn best_text_prefers_text_then_markdown_then_html() {
+        let all = serde_json::json!({ "text": "t", "markdown": "m", "html": "h" });
+        assert_eq!(execution_result(all).best_text(), Some("t"));
+        let no_text = serde_json::json!({ "markdown": "m", "html": "h" });
+        assert_eq!(execution_result(no_text).best_text(), Some("m"));
+        let html_only = serde_json::json!({ "html": "h" });
+        assert_eq!(execution_result(html_only).best_text(), Some("h"));
+        let svg_only = serde_json::json!({ "svg": "<svg/>" });
+        assert_eq!(execution_result(svg_only).best_text(), None);
+    }
+
+    #[test]
+    fn minimal_sandbox_payload_deserializes() {
+        let sandbox: Sandbox = serde_json::from_value(serde_json::json!({
+            "sandboxID": "isb1",
+            "templateID": "base",
+        }))
+        .unwrap();
+        assert_eq!(sandbox.sandbox_id, "isb1");
+        assert_eq!(sandbox.template_id, "base");
+        assert_eq!(sandbox.client_id, None);
+        assert_eq!(sandbox.team_id, "default");
+        assert!(sandbox.is_live);
+    }
+
+    #[test]
+    fn zero_limits_report_zero_percent() {
+        let metrics = SandboxMetrics {
+            mem_used: 512,
+            disk_used: 1024,
+            ..Default::default()
+        };
+        assert_eq!(metrics.memory_usage_percent(), 0.0);
+        assert_eq!(metrics.disk_usage_percent(), 0.0);
+        assert_eq!(metrics.to_human(), "CPU 0.0% | Mem 0/0 MB | Disk 0/0 MB");
+    }
+
+    #[test]
+    fn metrics_are_formatted_in_megabytes() {
+        const MB: u64 = 1_048_576;
+        let metrics = SandboxMetrics {
+            cpu_used_pct: 12.34,
+            mem_used: 512 * MB,
+            mem_total: 1024 * MB,
+            disk_used: MB,
+            disk_total: 4 * MB,
+            ..Default::default()
+        };
+        assert_eq!(metrics.memory_usage_percent(), 50.0);
+        assert_eq!(metrics.disk_usage_percent(), 25.0);
+        assert_eq!(
+            metrics.to_string(),
+            "CPU 12.3% | Mem 512/1024 MB | Disk 1/4 MB"
+        );
+    }
+}
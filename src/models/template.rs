@@ -16,6 +16,14 @@ pub struct Template {
     pub updated_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TemplateFilter {
+    #[default]
+    All,
+    Public,
+    Team,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TemplateCreateRequest {
     pub name: String,
@@ -44,7 +52,7 @@ pub struct TemplateBuild {
     pub finished_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum BuildStatus {
     Building,
@@ -56,14 +64,71 @@ pub enum BuildStatus {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BuildLog {
     pub timestamp: DateTime<Utc>,
+    #[serde(alias = "message")]
     pub line: String,
     pub level: BuildLogLevel,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum BuildLogLevel {
     Info,
+    #[serde(alias = "warning")]
+    Warn,
     Error,
     Debug,
+    /// A level the SDK doesn't know; ranked like `Info`.
+    #[serde(other)]
+    Other,
+}
+
+impl BuildLogLevel {
+    fn severity(self) -> u8 {
+        match self {
+            BuildLogLevel::Debug => 0,
+            BuildLogLevel::Info | BuildLogLevel::Other => 1,
+            BuildLogLevel::Warn => 2,
+            BuildLogLevel::Error => 3,
+        }
+    }
+
+    /// Whether this level is `min` or more severe
+    /// (`Debug` < `Info` < `Warn` < `Error`).
+    pub fn at_least(self, min: BuildLogLevel) -> bool {
+        self.severity() >= min.severity()
+    }
+
+    // Value of the status endpoint's `level` query parameter
+    pub(crate) fn as_query(self) -> Option<&'static str> {
+        match self {
+            BuildLogLevel::Debug => Some("debug"),
+            BuildLogLevel::Info => Some("info"),
+            BuildLogLevel::Warn => Some("warn"),
+            BuildLogLevel::Error => Some("error"),
+            BuildLogLevel::Other => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_log_levels_include_warn_and_unknown() {
+        let levels: Vec<BuildLogLevel> =
+            serde_json::from_str(r#"["warn", "warning", "trace", "error"]"#).unwrap();
+        assert_eq!(
+            levels,
+            [
+                BuildLogLevel::Warn,
+                BuildLogLevel::Warn,
+                BuildLogLevel::Other,
+                BuildLogLevel::Error
+            ]
+        );
+        assert!(BuildLogLevel::Warn.at_least(BuildLogLevel::Info));
+        assert!(!BuildLogLevel::Warn.at_least(BuildLogLevel::Error));
+        assert!(!BuildLogLevel::Other.at_least(BuildLogLevel::Warn));
+    }
 }
@@ -0,0 +1,151 @@
+use crate::error::{Error, Result};
+use std::future::Future;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: bool,
+    pub retry_on: fn(&Error) -> bool,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            ..Default::default()
+        }
+    }
+
+    /// A policy that never retries, useful in tests.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    pub fn base_delay(mut self, delay: Duration) -> Self {
+        self.base_delay = delay;
+        self
+    }
+
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn retry_on(mut self, predicate: fn(&Error) -> bool) -> Self {
+        self.retry_on = predicate;
+        self
+    }
+
+    /// Whether a failure on the zero-based `attempt` should be retried.
+    pub fn should_retry(&self, attempt: u32, error: &Error) -> bool {
+        attempt < self.max_retries && (self.retry_on)(error)
+    }
+
+    /// Exponential backoff for the zero-based `attempt`, capped at `max_delay`.
+    /// With jitter enabled the delay is picked uniformly from the upper half
+    /// of that range.
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        let delay = self.base_delay.saturating_mul(factor).min(self.max_delay);
+
+        if !self.jitter || delay.is_zero() {
+            return delay;
+        }
+
+        let half = delay / 2;
+        let random = (uuid::Uuid::new_v4().as_u128() % 1_000) as u32;
+        half + (delay - half) * random / 1_000
+    }
+
+    pub async fn run<T, F, Fut>(&self, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match operation().await {
+                Ok(value) => return Ok(value),
+                Err(e) if self.should_retry(attempt, &e) => {
+                    let delay = self.delay_for(attempt);
+                    tracing::debug!(
+                        "Attempt {} failed: {}. Retrying in {:?}",
+                        attempt + 1,
+                        e,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            jitter: true,
+            retry_on: default_retry_on,
+        }
+    }
+}
+
+pub fn default_retry_on(error: &Error) -> bool {
+    error.is_retryable()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn run_retries_retryable_errors_up_to_max_retries() {
+        let policy = RetryPolicy::new(2).base_delay(Duration::ZERO);
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = policy
+            .run(|| async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(Error::Timeout)
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn run_does_not_retry_other_errors() {
+        let policy = RetryPolicy::new(2).base_delay(Duration::ZERO);
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = policy
+            .run(|| async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(Error::Configuration("bad".to_string()))
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Configuration(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn config_max_retries_sets_the_policy() {
+        let config = crate::config::Config::with_api_key("key").max_retries(0);
+        assert_eq!(config.retry_policy.max_retries, 0);
+    }
+}
@@ -1,9 +1,10 @@
 use crate::{
-    models::{WriteData, WriteEntry, WriteInfo},
+    config::{acquire_permit, Config},
+    models::{ChecksumAlgo, WriteData, WriteEntry, WriteInfo},
     Error, Result,
 };
 use base64::{engine::general_purpose, Engine};
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use futures::{stream::BoxStream, StreamExt};
 use http::HeaderMap;
 use reqwest::{
@@ -12,18 +13,102 @@ use reqwest::{
 };
 use serde_json::Value;
 use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OnceCell, Semaphore};
 use tracing::debug;
 
+/// How long a client with a readiness gate waits for envd when it has no
+/// request timeout.
+pub const DEFAULT_READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Exit code of the append script when the data went in but the staged
+// chunk could not be removed
+const STAGED_LEFT_BEHIND: i32 = 125;
+
+/// Returns a fresh path under `/tmp` of the form `<prefix><token><suffix>`.
+pub(crate) fn temp_path(prefix: &str, suffix: &str) -> String {
+    format!("/tmp/{}{}{}", prefix, uuid::Uuid::new_v4().simple(), suffix)
+}
+
+#[derive(Clone)]
 pub struct RpcClient {
     base_url: String,
     http_client: HttpClient,
     headers: HeaderMap,
+    limiter: Option<Arc<Semaphore>>,
+    // Bounds each unary Connect call, and the wait for a stream's headers
+    request_timeout: Option<Duration>,
+    // Bounds the gap between frames of a process stream
+    idle_timeout: Option<Duration>,
+    // When set, requests first wait for envd to pass a health check, once
+    // per gate
+    readiness: Option<Arc<OnceCell<()>>>,
+}
+
+// Exit code and output of a process run by `RpcClient::run_tool`
+#[derive(Default)]
+struct ToolOutput {
+    exit_code: Option<i32>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl ToolOutput {
+    fn stderr(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.stderr)
+    }
 }
 
 impl RpcClient {
+    /// Every request carries Basic auth for the default `user` account, which
+    /// envd uses to pick the user a process or file operation runs as. On
+    /// secure sandboxes envd also requires `access_token` in `X-Access-Token`.
     pub async fn connect(url: impl Into<String>, access_token: Option<&str>) -> Result<Self> {
+        Self::connect_with_http(url, access_token, HttpClient::new()).await
+    }
+
+    /// Like `connect`, but fails a unary Connect call (and the wait for a
+    /// streaming call's response headers) with `Error::Timeout` after
+    /// `request_timeout`, and a process output stream once no frame has
+    /// arrived for `idle_timeout`. `None` leaves the respective limit off.
+    /// Uses default TLS settings and no concurrency limit; to keep those
+    /// from a `Config`, use `connect_with_config` with
+    /// `Config::timeout_seconds` and `Config::rpc_idle_timeout`.
+    pub async fn connect_with_timeouts(
+        url: impl Into<String>,
+        access_token: Option<&str>,
+        request_timeout: Option<Duration>,
+        idle_timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let mut client = Self::connect(url, access_token).await?;
+        client.request_timeout = request_timeout;
+        client.idle_timeout = idle_timeout;
+        Ok(client)
+    }
+
+    /// Like `connect`, but applies the TLS settings and concurrency limit
+    /// from `config`, with `timeout_seconds` as the request timeout and
+    /// `rpc_idle_timeout` as the idle timeout of `connect_with_timeouts`.
+    pub async fn connect_with_config(
+        url: impl Into<String>,
+        access_token: Option<&str>,
+        config: &Config,
+    ) -> Result<Self> {
+        let http_client = config.apply_tls(HttpClient::builder())?.build()?;
+        let mut client = Self::connect_with_http(url, access_token, http_client).await?;
+        client.limiter = config.request_limiter();
+        client.request_timeout = Some(Duration::from_secs(config.timeout_seconds));
+        client.idle_timeout = config.rpc_idle_timeout;
+        Ok(client)
+    }
+
+    async fn connect_with_http(
+        url: impl Into<String>,
+        access_token: Option<&str>,
+        http_client: HttpClient,
+    ) -> Result<Self> {
         let base_url = url.into();
-        let http_client = HttpClient::new();
         let mut headers = HeaderMap::new();
         headers.insert("Content-Type", "application/json".parse().unwrap());
         headers.insert("Accept", "application/json".parse().unwrap());
@@ -32,19 +117,14 @@ impl RpcClient {
 
         // Add Basic Auth header for user authentication
         // Using "user:" (username:password, but password is empty)
-        let auth_value = general_purpose::STANDARD.encode("user:");
-        headers.insert(
-            "Authorization",
-            format!("Basic {}", auth_value).parse().unwrap(),
-        );
+        headers.insert("Authorization", basic_auth("user").parse().unwrap());
 
         if let Some(token) = access_token {
             headers.insert(
                 "X-Access-Token",
-                token.parse().map_err(|e| Error::Api {
-                    status: 400,
-                    message: format!("Invalid access token header: {}", e),
-                })?,
+                token
+                    .parse()
+                    .map_err(|e| Error::wrap(400, "Invalid access token header", e))?,
             );
         }
 
@@ -52,16 +132,86 @@ impl RpcClient {
             base_url,
             http_client,
             headers,
+            limiter: None,
+            request_timeout: None,
+            idle_timeout: None,
+            readiness: None,
+        })
+    }
+
+    /// Makes the first request wait until envd answers `GET /health`, for
+    /// clients created before the sandbox has finished booting. Clients that
+    /// share `gate` poll only once between them. The wait is bounded by the
+    /// request timeout, or `DEFAULT_READY_TIMEOUT` without one.
+    pub fn with_readiness_gate(mut self, gate: Arc<OnceCell<()>>) -> Self {
+        self.readiness = Some(gate);
+        self
+    }
+
+    async fn wait_until_ready(&self) -> Result<()> {
+        match &self.readiness {
+            Some(gate) => gate
+                .get_or_try_init(|| self.poll_health())
+                .await
+                .map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    async fn poll_health(&self) -> Result<()> {
+        const PROBE_INTERVAL: Duration = Duration::from_millis(200);
+        const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+        let url = format!("{}/health", self.base_url);
+        let probe = async {
+            loop {
+                let response = self
+                    .http_client
+                    .get(&url)
+                    .headers(self.headers.clone())
+                    .timeout(PROBE_TIMEOUT)
+                    .send()
+                    .await;
+                match response {
+                    Ok(response) if response.status().is_success() => return,
+                    Ok(response) => debug!("envd not ready: {}", response.status()),
+                    Err(e) => debug!("envd not ready: {}", e),
+                }
+                tokio::time::sleep(PROBE_INTERVAL).await;
+            }
+        };
+
+        let limit = self.request_timeout.unwrap_or(DEFAULT_READY_TIMEOUT);
+        tokio::time::timeout(limit, probe)
+            .await
+            .map_err(|_| Error::Timeout)
+    }
+
+    /// Sends a single `GET /health` to envd, failing with its status when it
+    /// does not answer with success.
+    pub(crate) async fn check_health(&self) -> Result<()> {
+        let response = self
+            .http_client
+            .get(format!("{}/health", self.base_url))
+            .headers(self.headers.clone())
+            .send()
+            .await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        Err(Error::Envd {
+            status: status.as_u16(),
+            message: format!("envd health check failed: HTTP {}", status.as_u16()),
         })
     }
 
     pub fn set_header(&mut self, name: &'static str, value: &str) -> Result<()> {
         self.headers.insert(
             name,
-            value.parse().map_err(|e| Error::Api {
-                status: 400,
-                message: format!("Invalid header value: {}", e),
-            })?,
+            value
+                .parse()
+                .map_err(|e| Error::wrap(400, "Invalid header value", e))?,
         );
         Ok(())
     }
@@ -72,6 +222,20 @@ impl RpcClient {
         method: &str,
         request: Value,
         is_stream: bool,
+    ) -> Result<Response> {
+        self.post_connect_request_as(service, method, request, is_stream, None)
+            .await
+    }
+
+    // Like `post_connect_request`, but authenticates as `username` instead of
+    // the default user, which makes envd run the call as that user
+    async fn post_connect_request_as(
+        &self,
+        service: &str,
+        method: &str,
+        request: Value,
+        is_stream: bool,
+        username: Option<&str>,
     ) -> Result<Response> {
         let url = format!("{}/{}/{}", self.base_url, service, method);
 
@@ -79,6 +243,14 @@ impl RpcClient {
         debug!("Request body: {}", request);
 
         let mut headers = self.headers.clone();
+        if let Some(username) = username {
+            headers.insert(
+                "Authorization",
+                basic_auth(username)
+                    .parse()
+                    .map_err(|e| Error::wrap(400, "Invalid username", e))?,
+            );
+        }
 
         // Use different Content-Type based on whether it's a streaming request
         let content_type = if is_stream {
@@ -89,10 +261,8 @@ impl RpcClient {
         headers.insert("Content-Type", content_type.parse().unwrap());
 
         // For Connect protocol, we need to wrap the request in an envelope
-        let json_data = serde_json::to_string(&request).map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to serialize request: {}", e),
-        })?;
+        let json_data = serde_json::to_string(&request)
+            .map_err(|e| Error::undecodable("Failed to serialize request", e))?;
 
         let body = if is_stream {
             // For streaming requests, wrap in Connect envelope format
@@ -101,17 +271,29 @@ impl RpcClient {
             json_data.into_bytes()
         };
 
-        let response = self
-            .http_client
-            .post(&url)
-            .headers(headers)
-            .body(body)
-            .send()
-            .await
-            .map_err(|e| Error::Api {
-                status: 500,
-                message: format!("HTTP request failed: {}", e),
-            })?;
+        let mut request = self.http_client.post(&url).headers(headers).body(body);
+        // reqwest's per-request timeout also covers the body, which for a
+        // stream may legitimately stay open much longer
+        if let (Some(limit), false) = (self.request_timeout, is_stream) {
+            request = request.timeout(limit);
+        }
+
+        self.wait_until_ready().await?;
+        let _permit = acquire_permit(self.limiter.as_ref()).await;
+        let send = request.send();
+        let response = match (self.request_timeout, is_stream) {
+            (Some(limit), true) => tokio::time::timeout(limit, send)
+                .await
+                .map_err(|_| Error::Timeout)?,
+            _ => send.await,
+        }
+        .map_err(|e| {
+            if e.is_timeout() {
+                Error::Timeout
+            } else {
+                Error::wrap(500, "HTTP request failed", e)
+            }
+        })?;
 
         if !response.status().is_success() {
             let status = response.status().as_u16();
@@ -119,7 +301,7 @@ impl RpcClient {
                 .text()
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(Error::Api {
+            return Err(Error::Envd {
                 status,
                 message: format!("HTTP {} error: {}", status, body),
             });
@@ -135,10 +317,10 @@ impl RpcClient {
         let response = self
             .post_connect_request("process.Process", "List", request, false)
             .await?;
-        let result: Value = response.json().await.map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to parse response: {}", e),
-        })?;
+        let result: Value = response
+            .json()
+            .await
+            .map_err(|e| Error::undecodable("Failed to parse response", e))?;
 
         debug!("Process list response: {}", result);
         Ok(result)
@@ -149,7 +331,19 @@ impl RpcClient {
         let response = self
             .post_connect_request("process.Process", "Start", request, true)
             .await?;
-        ProcessStream::new(response).await
+        let mut stream = ProcessStream::new(response).await?;
+        stream.inner.idle_timeout = self.idle_timeout;
+        Ok(stream)
+    }
+
+    /// Like `process_start`, but runs the process as `username`.
+    pub async fn process_start_as(&self, params: Value, username: &str) -> Result<ProcessStream> {
+        let response = self
+            .post_connect_request_as("process.Process", "Start", params, true, Some(username))
+            .await?;
+        let mut stream = ProcessStream::new(response).await?;
+        stream.inner.idle_timeout = self.idle_timeout;
+        Ok(stream)
     }
 
     pub async fn process_send_input(&self, params: Value) -> Result<Value> {
@@ -157,10 +351,10 @@ impl RpcClient {
         let response = self
             .post_connect_request("process.Process", "SendInput", request, false)
             .await?;
-        let result: Value = response.json().await.map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to parse response: {}", e),
-        })?;
+        let result: Value = response
+            .json()
+            .await
+            .map_err(|e| Error::undecodable("Failed to parse response", e))?;
         Ok(result)
     }
 
@@ -169,10 +363,10 @@ impl RpcClient {
         let response = self
             .post_connect_request("process.Process", "SendSignal", request, false)
             .await?;
-        let result: Value = response.json().await.map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to parse response: {}", e),
-        })?;
+        let result: Value = response
+            .json()
+            .await
+            .map_err(|e| Error::undecodable("Failed to parse response", e))?;
         Ok(result)
     }
 
@@ -181,45 +375,99 @@ impl RpcClient {
         let response = self
             .post_connect_request("process.Process", "Connect", request, true)
             .await?;
-        ProcessStream::new(response).await
+        let mut stream = ProcessStream::new(response).await?;
+        stream.inner.idle_timeout = self.idle_timeout;
+        Ok(stream)
     }
 
     // Filesystem service calls using Connect protocol
+    /// Reads `path` as text, replacing invalid UTF-8 sequences with U+FFFD.
     pub async fn filesystem_read(&self, path: &str, username: &str) -> Result<String> {
-        // For filesystem read, we might need to use a different approach
-        // Let's try the files endpoint first as that might be a REST endpoint
-        let url = format!(
-            "{}/files?path={}&username={}",
-            self.base_url, path, username
-        );
+        let bytes = self.filesystem_read_bytes(path, username).await?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
 
-        let response = self
+    pub async fn filesystem_read_bytes(&self, path: &str, username: &str) -> Result<Vec<u8>> {
+        let (_, bytes) = self.fetch_file(path, username, None).await?;
+        Ok(bytes)
+    }
+
+    /// Reads up to `length` bytes starting at `offset` with an HTTP range
+    /// request. If envd ignores the range, the full body is sliced locally.
+    pub async fn filesystem_read_range(
+        &self,
+        path: &str,
+        username: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>> {
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+
+        let range = format!("bytes={}-{}", offset, offset + length - 1);
+        let (partial, bytes) = self.fetch_file(path, username, Some(&range)).await?;
+        if partial {
+            return Ok(bytes);
+        }
+
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(bytes.len());
+        let end = usize::try_from(offset.saturating_add(length))
+            .unwrap_or(usize::MAX)
+            .min(bytes.len());
+        Ok(bytes[start..end].to_vec())
+    }
+
+    // Returns whether the body is a partial (ranged) response, and the body.
+    async fn fetch_file(
+        &self,
+        path: &str,
+        username: &str,
+        range: Option<&str>,
+    ) -> Result<(bool, Vec<u8>)> {
+        // The files endpoint returns the raw file content, not base64
+        let url = format!("{}/files", self.base_url);
+
+        let mut request = self
             .http_client
             .get(&url)
             .headers(self.headers.clone())
+            .query(&[("path", path), ("username", username)]);
+        if let Some(range) = range {
+            request = request.header(reqwest::header::RANGE, range);
+        }
+
+        self.wait_until_ready().await?;
+        let _permit = acquire_permit(self.limiter.as_ref()).await;
+        let response = request
             .send()
             .await
-            .map_err(|e| Error::Api {
-                status: 500,
-                message: format!("HTTP request failed: {}", e),
-            })?;
+            .map_err(|e| Error::wrap(500, "HTTP request failed", e))?;
 
-        if !response.status().is_success() {
-            let status = response.status().as_u16();
-            let body = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(Error::Api {
-                status,
-                message: format!("HTTP {} error: {}", status, body),
-            });
+        match response.status() {
+            // The requested range starts at or past the end of the file
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE if range.is_some() => {
+                return Ok((true, Vec::new()))
+            }
+            status if !status.is_success() => {
+                let status = status.as_u16();
+                let body = response
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| "Unknown error".to_string());
+                return Err(Error::Envd {
+                    status,
+                    message: format!("HTTP {} error: {}", status, body),
+                });
+            }
+            _ => {}
         }
 
-        response.text().await.map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to read response: {}", e),
-        })
+        let partial = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        let bytes = response.bytes().await.map_err(Error::Http)?;
+        Ok((partial, bytes.to_vec()))
     }
 
     pub async fn filesystem_write(&self, params: Value) -> Result<Value> {
@@ -227,35 +475,248 @@ impl RpcClient {
         let response = self
             .post_connect_request("filesystem.Filesystem", "Write", request, false)
             .await?;
-        let result: Value = response.json().await.map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to parse response: {}", e),
-        })?;
+        let result: Value = response
+            .json()
+            .await
+            .map_err(|e| Error::undecodable("Failed to parse response", e))?;
         Ok(result)
     }
 
+    /// Uploads `entries` through the multipart `/files` endpoint, without
+    /// base64 or JSON encoding. In-memory data is moved into the request
+    /// rather than copied; `WriteData::Stream` entries (e.g. from
+    /// `WriteEntry::reader`) are sent as they are read, so their size is not
+    /// bounded by memory. Modes are applied as `username` once the upload
+    /// succeeds; if that fails, the uploaded files are removed again so the
+    /// call either fully succeeds or leaves nothing behind.
     pub async fn filesystem_upload(
         &self,
-        entries: &[WriteEntry],
+        entries: Vec<WriteEntry>,
         username: &str,
     ) -> Result<Vec<WriteInfo>> {
         if entries.is_empty() {
             return Ok(Vec::new());
         }
 
-        let url = format!("{}/files", self.base_url);
+        let single_path = match entries.as_slice() {
+            [entry] => Some(entry.path.clone()),
+            _ => None,
+        };
+
+        let mut modes: Vec<(u32, String)> = entries
+            .iter()
+            .filter_map(|entry| Some((entry.mode?, entry.path.clone())))
+            .collect();
         let mut form = Form::new();
 
         for entry in entries {
-            let part = match &entry.data {
-                WriteData::Text(text) => Part::text(text.clone()),
-                WriteData::Binary(bytes) => Part::bytes(bytes.clone()),
-            }
-            .file_name(entry.path.clone());
+            let mime = mime_type_for_path(&entry.path);
+            let bytes = match entry.data {
+                WriteData::Text(text) => Bytes::from(text),
+                WriteData::Binary(bytes) => Bytes::from(bytes),
+                WriteData::Stream(stream) => {
+                    let part = Part::stream(reqwest::Body::wrap_stream(stream))
+                        .file_name(entry.path)
+                        .mime_str(mime)?;
+                    form = form.part("file", part);
+                    continue;
+                }
+            };
+            let part = Part::stream(bytes).file_name(entry.path).mime_str(mime)?;
 
             form = form.part("file", part);
         }
 
+        let written = self
+            .send_upload(form, username, single_path.as_deref())
+            .await?;
+
+        // The upload endpoint has no mode parameter, so apply it afterwards,
+        // one chmod per distinct mode
+        modes.sort();
+        for group in modes.chunk_by(|a, b| a.0 == b.0) {
+            let paths: Vec<&str> = group.iter().map(|(_, path)| path.as_str()).collect();
+            if let Err(e) = self.chmod(group[0].0, &paths, username).await {
+                let uploaded: Vec<&str> = written.iter().map(|w| w.path.as_str()).collect();
+                if let Err(cleanup) = self.remove_as(&uploaded, username).await {
+                    tracing::warn!("Failed to remove {}: {}", uploaded.join(", "), cleanup);
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(written)
+    }
+
+    async fn chmod(&self, mode: u32, paths: &[&str], username: &str) -> Result<()> {
+        let mut args = vec![format!("{:o}", mode), "--".to_string()];
+        args.extend(paths.iter().map(|p| p.to_string()));
+
+        let output = self.run_tool("chmod", &args, Some(username)).await?;
+        if output.exit_code != Some(0) {
+            return Err(Error::Operation(format!(
+                "Failed to set mode {:o} on {}: {}",
+                mode,
+                paths.join(", "),
+                output.stderr().trim()
+            )));
+        }
+        Ok(())
+    }
+
+    async fn remove_as(&self, paths: &[&str], username: &str) -> Result<()> {
+        let mut args = vec!["-f".to_string(), "--".to_string()];
+        args.extend(paths.iter().map(|p| p.to_string()));
+
+        let output = self.run_tool("rm", &args, Some(username)).await?;
+        if output.exit_code != Some(0) {
+            return Err(Error::Operation(output.stderr().trim().to_string()));
+        }
+        Ok(())
+    }
+
+    /// Hex digest of `path`, computed inside the sandbox as `username`.
+    /// envd has no hashing RPC, so this runs `sha256sum`/`md5sum`.
+    pub async fn filesystem_checksum(
+        &self,
+        path: &str,
+        algo: ChecksumAlgo,
+        username: &str,
+    ) -> Result<String> {
+        let args = ["-b", "--", path].map(String::from);
+        let output = self.run_tool(algo.command(), &args, Some(username)).await?;
+        if output.exit_code != Some(0) {
+            let stderr = output.stderr();
+            if stderr.contains("No such file") {
+                return Err(Error::NotFound(format!("File {}", path)));
+            }
+            return Err(Error::Operation(format!(
+                "{} failed for {}: {}",
+                algo.command(),
+                path,
+                stderr.trim()
+            )));
+        }
+
+        parse_digest(&String::from_utf8_lossy(&output.stdout)).ok_or_else(|| {
+            Error::invalid_response(format!(
+                "{} returned no digest for {}",
+                algo.command(),
+                path
+            ))
+        })
+    }
+
+    /// Appends `data` to `path`, creating it if needed. The upload endpoint
+    /// only replaces whole files, so the data is uploaded to a temporary file
+    /// and concatenated onto `path` inside the sandbox.
+    pub async fn filesystem_append(&self, path: &str, data: Vec<u8>, username: &str) -> Result<()> {
+        let staged = temp_path(".append-", "");
+        self.filesystem_upload(vec![WriteEntry::binary(&staged, data)], username)
+            .await?;
+
+        // Exits with STAGED_LEFT_BEHIND when only the cleanup failed
+        let args = [
+            "-c",
+            r#"cat "$1" >> "$2" || exit; rm -f "$1" || exit 125"#,
+            "sh",
+            &staged,
+            path,
+        ]
+        .map(String::from);
+        let output = self.run_tool("sh", &args, Some(username)).await?;
+        if output.exit_code == Some(STAGED_LEFT_BEHIND) {
+            tracing::warn!(
+                "Appended to {} but failed to remove {}: {}",
+                path,
+                staged,
+                output.stderr().trim()
+            );
+        } else if output.exit_code != Some(0) {
+            return Err(Error::Operation(format!(
+                "Failed to append to {}: {}",
+                path,
+                output.stderr().trim()
+            )));
+        }
+        Ok(())
+    }
+
+    // Runs a short-lived helper process to completion
+    async fn run_tool(
+        &self,
+        cmd: &str,
+        args: &[String],
+        username: Option<&str>,
+    ) -> Result<ToolOutput> {
+        let params = serde_json::json!({
+            "process": { "cmd": cmd, "args": args }
+        });
+        let mut stream = match username {
+            Some(username) => self.process_start_as(params, username).await?,
+            None => self.process_start(params).await?,
+        };
+
+        let mut output = ToolOutput::default();
+        while let Some(event) = stream.next_event().await? {
+            match event.event {
+                ProcessEventData::Data { data } => {
+                    if let Some(decoded) = data
+                        .stdout
+                        .and_then(|s| general_purpose::STANDARD.decode(s).ok())
+                    {
+                        output.stdout.extend_from_slice(&decoded);
+                    }
+                    if let Some(decoded) = data
+                        .stderr
+                        .and_then(|s| general_purpose::STANDARD.decode(s).ok())
+                    {
+                        output.stderr.extend_from_slice(&decoded);
+                    }
+                }
+                ProcessEventData::End { end } => {
+                    output.exit_code = end.exit_code.or_else(|| {
+                        end.status
+                            .strip_prefix("exit status ")
+                            .and_then(|c| c.trim().parse().ok())
+                    });
+                    break;
+                }
+                ProcessEventData::Start { .. } => {}
+            }
+        }
+        Ok(output)
+    }
+
+    /// Streams a local file into the sandbox at `remote_path` without
+    /// loading it into memory.
+    pub async fn filesystem_upload_file(
+        &self,
+        local_path: &std::path::Path,
+        remote_path: &str,
+        username: &str,
+    ) -> Result<WriteInfo> {
+        let file = tokio::fs::File::open(local_path).await?;
+        let length = file.metadata().await.map(|m| m.len())?;
+
+        let part = Part::stream_with_length(reqwest::Body::from(file), length)
+            .file_name(remote_path.to_string())
+            .mime_str(mime_type_for_path(remote_path))?;
+        let form = Form::new().part("file", part);
+
+        let mut results = self.send_upload(form, username, Some(remote_path)).await?;
+        results
+            .pop()
+            .ok_or_else(|| Error::invalid_response("Upload returned no result"))
+    }
+
+    async fn send_upload(
+        &self,
+        form: Form,
+        username: &str,
+        single_path: Option<&str>,
+    ) -> Result<Vec<WriteInfo>> {
+        let url = format!("{}/files", self.base_url);
         let mut headers = self.headers.clone();
         headers.remove("Content-Type");
 
@@ -265,24 +726,23 @@ impl RpcClient {
             .headers(headers)
             .query(&[("username", username)]);
 
-        if entries.len() == 1 {
-            request = request.query(&[("path", entries[0].path.as_str())]);
+        if let Some(path) = single_path {
+            request = request.query(&[("path", path)]);
         }
 
+        self.wait_until_ready().await?;
+        let _permit = acquire_permit(self.limiter.as_ref()).await;
         let response = request
             .multipart(form)
             .send()
             .await
-            .map_err(|e| Error::Api {
-                status: 500,
-                message: format!("HTTP request failed: {}", e),
-            })?;
+            .map_err(|e| Error::wrap(500, "HTTP request failed", e))?;
 
         let status = response.status();
         let body = response.text().await.unwrap_or_else(|_| "".to_string());
 
         if !status.is_success() {
-            return Err(Error::Api {
+            return Err(Error::Envd {
                 status: status.as_u16(),
                 message: format!(
                     "HTTP {} error: {}",
@@ -299,10 +759,16 @@ impl RpcClient {
 
         tracing::debug!("filesystem upload response body: {}", body);
 
-        serde_json::from_str::<Vec<WriteInfo>>(&body).map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to parse response: {}", e),
-        })
+        serde_json::from_str::<Vec<WriteInfo>>(&body)
+            .map_err(|e| Error::undecodable("Failed to parse response", e))
+    }
+
+    pub async fn filesystem_watch(&self, params: Value) -> Result<WatchStream> {
+        let request = params;
+        let response = self
+            .post_connect_request("filesystem.Filesystem", "WatchDir", request, true)
+            .await?;
+        Ok(WatchStream::new(response))
     }
 
     pub async fn filesystem_list(&self, params: Value) -> Result<Value> {
@@ -310,10 +776,10 @@ impl RpcClient {
         let response = self
             .post_connect_request("filesystem.Filesystem", "ListDir", request, false)
             .await?;
-        let result: Value = response.json().await.map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to parse response: {}", e),
-        })?;
+        let result: Value = response
+            .json()
+            .await
+            .map_err(|e| Error::undecodable("Failed to parse response", e))?;
         Ok(result)
     }
 
@@ -322,10 +788,10 @@ impl RpcClient {
         let response = self
             .post_connect_request("filesystem.Filesystem", "Stat", request, false)
             .await?;
-        let result: Value = response.json().await.map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to parse response: {}", e),
-        })?;
+        let result: Value = response
+            .json()
+            .await
+            .map_err(|e| Error::undecodable("Failed to parse response", e))?;
         Ok(result)
     }
 
@@ -334,10 +800,10 @@ impl RpcClient {
         let response = self
             .post_connect_request("filesystem.Filesystem", "MakeDir", request, false)
             .await?;
-        let result: Value = response.json().await.map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to parse response: {}", e),
-        })?;
+        let result: Value = response
+            .json()
+            .await
+            .map_err(|e| Error::undecodable("Failed to parse response", e))?;
         Ok(result)
     }
 
@@ -346,10 +812,10 @@ impl RpcClient {
         let response = self
             .post_connect_request("filesystem.Filesystem", "Remove", request, false)
             .await?;
-        let result: Value = response.json().await.map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to parse response: {}", e),
-        })?;
+        let result: Value = response
+            .json()
+            .await
+            .map_err(|e| Error::undecodable("Failed to parse response", e))?;
         Ok(result)
     }
 
@@ -358,14 +824,81 @@ impl RpcClient {
         let response = self
             .post_connect_request("filesystem.Filesystem", "Move", request, false)
             .await?;
-        let result: Value = response.json().await.map_err(|e| Error::Api {
-            status: 500,
-            message: format!("Failed to parse response: {}", e),
-        })?;
+        let result: Value = response
+            .json()
+            .await
+            .map_err(|e| Error::undecodable("Failed to parse response", e))?;
         Ok(result)
     }
 }
 
+fn basic_auth(username: &str) -> String {
+    format!(
+        "Basic {}",
+        general_purpose::STANDARD.encode(format!("{}:", username))
+    )
+}
+
+// `sha256sum`/`md5sum` print "<digest> *<path>", with a `\` before the digest
+// when the path contains a backslash or newline
+fn parse_digest(output: &str) -> Option<String> {
+    output
+        .split_whitespace()
+        .next()
+        .map(|digest| digest.trim_start_matches('\\').to_ascii_lowercase())
+        .filter(|digest| !digest.is_empty())
+}
+
+// Guess a MIME type from the file extension, defaulting to raw bytes
+pub(crate) fn mime_type_for_path(path: &str) -> &'static str {
+    let extension = path
+        .rsplit('/')
+        .next()
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("pdf") => "application/pdf",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("zip") => "application/zip",
+        Some("gz") | Some("tgz") => "application/gzip",
+        Some("tar") => "application/x-tar",
+        Some("wasm") => "application/wasm",
+        Some("js") | Some("mjs") => "text/javascript",
+        Some("html") | Some("htm") => "text/html",
+        Some("css") => "text/css",
+        Some("csv") => "text/csv",
+        Some("md") => "text/markdown",
+        Some("txt") | Some("log") => "text/plain",
+        Some("py") => "text/x-python",
+        Some("sh") => "text/x-shellscript",
+        _ => "application/octet-stream",
+    }
+}
+
+// HTTP status the Connect protocol pairs with an error `code`
+fn connect_code_status(code: Option<&str>) -> u16 {
+    match code {
+        Some("canceled") => 499,
+        Some("invalid_argument") | Some("failed_precondition") | Some("out_of_range") => 400,
+        Some("unauthenticated") => 401,
+        Some("permission_denied") => 403,
+        Some("not_found") => 404,
+        Some("already_exists") | Some("aborted") => 409,
+        Some("resource_exhausted") => 429,
+        Some("unimplemented") => 501,
+        Some("unavailable") => 503,
+        Some("deadline_exceeded") => 504,
+        _ => 500,
+    }
+}
+
 // Create Connect protocol envelope
 fn create_connect_envelope(data: &str) -> Vec<u8> {
     let data_bytes = data.as_bytes();
@@ -379,30 +912,35 @@ fn create_connect_envelope(data: &str) -> Vec<u8> {
     envelope
 }
 
-// Streaming wrapper around Connect envelope responses
-// Simple struct to handle streaming process output
-pub struct ProcessStream {
+// Streaming wrapper around Connect envelope responses. Yields the JSON payload
+// of each frame, surfacing in-band error messages as `Err`.
+pub struct ConnectStream {
     stream: BoxStream<'static, reqwest::Result<bytes::Bytes>>,
     buffer: BytesMut,
-    messages: VecDeque<String>,
+    messages: VecDeque<Result<String>>,
     finished: bool,
+    idle_timeout: Option<Duration>,
 }
 
-impl ProcessStream {
-    pub async fn new(response: Response) -> Result<Self> {
-        let stream = response.bytes_stream().boxed();
+impl ConnectStream {
+    pub fn new(response: Response) -> Self {
+        Self::from_stream(response.bytes_stream().boxed())
+    }
 
-        Ok(Self {
+    fn from_stream(stream: BoxStream<'static, reqwest::Result<bytes::Bytes>>) -> Self {
+        Self {
             stream,
             buffer: BytesMut::new(),
             messages: VecDeque::new(),
             finished: false,
-        })
+            idle_timeout: None,
+        }
     }
 
-    pub async fn next_event(&mut self) -> Result<Option<ProcessEvent>> {
+    pub async fn next_message(&mut self) -> Result<Option<String>> {
         loop {
             if let Some(message) = self.messages.pop_front() {
+                let message = message?;
                 let trimmed = message.trim();
 
                 debug!("Processing message: {}", message);
@@ -416,8 +954,8 @@ impl ProcessStream {
 
                 if let Ok(error_resp) = serde_json::from_str::<serde_json::Value>(&message) {
                     if let Some(error) = error_resp.get("error") {
-                        return Err(Error::Api {
-                            status: 500,
+                        return Err(Error::Envd {
+                            status: connect_code_status(error.get("code").and_then(|c| c.as_str())),
                             message: format!(
                                 "Server error: {}",
                                 error
@@ -429,43 +967,46 @@ impl ProcessStream {
                     }
                 }
 
-                let event: ProcessEvent =
-                    serde_json::from_str(&message).map_err(|e| Error::Api {
-                        status: 500,
-                        message: format!("Failed to parse process event: {}", e),
-                    })?;
-
-                return Ok(Some(event));
+                return Ok(Some(message));
             }
 
             if self.finished {
                 return Ok(None);
             }
 
-            match self.stream.next().await {
+            let next = match self.idle_timeout {
+                Some(idle) => tokio::time::timeout(idle, self.stream.next())
+                    .await
+                    .map_err(|_| Error::Timeout)?,
+                None => self.stream.next().await,
+            };
+            match next {
                 Some(Ok(chunk)) => {
                     self.buffer.extend_from_slice(&chunk);
-                    self.extract_messages()?;
+                    self.extract_messages();
                 }
                 Some(Err(e)) => {
-                    return Err(Error::Api {
-                        status: 500,
-                        message: format!("Failed to read stream: {}", e),
-                    });
+                    return Err(Error::wrap(500, "Failed to read stream", e));
                 }
                 None => {
                     self.finished = true;
-                    // Consume any pending buffered messages before exiting
-                    self.extract_messages()?;
+                    // Consume any buffered messages before exiting
+                    self.extract_messages();
+                    if !self.buffer.is_empty() {
+                        self.messages.push_back(Err(Error::invalid_response(
+                            "Stream ended inside a message",
+                        )));
+                        self.buffer.clear();
+                    }
                 }
             }
         }
     }
 
-    fn extract_messages(&mut self) -> Result<()> {
+    fn extract_messages(&mut self) {
         loop {
             if self.buffer.len() < 5 {
-                return Ok(());
+                return;
             }
 
             let length = u32::from_be_bytes([
@@ -476,25 +1017,110 @@ impl ProcessStream {
             ]) as usize;
 
             if self.buffer.len() < 5 + length {
-                return Ok(());
+                return;
             }
 
             let frame = self.buffer.split_to(5 + length);
             let flags = frame[0];
-            let payload = &frame[5..];
-
-            let message = String::from_utf8(payload.to_vec()).map_err(|e| Error::Api {
-                status: 500,
-                message: format!("Failed to decode message: {}", e),
-            })?;
+            self.split_json_values(&frame[5..]);
 
             if flags & 0b0000_0010 != 0 {
                 self.finished = true;
             }
+        }
+    }
+
+    // Each envelope is decoded on its own, so a malformed payload only fails
+    // its own message. A payload may still carry several concatenated values.
+    fn split_json_values(&mut self, payload: &[u8]) {
+        let mut values =
+            serde_json::Deserializer::from_slice(payload).into_iter::<serde::de::IgnoredAny>();
+        let mut start = 0;
+
+        loop {
+            match values.next() {
+                Some(Ok(_)) => {
+                    let end = values.byte_offset();
+                    self.messages.push_back(
+                        String::from_utf8(payload[start..end].to_vec())
+                            .map_err(|e| Error::undecodable("Failed to decode message", e)),
+                    );
+                    start = end;
+                }
+                Some(Err(e)) => {
+                    self.messages
+                        .push_back(Err(Error::undecodable("Failed to decode message", e)));
+                    return;
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+// Simple struct to handle streaming process output
+pub struct ProcessStream {
+    inner: ConnectStream,
+}
+
+impl ProcessStream {
+    pub async fn new(response: Response) -> Result<Self> {
+        Ok(Self {
+            inner: ConnectStream::new(response),
+        })
+    }
+
+    pub async fn next_event(&mut self) -> Result<Option<ProcessEvent>> {
+        let Some(message) = self.inner.next_message().await? else {
+            return Ok(None);
+        };
+
+        let event: ProcessEvent = serde_json::from_str(&message)
+            .map_err(|e| Error::undecodable("Failed to parse process event", e))?;
+
+        Ok(Some(event))
+    }
+}
 
-            self.messages.push_back(message);
+// Streaming filesystem events from `filesystem.Filesystem/WatchDir`
+pub struct WatchStream {
+    inner: ConnectStream,
+}
+
+impl WatchStream {
+    pub fn new(response: Response) -> Self {
+        Self {
+            inner: ConnectStream::new(response),
         }
     }
+
+    pub async fn next_event(&mut self) -> Result<Option<WatchEvent>> {
+        loop {
+            let Some(message) = self.inner.next_message().await? else {
+                return Ok(None);
+            };
+
+            let response: WatchResponse = serde_json::from_str(&message)
+                .map_err(|e| Error::undecodable("Failed to parse watch event", e))?;
+
+            // Start and keepalive frames carry no event
+            if let Some(event) = response.event {
+                return Ok(Some(event));
+            }
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct WatchResponse {
+    event: Option<WatchEvent>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct WatchEvent {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
@@ -526,4 +1152,353 @@ pub struct ProcessEnd {
     pub exited: bool,
     pub status: String,
     pub exit_code: Option<i32>,
+    /// Set by envd when the process could not be started or was killed.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{live_sandbox, recording_endpoint};
+
+    fn chunked_stream(chunks: Vec<Vec<u8>>) -> ConnectStream {
+        let chunks = chunks.into_iter().map(|c| Ok(bytes::Bytes::from(c)));
+        ConnectStream::from_stream(futures::stream::iter(chunks).boxed())
+    }
+
+    async fn collect(mut stream: ConnectStream) -> Vec<Result<String>> {
+        let mut out = Vec::new();
+        loop {
+            match stream.next_message().await {
+                Ok(Some(message)) => out.push(Ok(message)),
+                Ok(None) => return out,
+                Err(e) => out.push(Err(e)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn message_split_mid_header_is_reassembled() {
+        let frame = create_connect_envelope(r#"{"event":{"data":"one"}}"#);
+        let stream = chunked_stream(vec![frame[..3].to_vec(), frame[3..].to_vec()]);
+        let messages = collect(stream).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].as_deref().unwrap(),
+            r#"{"event":{"data":"one"}}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn message_split_mid_json_is_reassembled() {
+        let mut bytes = create_connect_envelope(r#"{"event":{"data":"one"}}"#);
+        bytes.extend(create_connect_envelope(r#"{"event":{"data":"two"}}"#));
+        // Cut inside the first JSON value and again inside the second header
+        let stream = chunked_stream(vec![
+            bytes[..12].to_vec(),
+            bytes[12..32].to_vec(),
+            bytes[32..].to_vec(),
+        ]);
+        let messages: Vec<String> = collect(stream)
+            .await
+            .into_iter()
+            .map(|m| m.unwrap())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                r#"{"event":{"data":"one"}}"#.to_string(),
+                r#"{"event":{"data":"two"}}"#.to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_frame_does_not_corrupt_later_frames() {
+        let mut bytes = create_connect_envelope(r#"{"event":"#);
+        bytes.extend(create_connect_envelope(r#"{"event":{"data":"two"}}"#));
+        let messages = collect(chunked_stream(vec![bytes])).await;
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(
+            messages[0],
+            Err(Error::InvalidResponse {
+                source: Some(_),
+                ..
+            })
+        ));
+        assert!(!messages[0].as_ref().unwrap_err().is_retryable());
+        assert_eq!(
+            messages[1].as_deref().unwrap(),
+            r#"{"event":{"data":"two"}}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn concatenated_values_in_one_frame_are_split() {
+        let bytes = create_connect_envelope(r#"{"a":1}{"b":2}"#);
+        let messages: Vec<String> = collect(chunked_stream(vec![bytes]))
+            .await
+            .into_iter()
+            .map(|m| m.unwrap())
+            .collect();
+        assert_eq!(messages, vec![r#"{"a":1}"#, r#"{"b":2}"#]);
+    }
+
+    #[tokio::test]
+    async fn truncated_stream_reports_error() {
+        let frame = create_connect_envelope(r#"{"event":{"data":"one"}}"#);
+        let messages = collect(chunked_stream(vec![frame[..10].to_vec()])).await;
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn in_band_errors_keep_the_connect_status() {
+        let mut stream = chunked_stream(vec![create_connect_envelope(
+            r#"{"error":{"code":"not_found","message":"no such process"}}"#,
+        )]);
+        match stream.next_message().await {
+            Err(Error::Envd { status, message }) => {
+                assert_eq!(status, 404);
+                assert!(message.contains("no such process"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(connect_code_status(Some("unavailable")), 503);
+        assert_eq!(connect_code_status(None), 500);
+    }
+
+    #[test]
+    fn parse_digest_reads_first_field() {
+        let digest = parse_digest("D41D8CD98F00B204E9800998ECF8427E *empty.txt\n");
+        assert_eq!(digest.as_deref(), Some("d41d8cd98f00b204e9800998ecf8427e"));
+    }
+
+    #[test]
+    fn parse_digest_strips_escape_marker() {
+        let output = "\\e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 *a\\\\b\n";
+        assert_eq!(
+            parse_digest(output).as_deref(),
+            Some("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+    }
+
+    #[test]
+    fn parse_digest_rejects_empty_output() {
+        assert_eq!(parse_digest(""), None);
+        assert_eq!(parse_digest("\\"), None);
+    }
+
+    // Accepts connections, writes `reply` once the request headers are in,
+    // and then keeps the connection open without sending anything more
+    async fn stalled_endpoint(reply: impl AsRef<[u8]> + Send + 'static) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let _ = socket.write_all(reply.as_ref()).await;
+                held.push(socket);
+            }
+        });
+        url
+    }
+
+    // Answers `GET /health` with `503` until `failures` requests have been
+    // refused, then with `200`
+    async fn flaky_health(failures: usize) -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let probes = Arc::new(AtomicUsize::new(0));
+        let counter = probes.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !crate::test_support::request_complete(&request) {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let status = if counter.fetch_add(1, Ordering::SeqCst) < failures {
+                    "503 Service Unavailable"
+                } else {
+                    "200 OK"
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, probes)
+    }
+
+    #[tokio::test]
+    async fn health_check_is_retried_under_the_policy() {
+        let (url, probes) = flaky_health(2).await;
+        let client = RpcClient::connect(&url, None).await.unwrap();
+        let policy = crate::retry::RetryPolicy::new(3).base_delay(Duration::from_millis(10));
+        policy.run(|| client.check_health()).await.unwrap();
+        assert_eq!(probes.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        let (url, _) = flaky_health(5).await;
+        let client = RpcClient::connect(&url, None).await.unwrap();
+        let error = policy.run(|| client.check_health()).await.unwrap_err();
+        assert!(matches!(error, Error::Envd { status: 503, .. }));
+    }
+
+    #[tokio::test]
+    async fn unreadable_local_file_is_an_io_error() {
+        let client = RpcClient::connect("http://127.0.0.1:9", None)
+            .await
+            .unwrap();
+        let missing = std::env::temp_dir().join("e2b-missing-upload-source");
+        let error = client
+            .filesystem_upload_file(&missing, "/tmp/a", "user")
+            .await
+            .unwrap_err();
+        assert!(matches!(&error, Error::Io(e) if e.kind() == std::io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn mime_type_follows_the_extension() {
+        assert_eq!(mime_type_for_path("/tmp/chart.png"), "image/png");
+        assert_eq!(mime_type_for_path("/tmp/PHOTO.JPG"), "image/jpeg");
+        assert_eq!(
+            mime_type_for_path("/tmp/archive.tar.gz"),
+            "application/gzip"
+        );
+        assert_eq!(
+            mime_type_for_path("/tmp/Makefile"),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            mime_type_for_path("/tmp/dir.d/blob"),
+            "application/octet-stream"
+        );
+    }
+
+    #[tokio::test]
+    async fn png_upload_is_sent_as_image_png() {
+        let (url, request) =
+            recording_endpoint(r#"[{"path":"/tmp/chart.png","name":"chart.png"}]"#).await;
+        let client = RpcClient::connect(&url, None).await.unwrap();
+        let written = client
+            .filesystem_upload(
+                vec![WriteEntry::binary("/tmp/chart.png", b"\x89PNG".to_vec())],
+                "user",
+            )
+            .await
+            .unwrap();
+        assert_eq!(written[0].path, "/tmp/chart.png");
+
+        let request = String::from_utf8_lossy(&request.await.unwrap()).into_owned();
+        assert!(request.contains("filename=\"/tmp/chart.png\"\r\nContent-Type: image/png\r\n"));
+    }
+
+    #[tokio::test]
+    async fn text_read_of_non_utf8_file_is_lossy() {
+        let url = stalled_endpoint(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\na\xffb",
+        )
+        .await;
+        let client = RpcClient::connect(&url, None).await.unwrap();
+        let text = client.filesystem_read("/tmp/blob", "user").await.unwrap();
+        assert_eq!(text, "a\u{fffd}b");
+    }
+
+    #[tokio::test]
+    async fn unanswered_call_times_out() {
+        let url = stalled_endpoint("").await;
+        let config = Config::with_api_key("test").timeout_seconds(1);
+        let client = RpcClient::connect_with_config(&url, None, &config)
+            .await
+            .unwrap();
+
+        let result = client.process_list(serde_json::json!({})).await;
+        assert!(matches!(result, Err(Error::Timeout)), "{:?}", result.err());
+    }
+
+    #[tokio::test]
+    async fn silent_stream_hits_idle_timeout() {
+        let url = stalled_endpoint(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/connect+json\r\nTransfer-Encoding: chunked\r\n\r\n",
+        )
+        .await;
+        let config = Config::with_api_key("test").rpc_idle_timeout(Duration::from_millis(300));
+        let client = RpcClient::connect_with_config(&url, None, &config)
+            .await
+            .unwrap();
+
+        let mut stream = client.process_start(serde_json::json!({})).await.unwrap();
+        let event = tokio::time::timeout(Duration::from_secs(5), stream.next_event())
+            .await
+            .expect("idle timeout did not fire");
+        assert!(matches!(event, Err(Error::Timeout)), "{:?}", event.err());
+    }
+
+    #[tokio::test]
+    async fn readiness_gate_times_out_when_envd_never_answers() {
+        let url = stalled_endpoint("").await;
+
+        let client =
+            RpcClient::connect_with_timeouts(&url, None, Some(Duration::from_millis(300)), None)
+                .await
+                .unwrap()
+                .with_readiness_gate(Arc::new(OnceCell::new()));
+        let result = client.process_list(serde_json::json!({})).await;
+        assert!(matches!(result, Err(Error::Timeout)), "{:?}", result.err());
+    }
+
+    #[test]
+    fn basic_auth_encodes_username_without_password() {
+        assert_eq!(basic_auth("root"), "Basic cm9vdDo=");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs E2B_API_KEY and a live sandbox"]
+    async fn checksum_round_trips_binary_upload() {
+        let sandbox = live_sandbox().await;
+        let data: Vec<u8> = (0..=255u8).cycle().take(3 * 1024 * 1024 + 7).collect();
+        let local = std::env::temp_dir().join(format!("e2b-{}.bin", uuid::Uuid::new_v4()));
+        tokio::fs::write(&local, &data).await.unwrap();
+
+        let files = sandbox.files();
+        let (_, uploaded) = files
+            .upload_file(&local, "/tmp/streamed.bin", ChecksumAlgo::Sha256)
+            .await
+            .unwrap();
+        files
+            .write_binary("/tmp/buffered.bin", data.clone())
+            .await
+            .unwrap();
+        let buffered = files
+            .checksum("/tmp/buffered.bin", ChecksumAlgo::Sha256)
+            .await
+            .unwrap();
+
+        assert_eq!(uploaded.len(), 64);
+        assert_eq!(uploaded, buffered);
+        assert_eq!(files.read_binary("/tmp/streamed.bin").await.unwrap(), data);
+
+        let _ = tokio::fs::remove_file(&local).await;
+        sandbox.delete().await.unwrap();
+    }
 }
@@ -0,0 +1,101 @@
+//! Helpers for tests that need a live sandbox. Such tests are `#[ignore]`d;
+//! run them with `E2B_API_KEY` set and `cargo test -- --ignored`.
+use crate::{api::sandbox::SandboxInstance, Client};
+
+pub(crate) async fn live_sandbox() -> SandboxInstance {
+    live_sandbox_from("base").await
+}
+
+pub(crate) async fn live_sandbox_from(template: &str) -> SandboxInstance {
+    Client::new()
+        .expect("E2B_API_KEY must be set for live tests")
+        .sandbox()
+        .template(template)
+        .create()
+        .await
+        .expect("failed to create sandbox")
+}
+
+/// Answers a single HTTP request with a `200` carrying `reply`, and hands
+/// back the raw request once its body has been read.
+pub(crate) async fn recording_endpoint(
+    reply: &'static str,
+) -> (String, tokio::sync::oneshot::Receiver<Vec<u8>>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request_complete(&request) {
+            match socket.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            reply.len(),
+            reply
+        );
+        let _ = socket.write_all(response.as_bytes()).await;
+        let _ = tx.send(request);
+    });
+    (url, rx)
+}
+
+/// Answers every HTTP request with the status line and body that
+/// `reply(request line)` returns, e.g. `("200 OK", "[]")`, and reports each
+/// full request once answered.
+pub(crate) async fn serve(
+    reply: fn(&str) -> (&'static str, &'static str),
+) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request_complete(&request) {
+                match socket.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let request = String::from_utf8_lossy(&request).into_owned();
+            let (status, body) = reply(request.lines().next().unwrap_or_default());
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+            let _ = tx.send(request);
+        }
+    });
+    (url, rx)
+}
+
+/// Whether `request` holds a full HTTP request, body included.
+pub(crate) fn request_complete(request: &[u8]) -> bool {
+    let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return false;
+    };
+    let head = String::from_utf8_lossy(&request[..end]).to_ascii_lowercase();
+    if head.contains("transfer-encoding: chunked") {
+        return request.ends_with(b"0\r\n\r\n");
+    }
+    let length = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    request.len() >= end + 4 + length
+}